@@ -6,8 +6,7 @@ use slog::Logger;
 
 use rarian::schema::Schema;
 use rarian::db::Database;
-use rarian::db::dbm::DBManager;
-use rarian::Transaction;
+use rarian::db::dbm;
 
 use crate::Settings;
 
@@ -34,23 +33,10 @@ pub async fn create(log: &Logger, s: Settings, m: &clap::ArgMatches<'_>) {
         }
     };
 
-    let mut dbmb = DBManager::builder();
-    dbmb.set_max_dbs(126);
-    dbmb.set_map_size(10485760);
-    let dbm = DBManager::from_builder(&s.databasepath, dbmb).unwrap();
-
-    let mut txn = dbm.write().unwrap();
+    let dbm = s.dbmanager(dbm::EnvironmentFlags::empty()).unwrap();
 
     info!(log, "Creating database {}", target);
-    match Database::create(&mut txn, target, schema) {
-        Ok(db) => db,
-        Err(e) => {
-            crit!(log, "Can't create database {}: {:?}", target, e);
-            return;
-        }
-    }
-
-    if let Err(e) = Transaction::commit(txn) {
-        crit!(log, "Failed to commit transaction: {}", e);
+    if let Err(e) = dbm.write_with(|txn| Database::create(txn, target, schema.clone())) {
+        crit!(log, "Can't create database {}: {:?}", target, e);
     }
 }
@@ -0,0 +1,117 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+
+use clap;
+use slog::Logger;
+
+use rarian::db::{Database, UUID};
+use rarian::db::entry;
+use rarian::db::dbm;
+
+use crate::Settings;
+
+pub async fn edit(log: &Logger, s: Settings, m: &clap::ArgMatches<'_>) {
+    let target = m.value_of("target").expect("No value for `TARGET` set!");
+    let u = m.value_of("uuid").expect("No value for `UUID` set!");
+
+    let uuid = match UUID::parse_str(u) {
+        Ok(uuid) => uuid,
+        Err(e) => {
+            crit!(log, "Invalid UUID {}: {:?}", u, e);
+            return;
+        }
+    };
+
+    let dbm = s.dbmanager(dbm::EnvironmentFlags::empty()).unwrap();
+
+    let txn = dbm.read().unwrap();
+    info!(log, "Opening database {}", target);
+    let db = match Database::open(&txn, target) {
+        Ok(db) => db,
+        Err(e) => {
+            crit!(log, "Can't open database {}: {:?}", target, e);
+            return;
+        }
+    };
+
+    let old = match db.lookup(&txn, &uuid) {
+        Ok(e) => e,
+        Err(e) => {
+            crit!(log, "Can't find entry {}: {:?}", u, e);
+            return;
+        }
+    };
+    // The map can only be grown while no transaction is open
+    drop(txn);
+
+    let yaml = match old.to_yaml() {
+        Ok(y) => y,
+        Err(e) => {
+            crit!(log, "Can't encode entry: {}", e);
+            return;
+        }
+    };
+    // Created exclusively under a random name and removed again when dropped
+    let tmp = tempfile::Builder::new()
+        .prefix(&format!("pdas-{}-", uuid.as_uuid()))
+        .suffix(".yaml")
+        .tempfile()
+        .and_then(|mut f| f.write_all(yaml.as_bytes()).map(|()| f));
+    let tmp = match tmp {
+        Ok(f) => f,
+        Err(e) => {
+            crit!(log, "Can't write temporary file: {}", e);
+            return;
+        }
+    };
+    let path = tmp.path();
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    match Command::new(&editor).arg(&path).status() {
+        Ok(status) if status.success() => {},
+        Ok(status) => {
+            error!(log, "{} exited with {}, discarding changes", editor, status);
+            return;
+        }
+        Err(e) => {
+            crit!(log, "Failed to start `{}`: {}", editor, e);
+            return;
+        }
+    }
+
+    let buf = fs::read(path);
+    let new = match buf.map(|b| entry::from_yaml(&b)) {
+        Ok(Ok(e)) => e,
+        Ok(Err(e)) => {
+            crit!(log, "Can't decode edited entry: {}", e);
+            return;
+        }
+        Err(e) => {
+            crit!(log, "Can't read temporary file {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    if new.files != old.files {
+        warn!(log, "Changes to the file list are not supported and will be ignored");
+    }
+
+    let changes = old.diff(&new);
+    if changes.is_empty() {
+        info!(log, "No changes");
+        return;
+    }
+
+    let r = dbm.write_with(|txn| {
+        let mut db = Database::open(txn, target)?;
+        if db.needs_migration() {
+            db.migrate(txn)?;
+        }
+        db.update(txn, uuid, &changes)
+    });
+    if let Err(e) = r {
+        crit!(log, "Failed to update entry {}: {:?}", u, e);
+    }
+}
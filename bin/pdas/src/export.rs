@@ -4,7 +4,7 @@ use clap;
 use slog::Logger;
 
 use rarian::db::Database;
-use rarian::db::dbm::{self, DBManager};
+use rarian::db::dbm;
 
 use crate::Settings;
 
@@ -12,11 +12,7 @@ pub async fn export(log: &Logger, s: Settings, m: &clap::ArgMatches<'_>) {
     let target = m.value_of("target").expect("No value for `TARGET` set!");
     let entries = m.value_of("entries").expect("No entries folder provided");
 
-    let mut dbmb = DBManager::builder();
-    dbmb.set_flags(dbm::EnvironmentFlags::empty());
-    dbmb.set_max_dbs(126);
-    dbmb.set_map_size(10485760);
-    let dbm = DBManager::from_builder(&s.databasepath, dbmb).unwrap();
+    let dbm = s.dbmanager(dbm::EnvironmentFlags::empty()).unwrap();
 
     let txn = dbm.read().unwrap();
     info!(log, "Opening database {}", target);
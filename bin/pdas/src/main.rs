@@ -23,6 +23,12 @@ mod import;
 use import::import;
 mod export;
 use export::export;
+mod rm;
+use rm::rm;
+mod edit;
+use edit::edit;
+mod stats;
+use stats::stats;
 
 mod segments;
 
@@ -48,6 +54,10 @@ fn main() {
         (@subcommand query =>
             (about: "Query the database")
             (@arg target: -t --target env("TARGET") +required "The target database")
+            (@arg sort: --sort +takes_value +multiple number_of_values(1) "Sort by an attribute, append :desc to sort descending")
+            (@arg limit: --limit +takes_value "Print at most this many results")
+            (@arg offset: --offset +takes_value "Skip this many results")
+            (@arg facet: --facet +takes_value +multiple number_of_values(1) "Count the values of an attribute over all results")
             (@arg query: ... "The query to run"))
         (@subcommand create =>
             (about: "Create a database with a schema")
@@ -64,6 +74,19 @@ fn main() {
             (about: "Export the database into a directory")
             (@arg target: -t --target env("TARGET") +required "The target database")
             (@arg entries: -d --directory +required +takes_value "Directory to export to"))
+        (@subcommand rm =>
+            (about: "Remove entries from the database")
+            (@arg target: -t --target env("TARGET") +required "The target database")
+            (@arg uuids: ... +required "UUIDs of the entries to remove"))
+        (@subcommand edit =>
+            (about: "Edit the metadata of an entry using $EDITOR")
+            (@arg target: -t --target env("TARGET") +required "The target database")
+            (@arg uuid: +required "UUID of the entry to edit"))
+        (@subcommand stats =>
+            (about: "Show the size and contents of the database and its indices")
+            (@arg target: -t --target env("TARGET") +required "The target database")
+            (@arg top: --top +takes_value "Number of largest postings to list per index, 10 by default")
+            (@arg json: --json "Print the statistics as JSON"))
     ).get_matches();
 
     let decorator = slog_term::TermDecorator::new().build();
@@ -123,6 +146,21 @@ fn main() {
             block_on(f);
             exit(log, 0);
         },
+        ("rm", Some(m)) => {
+            let f = rm(&log, s, m);
+            block_on(f);
+            exit(log, 0);
+        },
+        ("edit", Some(m)) => {
+            let f = edit(&log, s, m);
+            block_on(f);
+            exit(log, 0);
+        },
+        ("stats", Some(m)) => {
+            let f = stats(&log, s, m);
+            block_on(f);
+            exit(log, 0);
+        },
         (subcmd, _) => {
             crit!(log, "Unknown subcommand {}.", subcmd);
             exit(log, -2);
@@ -1,11 +1,14 @@
 use clap::ArgMatches;
 use slog::Logger;
 
-use rarian::db::dbm::{self, DBManager};
+use rarian::db::dbm;
 use rarian::db::Database;
-use rarian::query::Querier;
+use rarian::query::{Querier, Facet};
 use rarian::Transaction;
 use rarian::query::parse;
+use rarian::db::meta::Metakey;
+use rarian::db::date::{Date, Precision};
+use rarian::schema::{Schema, Attributetype};
 
 use crate::Settings;
 
@@ -13,11 +16,7 @@ pub async fn query(log: &Logger, s: Settings, m: &ArgMatches<'_>) {
     let target = m.value_of("target").expect("No value for `TARGET` set!");
     let query = m.value_of("query").expect("No value for `QUERY` set!");
 
-    let mut dbmb = DBManager::builder();
-    dbmb.set_flags(dbm::EnvironmentFlags::READ_ONLY);
-    dbmb.set_max_dbs(126);
-    dbmb.set_map_size(10485760);
-    let dbm = DBManager::from_builder(&s.databasepath, dbmb).unwrap();
+    let dbm = s.dbmanager(dbm::EnvironmentFlags::READ_ONLY).unwrap();
 
     let txn = dbm.read().unwrap();
     info!(log, "Opening database {}", target);
@@ -28,25 +27,132 @@ pub async fn query(log: &Logger, s: Settings, m: &ArgMatches<'_>) {
             return;
         }
     };
+    if db.needs_migration() {
+        warn!(log, "Database {} has indices in an outdated format, they will be unavailable until it is opened for writing", target);
+    }
 
     let mut qr = Querier::new(&txn, &db);
-    match parse(query) {
-        Ok(q) => {
-            match qr.run(q) {
+    match parse(query, &db.schema) {
+        Ok(mut q) => {
+            for sort in m.values_of("sort").into_iter().flatten() {
+                let (key, descending) = match sort.rsplit_once(':') {
+                    Some((key, "desc")) => (key, true),
+                    Some((key, "asc")) => (key, false),
+                    _ => (sort, false),
+                };
+                match Metakey::from_str(key, &db.schema) {
+                    Ok(k) => { q.add_sort(k, descending); },
+                    Err(_) => {
+                        crit!(log, "Can't sort by {}: attribute is not declared in the schema", key);
+                        return;
+                    }
+                }
+            }
+            if let Some(v) = m.value_of("limit") {
+                match v.parse() {
+                    Ok(n) => { q.set_limit(n); },
+                    Err(e) => {
+                        crit!(log, "Invalid limit {}: {}", v, e);
+                        return;
+                    }
+                }
+            }
+            if let Some(v) = m.value_of("offset") {
+                match v.parse() {
+                    Ok(n) => { q.set_offset(n); },
+                    Err(e) => {
+                        crit!(log, "Invalid offset {}: {}", v, e);
+                        return;
+                    }
+                }
+            }
+            let mut facets = Vec::new();
+            for facet in m.values_of("facet").into_iter().flatten() {
+                match Metakey::from_str(facet, &db.schema) {
+                    Ok(k) => facets.push(k),
+                    Err(_) => {
+                        crit!(log, "Can't count {}: attribute is not declared in the schema", facet);
+                        return;
+                    }
+                }
+            }
+            let all = q.clone();
+
+            match qr.ranked(q) {
                 Ok(matches) => {
-                    for entry in matches.iter().map(|u| db.lookup(&txn, u)).filter_map(Result::ok) {
-                        println!("{}", entry);
+                    for t in qr.unindexed() {
+                        warn!(log, "{} is not indexed, the query had to scan all entries", t);
+                    }
+                    for (uuid, score) in matches.iter() {
+                        if let Ok(entry) = db.lookup(&txn, uuid) {
+                            debug!(log, "{} scored {}", uuid.as_uuid(), score);
+                            println!("{}", entry);
+                        }
                     }
                 },
                 Err(e) => {
-                    crit!(log, "Failed to run query: {:?}", e);
+                    crit!(log, "Failed to run query: {}", e);
+                    return;
+                }
+            }
+
+            if !facets.is_empty() {
+                // Facets are counted over all matches, regardless of limit and offset
+                let r = qr.run(all).and_then(|matches| qr.facets(matches.iter(), &facets));
+                match r {
+                    Ok(facets) => print_facets(&facets, &db.schema),
+                    Err(e) => crit!(log, "Failed to count facets: {}", e),
                 }
             }
         },
         Err(e) => {
-            crit!(log, "Can't parse query: {:?}", e)
+            crit!(log, "Can't parse query: {}", e)
         }
     }
 
     Transaction::commit(txn).unwrap();
 }
+
+fn print_facets(facets: &[(Metakey, Facet)], schema: &Schema) {
+    for (key, facet) in facets.iter() {
+        match facet {
+            Facet::Values(values) => {
+                println!("{}: {} values", key, values.len());
+                for (value, count) in values.iter() {
+                    println!("\t{}: {}", value, count);
+                }
+            }
+            Facet::Ranges(buckets) => {
+                let atype = schema.attribute(key).map(|a| a.atype);
+                let width = buckets.first().map_or(0, |b| b.upper.saturating_sub(b.lower));
+                let bound = |v| bound(v, atype, width);
+                match (buckets.first(), buckets.last()) {
+                    (Some(first), Some(last)) => println!("{}: {} to {}", key, bound(first.lower), bound(last.upper)),
+                    _ => println!("{}: no values", key),
+                }
+                for b in buckets.iter() {
+                    println!("\t{}..{}: {}", bound(b.lower), bound(b.upper), b.count);
+                }
+            }
+        }
+    }
+}
+
+/// A bucket bound of a range facet. Dates and timestamps are shown as dates, only as precise as
+/// buckets of `width` seconds need.
+fn bound(v: i64, atype: Option<Attributetype>, width: i64) -> String {
+    const DAY: i64 = 24 * 60 * 60;
+    let precision = match atype {
+        Some(Attributetype::Date) | Some(Attributetype::Timestamp) => if width >= 365 * DAY {
+            Precision::Year
+        } else if width >= 28 * DAY {
+            Precision::Month
+        } else if width >= DAY {
+            Precision::Day
+        } else {
+            Precision::Second
+        },
+        _ => return v.to_string(),
+    };
+    Date::new(v, precision).map_or_else(|_| v.to_string(), |d| d.to_string())
+}
@@ -0,0 +1,45 @@
+use clap;
+use slog::Logger;
+
+use rarian::db::{Database, UUID};
+use rarian::db::dbm;
+
+use crate::Settings;
+
+pub async fn rm(log: &Logger, s: Settings, m: &clap::ArgMatches<'_>) {
+    let target = m.value_of("target").expect("No value for `TARGET` set!");
+    let uuids: Vec<&str> = m.values_of("uuids").expect("No value for `UUIDS` set!").collect();
+
+    let dbm = s.dbmanager(dbm::EnvironmentFlags::empty()).unwrap();
+
+    info!(log, "Opening database {}", target);
+    let r = dbm.write_with(|txn| {
+        let mut db = Database::open(txn, target)?;
+        if db.needs_migration() {
+            db.migrate(txn)?;
+        }
+
+        for u in uuids.iter() {
+            let uuid = match UUID::parse_str(u) {
+                Ok(uuid) => uuid,
+                Err(e) => {
+                    error!(log, "Invalid UUID {}: {:?}", u, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = db.lookup(txn, &uuid) {
+                error!(log, "Can't find entry {}: {:?}", u, e);
+                continue;
+            }
+            // A failed removal may have left the entry half removed, so none are removed then
+            db.remove(txn, uuid)?;
+            info!(log, "Removed {}", u);
+        }
+        Ok(())
+    });
+
+    if let Err(e) = r {
+        crit!(log, "Failed to remove entries from {}, none were removed: {}", target, e);
+    }
+}
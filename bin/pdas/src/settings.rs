@@ -4,11 +4,29 @@ use dirs;
 
 use std::path::{Path, PathBuf};
 
+use rarian::db::MergePolicy;
+use rarian::db::dbm::{DBManager, EnvironmentFlags};
+use rarian::Error;
+use rarian::extract::Registry;
+use rarian::extract::exiftool::Exiftool;
+
 fn default_loglevel() -> usize {
     // TODO: Make that compile time const
     slog::Level::Error.as_usize()
 }
 
+fn default_mapsize() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_maxdbs() -> u32 {
+    126
+}
+
+fn default_exiftool() -> bool {
+    true
+}
+
 #[derive(Debug,Deserialize)]
 /// PDAS application settings
 ///
@@ -21,6 +39,27 @@ pub struct Settings {
 
     #[serde(default = "default_loglevel")]
     pub loglevel: usize,
+
+    /// How to resolve conflicting metadata when adding a file to an existing entry
+    #[serde(default)]
+    pub mergepolicy: MergePolicy,
+
+    /// Initial size of the LMDB map in bytes. Writes grow it as needed, a larger map only reserves
+    /// address space.
+    #[serde(default = "default_mapsize")]
+    pub mapsize: usize,
+
+    /// Largest size in bytes the map is grown to, without limit if unset
+    #[serde(default)]
+    pub maxmapsize: Option<usize>,
+
+    /// How many named LMDB databases can be opened. Every database uses two plus one per index.
+    #[serde(default = "default_maxdbs")]
+    pub maxdbs: u32,
+
+    /// Run exiftool on files the built-in readers don't understand
+    #[serde(default = "default_exiftool")]
+    pub exiftool: bool,
 }
 
 impl Default for Settings {
@@ -28,6 +67,11 @@ impl Default for Settings {
         Self {
             databasepath: PathBuf::from(""),
             loglevel: default_loglevel(),
+            mergepolicy: MergePolicy::default(),
+            mapsize: default_mapsize(),
+            maxmapsize: None,
+            maxdbs: default_maxdbs(),
+            exiftool: default_exiftool(),
         }
     }
 }
@@ -59,6 +103,26 @@ impl Settings {
         s.try_into()
     }
 
+    /// Open the LMDB environment at `databasepath` as configured
+    pub fn dbmanager(&self, flags: EnvironmentFlags) -> Result<DBManager, Error> {
+        let mut dbmb = DBManager::builder();
+        dbmb.set_flags(flags);
+        dbmb.set_max_dbs(self.maxdbs);
+        dbmb.set_map_size(self.mapsize);
+        let mut dbm = DBManager::from_builder(&self.databasepath, dbmb)?;
+        dbm.set_max_map_size(self.maxmapsize);
+        Ok(dbm)
+    }
+
+    /// The extractors to read the metadata of files with, with exiftool as fallback if enabled
+    pub fn extractors(&self) -> Registry {
+        let mut r = Registry::builtin();
+        if self.exiftool {
+            r.set_fallback(Some(Box::new(Exiftool)));
+        }
+        r
+    }
+
     pub fn set_loglevel(&mut self, level: slog::Level) {
         self.loglevel = level.as_usize();
     }
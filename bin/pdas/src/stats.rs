@@ -0,0 +1,50 @@
+use clap;
+use slog::Logger;
+
+use serde_json::json;
+
+use rarian::db::Database;
+use rarian::db::dbm;
+use rarian::Transaction;
+
+use crate::Settings;
+
+pub async fn stats(log: &Logger, s: Settings, m: &clap::ArgMatches<'_>) {
+    let target = m.value_of("target").expect("No value for `TARGET` set!");
+    let top = match m.value_of("top").unwrap_or("10").parse() {
+        Ok(n) => n,
+        Err(e) => {
+            crit!(log, "Invalid number of postings: {}", e);
+            return;
+        }
+    };
+
+    let dbm = s.dbmanager(dbm::EnvironmentFlags::READ_ONLY).unwrap();
+
+    let txn = dbm.read().unwrap();
+    info!(log, "Opening database {}", target);
+    let db = match Database::open(&txn, target) {
+        Ok(db) => db,
+        Err(e) => {
+            crit!(log, "Can't open database {}: {:?}", target, e);
+            return;
+        }
+    };
+    if db.needs_migration() {
+        warn!(log, "Database {} has indices in an outdated format, they will be unavailable until it is opened for writing", target);
+    }
+
+    let r = db.stats(&txn, top).and_then(|d| dbm.stats().map(|e| (d, e)));
+    match r {
+        Ok((d, e)) if m.is_present("json") => {
+            println!("{}", json!({ "database": d, "environment": e }));
+        }
+        Ok((d, e)) => {
+            print!("{}", d);
+            print!("{}", e);
+        }
+        Err(e) => crit!(log, "Failed to collect statistics: {}", e),
+    }
+
+    Transaction::commit(txn).unwrap();
+}
@@ -4,11 +4,15 @@ pub mod term;
 pub use term::TermDB;
 pub mod range;
 pub use range::RangeDB;
+pub mod keyword;
+pub use keyword::KeywordDB;
 pub mod filekey;
 pub use filekey::FilekeyDB;
 
 pub mod dbm;
 pub mod meta;
+pub mod date;
+pub mod stats;
 
 use entry::EntryT;
 use crate::error::{Result, Error};
@@ -16,7 +20,7 @@ pub use crate::uuid::UUID;
 use crate::schema::{Schema, IndexDescription};
 use dbm::DBManager;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 
 use lmdb::{Transaction, RoTransaction, RwTransaction};
@@ -35,11 +39,45 @@ pub struct Database {
     pub entries: EntryDB,
     pub filekeys: FilekeyDB,
     pub indices: HashMap<meta::Metakey, Index>,
+    pub schema: Schema,
+    name: String,
+    /// Range indices still stored as a single serialized blob, waiting for `migrate`
+    legacy: Vec<(meta::Metakey, String)>,
+    /// Term indices written before positions were recorded, waiting for `migrate`
+    legacy_terms: Vec<meta::Metakey>,
+    /// Set if the schema is stored in an outdated format, entries may also still use the fixed
+    /// pre-schema metadata keys
+    legacy_schema: bool,
+    merge_policy: MergePolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// What to do if merging two entries finds disagreeing values for the same key
+pub enum MergePolicy {
+    /// Keep the value of the entry already in the database
+    KeepExisting,
+    /// Replace the value with the one of the entry being inserted
+    PreferNew,
+    /// Abort the insert with `Error::MergeConflict`
+    Fail,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy::KeepExisting
+    }
 }
 
 impl<'env> Database {
-    fn new(entries: EntryDB, indices: HashMap<meta::Metakey, Index>, filekeys: FilekeyDB) -> Self {
-        Self { entries, indices, filekeys }
+    fn new(name: String, schema: Schema, entries: EntryDB, indices: HashMap<meta::Metakey, Index>, filekeys: FilekeyDB) -> Self {
+        Self {
+            entries, indices, filekeys, schema, name,
+            legacy: Vec::new(),
+            legacy_terms: Vec::new(),
+            legacy_schema: false,
+            merge_policy: MergePolicy::default(),
+        }
     }
 
     pub fn open<T: Transaction>(txn: &T, roname: &str) -> Result<Self> {
@@ -50,20 +88,134 @@ impl<'env> Database {
         name.push_str("_schema");
 
         let b = txn.get(db, &name.as_bytes())?;
-        let schema = Schema::decode(b)?;
+        let (schema, legacy_schema) = Schema::decode(b)?;
+        if legacy_schema {
+            warn!("Database {} has a schema in an outdated format and needs to be migrated", roname);
+        }
 
         name.replace_range(len.., "_filekeys");
-        let db = unsafe { txn.open_db(Some(&name))? };
-        let filekeys = FilekeyDB::new(db);
-
-        let indices: HashMap<meta::Metakey, Index> = schema.attributes.iter()
-            .filter_map(|(k,a)| Index::construct(txn, db, a).ok().map(|x| (*k,x)))
-            .collect();
+        let fdb = unsafe { txn.open_db(Some(&name))? };
+        let filekeys = FilekeyDB::new(fdb);
+
+        let mut indices = HashMap::new();
+        let mut legacy = Vec::new();
+        let mut legacy_terms = Vec::new();
+        for (k, a) in schema.attributes.iter() {
+            let a = match a.index {
+                Some(ref a) => a,
+                None => continue,
+            };
+            match Index::construct(txn, a) {
+                Ok(Index::Term(t)) if t.is_legacy(txn)? => {
+                    warn!("Term index for {} has no word positions and needs to be migrated", k);
+                    legacy_terms.push(k.clone());
+                    indices.insert(k.clone(), Index::Term(t));
+                },
+                Ok(i) => { indices.insert(k.clone(), i); },
+                // Term indices written before field lengths were recorded lack their length
+                // database. Until it is rebuilt the attribute is searched by scanning.
+                Err(Error::LMDB(lmdb::Error::NotFound)) if a.is_term() => {
+                    warn!("Term index for {} has no field lengths and needs to be migrated", k);
+                    legacy_terms.push(k.clone());
+                },
+                // Named databases live in the main database too, so an old range index blob
+                // stored under the same name makes opening it as a database fail.
+                Err(Error::LMDB(lmdb::Error::Incompatible)) => {
+                    if let IndexDescription::RangeTree { name } = a {
+                        warn!("Range index {} uses the legacy format and needs to be migrated", name);
+                        legacy.push((k.clone(), name.clone()));
+                    }
+                },
+                Err(e) => warn!("Failed to open index for {:?}: {:?}", k, e),
+            }
+        }
 
         let entries = unsafe { txn.open_db(Some(roname))? };
         let entries = EntryDB::new(entries);
 
-        Ok(Self::new(entries, indices, filekeys))
+        let mut this = Self::new(roname.to_string(), schema, entries, indices, filekeys);
+        this.legacy = legacy;
+        this.legacy_terms = legacy_terms;
+        this.legacy_schema = legacy_schema;
+        Ok(this)
+    }
+
+    /// Returns true if the schema, entries or some indices are stored in an outdated format
+    pub fn needs_migration(&self) -> bool {
+        self.legacy_schema || !self.legacy.is_empty() || !self.legacy_terms.is_empty()
+    }
+
+    /// Convert any schema, entries or indices that were opened in an outdated on-disk format
+    ///
+    /// Until this has been run (and the transaction committed) outdated indices are not
+    /// available.
+    pub fn migrate(&mut self, txn: &mut RwTransaction) -> Result<()> {
+        let main = unsafe { txn.open_db(None)? };
+
+        if self.legacy_schema {
+            info!("Migrating schema and entries of {}", self.name);
+            let name = format!("{}_schema", self.name);
+            txn.put(main, &name, &self.schema.encode()?, lmdb::WriteFlags::empty())?;
+
+            // Entries decode transparently from the old format, so writing them back is enough.
+            let mut old = Vec::new();
+            for r in self.entries.iter_start(txn)? {
+                let (k, v) = r?;
+                if let (e, true) = EntryT::decode_compat(v)? {
+                    old.push((UUID::from_bytes(k)?, e));
+                }
+            }
+            for (uuid, e) in old.iter() {
+                self.entries.put(txn, uuid, e)?;
+            }
+
+            self.legacy_schema = false;
+        }
+
+        for (k, name) in self.legacy.drain(..) {
+            info!("Migrating range index for {:?}", k);
+            let map = range::decode_blob(txn.get(main, &name)?)?;
+            txn.del(main, &name, None)?;
+
+            let db = unsafe { txn.create_db(Some(&name), RangeDB::flags())? };
+            let mut rdb = RangeDB::new(db);
+            for (value, uuids) in map.into_iter() {
+                for uuid in uuids.into_iter() {
+                    rdb.index(txn, value, uuid)?;
+                }
+            }
+
+            self.indices.insert(k, Index::IntMap(rdb));
+        }
+
+        // Old term postings can't be converted since the positions were never recorded,
+        // rebuild them from the entries instead.
+        if !self.legacy_terms.is_empty() {
+            let mut entries = Vec::new();
+            for r in self.entries.iter_start(txn)? {
+                let (k, v) = r?;
+                entries.push((UUID::from_bytes(k)?, EntryT::decode(v)?));
+            }
+
+            for k in self.legacy_terms.drain(..) {
+                info!("Rebuilding term index for {}", k);
+                let desc = match self.schema.attribute(&k).and_then(|a| a.index.as_ref()) {
+                    Some(d) => d,
+                    None => continue,
+                };
+                Index::create(txn, desc)?;
+                if let Index::Term(mut t) = Index::construct(txn, desc)? {
+                    t.clear(txn)?;
+                    for (uuid, e) in entries.iter() {
+                        if let Some(v) = e.metadata.get(&k) {
+                            t.index(txn, v.to_str().map(|s| &**s), *uuid)?;
+                        }
+                    }
+                    self.indices.insert(k, Index::Term(t));
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn create(txn: &mut RwTransaction, roname: &str, schema: Schema) -> Result<()> {
@@ -75,19 +227,17 @@ impl<'env> Database {
         };
         name.push_str("_schema");
 
-        let schema_size = schema.encoded_size()? as usize;
-        let schema_buf = txn.reserve(db, &name.as_bytes(), schema_size, lmdb::WriteFlags::empty())?;
-        schema.encode_into(schema_buf)?;
+        txn.put(db, &name.as_bytes(), &schema.encode()?, lmdb::WriteFlags::empty())?;
 
         name.replace_range(len.., "_filekeys");
         unsafe {
             txn.create_db(Some(&name), lmdb::DatabaseFlags::empty())?;
         }
 
-        for (k, index) in schema.attributes.iter() {
-            println!("Creating index for {:?}", k);
-            Index::create(txn, db, index).ok();
-            println!("index {:?} created", k);
+        for (k, index) in schema.attributes.iter().filter_map(|(k, a)| a.index.as_ref().map(|i| (k, i))) {
+            println!("Creating index for {}", k);
+            Index::create(txn, index).ok();
+            println!("index {} created", k);
         }
 
         unsafe {
@@ -106,6 +256,8 @@ impl<'env> Database {
     }
 
     pub fn insert(&mut self, txn: &mut RwTransaction, uuid: UUID, entry: &EntryT) -> Result<()> {
+        self.schema.typecheck_entry(entry)?;
+
         // 1: Check if unique
         let mut other: Option<UUID> = None;
         for fk in entry.files.iter() {
@@ -129,6 +281,8 @@ impl<'env> Database {
     }
 
     pub fn insert_raw(&mut self, txn: &mut RwTransaction, uuid: UUID, entry: &EntryT) -> Result<()> {
+        self.schema.typecheck_entry(entry)?;
+
         // 2: Index entry
         for (key, i) in self.indices.iter_mut() {
             if let Some(val) = entry.metadata.get(key) {
@@ -145,10 +299,163 @@ impl<'env> Database {
         Ok(())
     }
 
-    // TODO: Implement this properly ^^'
+    /// Insert an entry like `insert_rand` and index `text`, the body of its files, in all
+    /// full-text indices
+    ///
+    /// If the entry is merged into an existing one, the text replaces the one indexed for that
+    /// entry before. Empty text leaves it as it is.
+    pub fn insert_text(&mut self, txn: &mut RwTransaction, entry: &EntryT, text: &str) -> Result<()> {
+        self.insert_rand(txn, entry)?;
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        match entry.files.iter().next() {
+            Some(file) => {
+                let uuid = self.filekeys.get(txn, &file.key)?;
+                self.index_text(txn, uuid, text)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Index `text` as the body of the entry `uuid` in all full-text indices, each up to its
+    /// `max_size`
+    pub fn index_text(&mut self, txn: &mut RwTransaction, uuid: UUID, text: &str) -> Result<()> {
+        for (key, max_size) in self.schema.fulltext() {
+            let mut end = text.len().min(max_size);
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            if let Some(Index::Term(t)) = self.indices.get_mut(key) {
+                t.index_document(txn, &text[..end], uuid)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge `entry` into the existing entry `other`
+    ///
+    /// Files are unioned, metadata is merged per key using `Metavalue::merge`. Differing values of
+    /// `single` attributes and values of different types are conflicts, resolved according to the
+    /// configured `MergePolicy`. Only fields whose value changed are re-indexed.
     fn merge(&mut self, txn: &mut RwTransaction, other: UUID, entry: &EntryT) -> Result<()> {
-        // FIXME currently this just overwrites the existing one.
-        self.insert_raw(txn, other, entry)
+        let mut existing = self.entries.get(txn, &other)?;
+
+        let mut changed = Vec::new();
+        for (key, new) in entry.metadata.iter() {
+            let single = self.schema.attribute(key).is_some_and(|a| a.single);
+            let merged = match existing.metadata.get(key) {
+                None => new.clone(),
+                Some(old) if single && old != new => match self.merge_policy {
+                    MergePolicy::KeepExisting => continue,
+                    MergePolicy::PreferNew => new.clone(),
+                    MergePolicy::Fail => return Err(Error::MergeConflict),
+                },
+                Some(old) => match old.merge(new) {
+                    Ok(v) => v,
+                    Err(Error::MergeConflict) => match self.merge_policy {
+                        MergePolicy::KeepExisting => continue,
+                        MergePolicy::PreferNew => new.clone(),
+                        MergePolicy::Fail => return Err(Error::MergeConflict),
+                    },
+                    Err(e) => return Err(e),
+                },
+            };
+
+            if existing.metadata.get(key) != Some(&merged) {
+                changed.push((key.clone(), existing.metadata.insert(key.clone(), merged)));
+            }
+        }
+
+        for (key, old) in changed {
+            self.reindex(txn, other, &key, old.as_ref(), existing.metadata.get(&key))?;
+        }
+
+        for file in entry.files.iter() {
+            if existing.files.insert(file.clone()) {
+                self.filekeys.put(txn, &file.key, &other)?;
+            }
+        }
+
+        self.entries.put(txn, &other, &existing)
+    }
+
+    /// Apply a list of metadata changes to an existing entry, keeping the indices consistent
+    pub fn update(&mut self, txn: &mut RwTransaction, uuid: UUID, changes: &[meta::Change]) -> Result<EntryT> {
+        for change in changes.iter() {
+            match change {
+                meta::Change::Set(k, v) | meta::Change::Append(k, v) | meta::Change::Remove(k, v) =>
+                    self.schema.typecheck(k, v)?,
+                meta::Change::Clear(_) => {},
+            }
+        }
+
+        let mut entry = self.entries.get(txn, &uuid)?;
+        let old = entry.metadata.clone();
+
+        for change in changes.iter() {
+            change.apply(&mut entry.metadata)?;
+        }
+
+        let keys: HashSet<&meta::Metakey> = changes.iter().map(meta::Change::key).collect();
+        for key in keys {
+            if old.get(key) != entry.metadata.get(key) {
+                self.reindex(txn, uuid, key, old.get(key), entry.metadata.get(key))?;
+            }
+        }
+
+        self.entries.put(txn, &uuid, &entry)?;
+
+        Ok(entry)
+    }
+
+    /// Replace the index postings of `uuid` for a single key
+    fn reindex(&mut self, txn: &mut RwTransaction, uuid: UUID, key: &meta::Metakey,
+        old: Option<&meta::Metavalue>, new: Option<&meta::Metavalue>) -> Result<()>
+    {
+        if let Some(i) = self.indices.get_mut(key) {
+            if let Some(old) = old {
+                i.unindex(txn, uuid, old)?;
+            }
+            if let Some(new) = new {
+                i.index(txn, uuid, new)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set how conflicting metadata is handled when merging entries
+    pub fn set_merge_policy(&mut self, policy: MergePolicy) {
+        self.merge_policy = policy;
+    }
+
+    /// Remove an entry, its filekeys and all its index postings
+    pub fn remove(&mut self, txn: &mut RwTransaction, uuid: UUID) -> Result<EntryT> {
+        let entry = self.entries.get(txn, &uuid)?;
+
+        for (key, i) in self.indices.iter_mut() {
+            if let Some(val) = entry.metadata.get(key) {
+                i.unindex(txn, uuid, val)?;
+            }
+            if let Index::Term(t) = i {
+                if t.is_fulltext() {
+                    t.unindex_document(txn, uuid)?;
+                }
+            }
+        }
+
+        for file in entry.files.iter() {
+            // Only remove the mapping if it actually points to this entry
+            match self.filekeys.get(txn, &file.key) {
+                Ok(u) if u == uuid => self.filekeys.delete(txn, &file.key)?,
+                Ok(_) | Err(Error::LMDB(lmdb::Error::NotFound)) => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.entries.delete(txn, &uuid)?;
+
+        Ok(entry)
     }
 
     pub fn dump(&self, txn: &RoTransaction) -> Result<()> {
@@ -231,6 +538,7 @@ impl<'env> Database {
 pub enum Index {
     IntMap(RangeDB),
     Term(TermDB),
+    Keyword(KeywordDB),
 }
 
 impl Index {
@@ -239,12 +547,15 @@ impl Index {
         match self {
             Self::IntMap(db) => {
                 for value in entry_v.to_int() {
-                    db.index(txn, *value, uuid)?;
+                    db.index(txn, value, uuid)?;
                 }
             },
             Self::Term(db) => {
-                for term in entry_v.to_str() {
-                    db.index(txn, term.to_string(), uuid)?;
+                db.index(txn, entry_v.to_str().map(|s| &**s), uuid)?;
+            }
+            Self::Keyword(db) => {
+                for value in entry_v.to_str() {
+                    db.index(txn, value, uuid)?;
                 }
             }
         }
@@ -252,36 +563,83 @@ impl Index {
     }
 
     #[inline]
-    pub fn construct<'txn, T: Transaction> (txn: &'txn T, db: lmdb::Database, desc: &IndexDescription) 
+    pub fn unindex(&mut self, txn: &mut RwTransaction, uuid: UUID, entry_v: &meta::Metavalue) -> Result<()> {
+        match self {
+            Self::IntMap(db) => {
+                for value in entry_v.to_int() {
+                    db.unindex(txn, value, uuid)?;
+                }
+            },
+            Self::Term(db) => {
+                db.unindex(txn, entry_v.to_str().map(|s| &**s), uuid)?;
+            }
+            Self::Keyword(db) => {
+                for value in entry_v.to_str() {
+                    db.unindex(txn, value, uuid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn construct<'txn, T: Transaction> (txn: &'txn T, desc: &IndexDescription) 
         -> Result<Self> 
     {
         match desc {
             IndexDescription::RangeTree { name } => {
-                let bytes = txn.get(db, name)?;
-                let map = RangeDB::decode(bytes)?;
-                Ok(Self::IntMap(RangeDB::new(db, name.clone(), map)))
+                let db = unsafe { txn.open_db(Some(name))? };
+                Ok(Self::IntMap(RangeDB::new(db)))
             },
-            IndexDescription::StemmedTerm { dbname } => {
+            IndexDescription::StemmedTerm { dbname, language, stopwords, tokenizer } => {
+                let db = unsafe { txn.open_db(Some(dbname))? };
+                let lengths = unsafe { txn.open_db(Some(&TermDB::lengths_name(dbname)))? };
+                let analyzer = term::Analyzer::new(*language, stopwords.as_deref(), *tokenizer);
+                Ok(Self::Term(TermDB::new(db, lengths, analyzer)))
+            }
+            IndexDescription::Keyword { name } => {
+                let db = unsafe { txn.open_db(Some(name))? };
+                Ok(Self::Keyword(KeywordDB::new(db)))
+            }
+            IndexDescription::FullText { dbname, language, stopwords, tokenizer, .. } => {
                 let db = unsafe { txn.open_db(Some(dbname))? };
-                Ok(Self::Term(TermDB::new(db)))
+                let lengths = unsafe { txn.open_db(Some(&TermDB::lengths_name(dbname)))? };
+                let documents = unsafe { txn.open_db(Some(&TermDB::documents_name(dbname)))? };
+                let analyzer = term::Analyzer::new(*language, stopwords.as_deref(), *tokenizer);
+                Ok(Self::Term(TermDB::with_documents(db, lengths, documents, analyzer)))
             }
         }
     }
 
     #[inline]
-    pub fn create(txn: &mut RwTransaction, db: lmdb::Database, desc: &IndexDescription) 
+    pub fn create(txn: &mut RwTransaction, desc: &IndexDescription) 
         -> Result<()>
     {
         match desc {
             IndexDescription::RangeTree { name } => {
-                let buf = txn.reserve(db, name, RangeDB::empty_encoded_size()? as usize, 
-                    lmdb::WriteFlags::empty())?;
-                RangeDB::empty_encode_into(buf)?;
+                unsafe {
+                    txn.create_db(Some(name), RangeDB::flags())?;
+                }
                 Ok(())
             },
-            IndexDescription::StemmedTerm { dbname } => {
+            IndexDescription::StemmedTerm { dbname, .. } => {
                 unsafe {
                     txn.create_db(Some(dbname), lmdb::DatabaseFlags::empty())?;
+                    txn.create_db(Some(&TermDB::lengths_name(dbname)), lmdb::DatabaseFlags::empty())?;
+                }
+                Ok(())
+            }
+            IndexDescription::Keyword { name } => {
+                unsafe {
+                    txn.create_db(Some(name), KeywordDB::flags())?;
+                }
+                Ok(())
+            }
+            IndexDescription::FullText { dbname, .. } => {
+                unsafe {
+                    txn.create_db(Some(dbname), lmdb::DatabaseFlags::empty())?;
+                    txn.create_db(Some(&TermDB::lengths_name(dbname)), lmdb::DatabaseFlags::empty())?;
+                    txn.create_db(Some(&TermDB::documents_name(dbname)), lmdb::DatabaseFlags::empty())?;
                 }
                 Ok(())
             }
@@ -291,11 +649,14 @@ impl Index {
     pub fn list<'txn, T: Transaction>(&self, txn: &'txn T) -> Result<()> {
         match self {
             Self::IntMap(db) => {
-                db.list()
+                db.list(txn)
             },
             Self::Term(db) => {
                 db.list(txn)
             }
+            Self::Keyword(db) => {
+                db.list(txn)
+            }
         }
     }
 }
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::str::FromStr;
+use std::convert::TryInto;
+use std::ops::{Bound, RangeBounds};
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime};
+use serde::{Serialize, Deserialize, Serializer, Deserializer, de};
+
+use crate::error::{Result, Error};
+
+/// Length in seconds of the longest period a date can cover, a leap year
+pub const MAX_SPAN: i64 = 366 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How much of a date is known
+pub enum Precision {
+    Year,
+    Month,
+    Day,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A point in time known to a certain precision, such as just the year a record was released
+///
+/// Stored as the first second of the period it covers, in UTC. Written as `1999`, `1999-06`,
+/// `1999-06-12` or `1999-06-12T20:15:00Z` depending on the precision.
+///
+/// Ranges match a date if they overlap the period it covers, so `1984` is within `1984-06..1984-12`.
+pub struct Date {
+    timestamp: i64,
+    precision: Precision,
+}
+
+impl Date {
+    /// The date starting at `timestamp`, truncated to `precision`
+    pub fn new(timestamp: i64, precision: Precision) -> Result<Self> {
+        let t = DateTime::from_timestamp(timestamp, 0)
+            .ok_or_else(|| Error::BadDate(timestamp.to_string()))?
+            .naive_utc();
+        let day = match precision {
+            Precision::Year => t.date().with_day(1).and_then(|d| d.with_month(1)),
+            Precision::Month => t.date().with_day(1),
+            Precision::Day | Precision::Second => Some(t.date()),
+        };
+        let timestamp = match precision {
+            Precision::Second => timestamp,
+            _ => start_of(day)?,
+        };
+        Ok(Self { timestamp, precision })
+    }
+
+    pub fn ymd(year: i32, month: Option<u32>, day: Option<u32>) -> Result<Self> {
+        let precision = match (month, day) {
+            (None, _) => Precision::Year,
+            (Some(_), None) => Precision::Month,
+            (Some(_), Some(_)) => Precision::Day,
+        };
+        let date = NaiveDate::from_ymd_opt(year, month.unwrap_or(1), day.unwrap_or(1));
+        Ok(Self { timestamp: start_of(date)?, precision })
+    }
+
+    pub fn year(&self) -> i32 {
+        self.naive().year()
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    /// First second of the period this date covers, in seconds since the UNIX epoch
+    pub fn start(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Last second of the period this date covers, in seconds since the UNIX epoch
+    pub fn end(&self) -> i64 {
+        let d = self.naive().date();
+        let next = match self.precision {
+            Precision::Year => NaiveDate::from_ymd_opt(d.year() + 1, 1, 1),
+            Precision::Month if d.month() == 12 => NaiveDate::from_ymd_opt(d.year() + 1, 1, 1),
+            Precision::Month => NaiveDate::from_ymd_opt(d.year(), d.month() + 1, 1),
+            Precision::Day => d.succ_opt(),
+            Precision::Second => return self.timestamp,
+        };
+        start_of(next).map_or(i64::MAX, |t| t - 1)
+    }
+
+    /// Check if any second of the period this date covers is within `r`
+    pub fn overlaps<R: RangeBounds<i64>>(&self, r: &R) -> bool {
+        let after_start = match r.start_bound() {
+            Bound::Included(l) => self.end() >= *l,
+            Bound::Excluded(l) => self.end() > *l,
+            Bound::Unbounded => true,
+        };
+        let before_end = match r.end_bound() {
+            Bound::Included(u) => self.start() <= *u,
+            Bound::Excluded(u) => self.start() < *u,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    fn naive(&self) -> NaiveDateTime {
+        // Only constructed from valid dates
+        DateTime::from_timestamp(self.timestamp, 0).unwrap_or_default().naive_utc()
+    }
+}
+
+fn start_of(date: Option<NaiveDate>) -> Result<i64> {
+    date.and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|t| t.and_utc().timestamp())
+        .ok_or_else(|| Error::BadDate(format!("{:?}", date)))
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = self.naive();
+        match self.precision {
+            Precision::Year => write!(f, "{:04}", t.year()),
+            Precision::Month => write!(f, "{:04}-{:02}", t.year(), t.month()),
+            Precision::Day => write!(f, "{}", t.format("%Y-%m-%d")),
+            Precision::Second => write!(f, "{}", t.format("%Y-%m-%dT%H:%M:%SZ")),
+        }
+    }
+}
+
+impl FromStr for Date {
+    type Err = Error;
+
+    /// Parse ISO 8601 dates of any precision
+    ///
+    /// EXIF style dates using colons as in `1999:06:12 20:15:00` are accepted as well. Times
+    /// without an offset are taken to be UTC.
+    fn from_str(s: &str) -> Result<Self> {
+        let bad = || Error::BadDate(s.to_string());
+        let s = s.trim();
+
+        // EXIF separates the parts of the date by colons as well
+        let mut iso = s.to_string();
+        if s.len() >= 10 && s.is_char_boundary(10) && s.as_bytes()[4] == b':' && s.as_bytes()[7] == b':' {
+            iso.replace_range(4..5, "-");
+            iso.replace_range(7..8, "-");
+        }
+
+        if iso.len() > 10 {
+            if let Ok(t) = DateTime::parse_from_rfc3339(&iso) {
+                return Self::new(t.timestamp(), Precision::Second);
+            }
+            for fmt in ["%Y-%m-%d %H:%M:%S%:z", "%Y-%m-%d %H:%M:%S%z"].iter() {
+                if let Ok(t) = DateTime::parse_from_str(&iso, fmt) {
+                    return Self::new(t.timestamp(), Precision::Second);
+                }
+            }
+            for fmt in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"].iter() {
+                if let Ok(t) = NaiveDateTime::parse_from_str(&iso, fmt) {
+                    return Self::new(t.and_utc().timestamp(), Precision::Second);
+                }
+            }
+            return Err(bad());
+        }
+
+        let mut parts = iso.splitn(3, '-');
+        let year = parts.next()
+            .filter(|y| y.len() == 4)
+            .and_then(|y| y.parse().ok())
+            .ok_or_else(bad)?;
+        let mut num = |len: usize| match parts.next() {
+            None => Ok(None),
+            Some(p) if p.len() == len => p.parse().map(Some).map_err(|_| bad()),
+            Some(_) => Err(bad()),
+        };
+        let month = num(2)?;
+        let day = num(2)?;
+        Self::ymd(year, month, day).map_err(|_| bad())
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        struct DateVisitor;
+
+        impl<'de> de::Visitor<'de> for DateVisitor {
+            type Value = Date;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a date like 1999, 1999-06 or 1999-06-12")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Date, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            // YAML reads a bare year as a number
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Date, E> {
+                let year = v.try_into().map_err(E::custom)?;
+                Date::ymd(year, None, None).map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Date, E> {
+                let year = v.try_into().map_err(E::custom)?;
+                Date::ymd(year, None, None).map_err(E::custom)
+            }
+        }
+
+        if d.is_human_readable() {
+            d.deserialize_any(DateVisitor)
+        } else {
+            d.deserialize_str(DateVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precision() {
+        let d: Date = "1999".parse().unwrap();
+        assert_eq!(d.precision(), Precision::Year);
+        assert_eq!(d.start(), 915148800);
+        assert_eq!(d.end(), 946684799);
+        assert_eq!(d.to_string(), "1999");
+
+        let d: Date = "1999-12".parse().unwrap();
+        assert_eq!(d.end(), 946684799);
+        assert_eq!(d.to_string(), "1999-12");
+
+        let d: Date = "2015:01:01".parse().unwrap();
+        assert_eq!(d.precision(), Precision::Day);
+        assert_eq!(d.end() - d.start(), 86399);
+
+        let d: Date = "2003:05:12 20:15:00+02:00".parse().unwrap();
+        assert_eq!(d.to_string(), "2003-05-12T18:15:00Z");
+        assert_eq!(d.start(), d.end());
+
+        assert!("1999-13".parse::<Date>().is_err());
+        assert!("99".parse::<Date>().is_err());
+        assert!("0000:00:00 00:00:00".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn overlaps() {
+        let d: Date = "1984".parse().unwrap();
+        let june: Date = "1984-06".parse().unwrap();
+        assert!(d.overlaps(&(june.start()..=june.end())));
+        assert!(d.overlaps(&(..june.start())));
+        assert!(!d.overlaps(&(d.end() + 1..)));
+        assert!(!d.overlaps(&(..d.start())));
+        assert!(june.overlaps(&(d.start()..=d.end())));
+    }
+
+    #[test]
+    fn serialized() {
+        let d = Date::ymd(1999, Some(6), None).unwrap();
+        assert_eq!(serde_yaml::to_string(&d).unwrap(), "---\n1999-06\n");
+        assert_eq!(serde_yaml::from_str::<Date>("1999").unwrap(), Date::ymd(1999, None, None).unwrap());
+        let b = bincode::serialize(&d).unwrap();
+        assert_eq!(bincode::deserialize::<Date>(&b).unwrap(), d);
+    }
+}
@@ -23,8 +23,47 @@ pub use lmdb::{
 
 use std::path::Path;
 
+use crate::db::stats::{EnvironmentStats, PageStats};
+
+/// Iterator over a database that keeps its cursor open for as long as it is in use
+///
+/// `lmdb::Iter` only holds a raw pointer to its cursor, so the cursor must not be dropped while
+/// iterating.
+pub struct CursorIter<'txn> {
+    iter: Iter<'txn>,
+    _cursor: RoCursor<'txn>,
+}
+
+impl<'txn> CursorIter<'txn> {
+    pub fn start<T: Transaction>(txn: &'txn T, db: Database) -> Result<Self> {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        let iter = cursor.iter_start();
+        Ok(Self { iter, _cursor: cursor })
+    }
+
+    pub fn from<T: Transaction, K: AsRef<[u8]>>(txn: &'txn T, db: Database, key: K) -> Result<Self> {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        let iter = cursor.iter_from(key);
+        Ok(Self { iter, _cursor: cursor })
+    }
+}
+
+impl<'txn> Iterator for CursorIter<'txn> {
+    type Item = lmdb::Result<(&'txn [u8], &'txn [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter.next() {
+            // Iterating an empty database reports NotFound instead of just ending
+            Some(Err(lmdb::Error::NotFound)) => None,
+            r => r,
+        }
+    }
+}
+
 pub struct DBManager {
-    env: Environment
+    env: Environment,
+    /// Largest size `write_with` may grow the map to
+    max_map_size: Option<usize>,
 }
 
 impl DBManager {
@@ -34,10 +73,22 @@ impl DBManager {
 
     pub fn from_builder(path: &Path, env: EnvironmentBuilder) -> Result<Self> {
         Ok(DBManager {
-            env: env.open(path).map_err(Error::LMDB)?
+            env: env.open(path).map_err(Error::LMDB)?,
+            max_map_size: None,
         })
     }
 
+    /// Limit how large the map may be grown when a transaction runs out of space, `None` to
+    /// grow it without limit
+    pub fn set_max_map_size(&mut self, max: Option<usize>) {
+        self.max_map_size = max;
+    }
+
+    /// Current size of the memory map in bytes
+    pub fn map_size(&self) -> Result<usize> {
+        Ok(self.env.info()?.map_size())
+    }
+
     pub fn open(&self) -> Result<lmdb::Database> {
         self.env.open_db(None).map_err(Error::LMDB)
     }
@@ -61,4 +112,59 @@ impl DBManager {
     pub fn write(&self) -> Result<RwTransaction> {
         self.env.begin_rw_txn().map_err(Error::LMDB)
     }
+
+    /// Run `f` in a write transaction and commit it
+    ///
+    /// If the map fills up, either while running `f` or while committing, the transaction is
+    /// aborted, the map doubled in size and `f` run again in a new transaction. `f` must therefore
+    /// not have effects outside of the transaction, or be fine with repeating them. Once the map
+    /// can't grow past the maximum set with `set_max_map_size` the `MapFull` error is returned.
+    ///
+    /// Must not be called while this process holds any other transaction open on the environment.
+    pub fn write_with<T, F>(&self, mut f: F) -> Result<T>
+        where F: FnMut(&mut RwTransaction) -> Result<T>
+    {
+        loop {
+            let r = match self.write() {
+                Ok(mut txn) => match f(&mut txn) {
+                    Ok(v) => txn.commit().map(|()| v).map_err(Error::LMDB),
+                    // Dropping the transaction aborts it
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match r {
+                Err(e) if e.is_map_full() => self.grow()?,
+                // Another process has grown the map, adopt its size
+                Err(Error::LMDB(lmdb::Error::MapResized)) => self.env.set_map_size(0)?,
+                r => return r,
+            }
+        }
+    }
+
+    /// Double the size of the map, up to the maximum map size
+    fn grow(&self) -> Result<()> {
+        let size = self.map_size()?;
+        let mut new = size.saturating_mul(2);
+        if let Some(max) = self.max_map_size {
+            if size >= max {
+                return Err(Error::LMDB(lmdb::Error::MapFull));
+            }
+            new = new.min(max);
+        }
+        info!("Map is full, growing it from {} to {} bytes", size, new);
+        self.env.set_map_size(new)?;
+        Ok(())
+    }
+
+    /// Page usage of the whole environment
+    pub fn stats(&self) -> Result<EnvironmentStats> {
+        let pages: PageStats = self.env.stat()?.into();
+        let info = self.env.info()?;
+        Ok(EnvironmentStats {
+            pages,
+            map_size: info.map_size(),
+            used: (info.last_pgno() + 1) * pages.page_size as usize,
+        })
+    }
 }
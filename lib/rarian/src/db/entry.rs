@@ -33,8 +33,12 @@ use libc::size_t;
 
 use crate::db::dbm::DBManager;
 
-use crate::db::meta::{Metakey, Metavalue};
+use crate::db::meta::{Metakey, Metavalue, Change, legacy};
+use crate::schema::strict;
+use bincode::Options;
+use crate::db::stats::{self, PageStats};
 use crate::error::{Result, Error};
+use crate::db::dbm::CursorIter;
 use crate::uuid::{UUID, Uuid};
 
 pub type FileKey = String;
@@ -43,6 +47,14 @@ pub type FileKey = String;
 pub enum FormatKey {
     /// MIME type of the given file
     MimeType,
+    /// Name of the container or file format, such as `FLAC` or `MP4`
+    Container,
+    /// Playing time in seconds
+    Duration,
+    /// Audio sample rate in Hz
+    SampleRate,
+    /// Number of audio channels
+    Channels,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -97,10 +109,25 @@ impl fmt::Display for FileT {
 /// Vorbis comments or ID3 tags attached / not attached should be the same entry.
 pub struct EntryT {
     pub files: HashSet<FileT>,
-    /// Metadata is an arbitrary key-value map
-    #[serde(serialize_with = "map_to_list", deserialize_with = "list_to_map") ]
+    /// Metadata is a key-value map with the keys defined by the database's schema
     pub metadata: HashMap<Metakey, Metavalue>,
 }
+
+#[derive(Deserialize)]
+/// Entry as written before attributes were user-defined
+struct LegacyEntryT {
+    files: HashSet<FileT>,
+    metadata: Vec<legacy::Metavalue>,
+}
+
+impl LegacyEntryT {
+    fn convert(self) -> EntryT {
+        let metadata = self.metadata.into_iter()
+            .map(legacy::Metavalue::convert)
+            .collect();
+        EntryT::newv(self.files, metadata)
+    }
+}
 impl EntryT {
     pub fn new(filekey: FileT, metadata: HashMap<Metakey, Metavalue>) -> Self {
         let mut set = HashSet::new();
@@ -116,8 +143,20 @@ impl EntryT {
         }
     }
 
+    /// Decode an entry, also accepting entries written before attributes were user-defined
     pub fn decode(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).map_err(Error::Bincode)
+        Self::decode_compat(bytes).map(|(e, _)| e)
+    }
+
+    /// Decode an entry; the returned flag is set if the entry was in the old format.
+    pub fn decode_compat(bytes: &[u8]) -> Result<(Self, bool)> {
+        match strict().deserialize(bytes) {
+            Ok(e) => Ok((e, false)),
+            Err(e) => match strict().deserialize::<LegacyEntryT>(bytes) {
+                Ok(l) => Ok((l.convert(), true)),
+                Err(_) => Err(Error::Bincode(e)),
+            }
+        }
     }
 
     pub fn encode_into(&self, bytes: &mut [u8]) -> Result<()> {
@@ -131,10 +170,32 @@ impl EntryT {
     pub fn to_yaml(&self) -> std::result::Result<String, serde_yaml::Error> {
         serde_yaml::to_string(self)
     }
+
+    /// Changes required to turn the metadata of `self` into the metadata of `other`
+    pub fn diff(&self, other: &EntryT) -> Vec<Change> {
+        let mut changes: Vec<Change> = other.metadata.iter()
+            .filter(|(k, v)| self.metadata.get(*k) != Some(*v))
+            .map(|(k, v)| Change::Set(k.clone(), v.clone()))
+            .collect();
+
+        changes.extend(self.metadata.keys()
+            .filter(|k| !other.metadata.contains_key(*k))
+            .map(|k| Change::Clear(k.clone())));
+
+        changes
+    }
 }
 
 pub fn from_yaml(s: &[u8]) -> std::result::Result<EntryT, serde_yaml::Error> {
-    serde_yaml::from_slice(s)
+    let v: serde_yaml::Value = serde_yaml::from_slice(s)?;
+
+    // Old exports stored metadata as a list of tagged values instead of a map
+    if v.get("metadata").map(serde_yaml::Value::is_sequence).unwrap_or(false) {
+        let l: LegacyEntryT = serde_yaml::from_value(v)?;
+        Ok(l.convert())
+    } else {
+        serde_yaml::from_value(v)
+    }
 }
 
 impl fmt::Display for EntryT {
@@ -144,8 +205,8 @@ impl fmt::Display for EntryT {
             write!(f, "\t\t{}\n", file)?;
         }
         write!(f, "\tMetadata:\n")?;
-        for meta in self.metadata.values() {
-            write!(f, "\t\t{}\n", meta)?;
+        for (key, meta) in self.metadata.iter() {
+            write!(f, "\t\t{}: {}\n", key, meta)?;
         }
 
         Ok(())
@@ -186,9 +247,17 @@ impl EntryDB {
         self.get_bytes(txn, &key.as_bytes()).and_then(EntryT::decode)
     }
 
-    pub fn iter_start<'txn, T: Transaction>(self, txn: &'txn T) -> Result<Iter<'txn>> {
-        let mut cursor = txn.open_ro_cursor(self.db)?;
-        Ok(cursor.iter_start())
+    pub fn delete(self, txn: &mut RwTransaction, key: &UUID) -> Result<()> {
+        txn.del(self.db, &key.as_bytes(), None).map_err(Error::LMDB)
+    }
+
+    pub fn iter_start<'txn, T: Transaction>(self, txn: &'txn T) -> Result<CursorIter<'txn>> {
+        CursorIter::start(txn, self.db)
+    }
+
+    /// Page usage of the backing database
+    pub fn stat<T: Transaction>(&self, txn: &T) -> Result<PageStats> {
+        stats::page_stats(txn, self.db)
     }
 
     pub fn list<'txn, T: Transaction>(&self, txn: &'txn T) -> Result<()> {
@@ -214,24 +283,6 @@ impl EntryDB {
     }
 }
 
-fn list_to_map<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<HashMap<Metakey, Metavalue>, D::Error> {
-    let mut map = HashMap::new();
-    let v = Vec::<Metavalue>::deserialize(deserializer)?;
-    for value in v {
-        map.insert(value.to_key(), value);
-    }
-
-    Ok(map)
-}
-fn map_to_list<S: Serializer>(map: &HashMap<Metakey, Metavalue>, serializer: S) -> std::result::Result<S::Ok, S::Error>{
-    let list: Vec<Metavalue> = map.values().map(|v| v.clone()).collect();
-    let mut seq = serializer.serialize_seq(Some(list.len()))?;
-    for element in list.iter() {
-        seq.serialize_element(&element)?;
-    }
-    seq.end()
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -247,9 +298,9 @@ mod tests {
         });
 
         let mut metadata = HashMap::new();
-        metadata.insert(Metakey::Title, Metavalue::Title("Leviathan".to_string().into_boxed_str()));
-        metadata.insert(Metakey::Artist, Metavalue::Artist("blinch".to_string().into_boxed_str()));
-        metadata.insert(Metakey::TrackNumber, Metavalue::TrackNumber(20));
+        metadata.insert(Metakey::new("title"), Metavalue::Str(vec!["Leviathan".to_string().into_boxed_str()].into_boxed_slice()));
+        metadata.insert(Metakey::new("artist"), Metavalue::Str(vec!["blinch".to_string().into_boxed_str()].into_boxed_slice()));
+        metadata.insert(Metakey::new("tracknumber"), Metavalue::Int(vec![20].into_boxed_slice()));
 
         let e = EntryT::newv(files, metadata);
 
@@ -258,4 +309,13 @@ mod tests {
 
         assert_eq!(e, e2);
     }
+
+    #[test]
+    fn decode_legacy_yaml() {
+        let yaml = b"---\nfiles:\n  - key: abc\n    format: {}\nmetadata:\n  - Title:\n      - Leviathan\n  - TrackNumber:\n      - 20\n";
+        let e = from_yaml(yaml).expect("Failed to decode old export");
+
+        assert_eq!(e.metadata[&Metakey::new("title")], Metavalue::Str(vec!["Leviathan".into()].into_boxed_slice()));
+        assert_eq!(e.metadata[&Metakey::new("tracknumber")], Metavalue::Int(vec![20].into_boxed_slice()));
+    }
 }
@@ -9,6 +9,7 @@ use lmdb::{
     WriteFlags,
 };
 
+use crate::db::stats::{self, PageStats};
 use crate::error::{Result, Error};
 
 use crate::db::entry::FileKey;
@@ -49,4 +50,13 @@ impl FilekeyDB {
         self.get_bytes(txn, &key.as_bytes()).and_then(UUID::from_bytes)
     }
 
+    /// Page usage of the backing database
+    pub fn stat<T: Transaction>(&self, txn: &T) -> Result<PageStats> {
+        stats::page_stats(txn, self.db)
+    }
+
+    pub fn delete(self, txn: &mut RwTransaction, key: &FileKey) -> Result<()> {
+        txn.del(self.db, &key.as_bytes(), None).map_err(Error::LMDB)
+    }
+
 }
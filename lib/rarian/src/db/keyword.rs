@@ -0,0 +1,205 @@
+use std::collections::HashSet;
+
+use lmdb::{
+    Database,
+    Transaction,
+    RwTransaction,
+    WriteFlags,
+    Cursor,
+};
+
+use crate::uuid::UUID;
+use crate::error::{Result, Error};
+use crate::db::term::{glob_match, within_edits};
+use crate::db::stats::{self, IndexStats};
+
+/// Longest key LMDB accepts with its default page size
+pub const MAX_KEY: usize = 511;
+
+#[derive(Debug, Copy, Clone)]
+/// Index over whole string values
+///
+/// Every normalized value is a key in its own LMDB database, mapping to a sorted set of UUIDs via
+/// `DUP_SORT`. Unlike a term index values aren't split into words or stemmed, so "The The" is
+/// found as exactly that.
+pub struct KeywordDB {
+    db: Database,
+}
+
+impl KeywordDB {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Flags the backing LMDB database has to be created with
+    pub fn flags() -> lmdb::DatabaseFlags {
+        lmdb::DatabaseFlags::DUP_SORT
+    }
+
+    pub fn index(&mut self, txn: &mut RwTransaction, value: &str, uuid: UUID) -> Result<()> {
+        let key = encode_key(value);
+        if key.is_empty() {
+            return Ok(());
+        }
+        match txn.put(self.db, &key, &uuid.as_bytes(), WriteFlags::NO_DUP_DATA) {
+            Ok(()) | Err(lmdb::Error::KeyExist) => Ok(()),
+            Err(e) => Err(Error::LMDB(e)),
+        }
+    }
+
+    /// Remove `uuid` from the postings of `value`. Missing postings are not an error.
+    pub fn unindex(&mut self, txn: &mut RwTransaction, value: &str, uuid: UUID) -> Result<()> {
+        let key = encode_key(value);
+        if key.is_empty() {
+            return Ok(());
+        }
+        match txn.del(self.db, &key, Some(&uuid.as_bytes())) {
+            Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+            Err(e) => Err(Error::LMDB(e)),
+        }
+    }
+
+    /// All UUIDs with a value equal to `value` after normalization
+    ///
+    /// Values longer than `MAX_KEY` are indexed truncated, so for those this can also return
+    /// UUIDs of values that only share the first `MAX_KEY` bytes.
+    pub fn equals<T: Transaction>(&self, txn: &T, value: &str) -> Result<HashSet<UUID>> {
+        let key = encode_key(value);
+        self.scan(txn, &key, |k| k == key)
+    }
+
+    /// All UUIDs with a value starting with `prefix`
+    pub fn prefix<T: Transaction>(&self, txn: &T, prefix: &str) -> Result<HashSet<UUID>> {
+        let prefix = normalize(prefix);
+        self.scan(txn, &prefix, |_| true)
+    }
+
+    /// All UUIDs with a value matching `pattern`, where `*` matches any number of characters and
+    /// `?` exactly one
+    pub fn wildcard<T: Transaction>(&self, txn: &T, pattern: &str) -> Result<HashSet<UUID>> {
+        let pattern = normalize(pattern);
+        let prefix = match pattern.find(['*', '?']) {
+            Some(i) => &pattern[..i],
+            None => &pattern[..],
+        };
+        self.scan(txn, prefix, |k| glob_match(&pattern, k))
+    }
+
+    /// All UUIDs with a value at most `max_edits` edits away from `value`
+    pub fn fuzzy<T: Transaction>(&self, txn: &T, value: &str, max_edits: u32) -> Result<HashSet<UUID>> {
+        let value = normalize(value);
+        self.scan(txn, "", |k| within_edits(&value, k, max_edits))
+    }
+
+    /// UUIDs of all values starting with `prefix` that `pred` accepts
+    fn scan<T: Transaction, F: Fn(&str) -> bool>(&self, txn: &T, prefix: &str, pred: F) -> Result<HashSet<UUID>> {
+        let mut out = HashSet::new();
+        let mut cursor = txn.open_ro_cursor(self.db)?;
+        // LMDB rejects empty keys, even just for positioning a cursor
+        let iter = if prefix.is_empty() {
+            cursor.iter_start()
+        } else {
+            cursor.iter_from(prefix)
+        };
+
+        for res in iter {
+            let (k, v) = match res {
+                Ok(kv) => kv,
+                // An empty database has nothing to iterate over
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(Error::LMDB(e)),
+            };
+            let k = std::str::from_utf8(k)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            if pred(k) {
+                out.insert(UUID::from_bytes(v)?);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Number of distinct values and postings, and the `top` values shared by the most entries
+    pub fn summary<T: Transaction>(&self, txn: &T, top: usize) -> Result<IndexStats> {
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        let mut cursor = txn.open_ro_cursor(self.db)?;
+        for res in cursor.iter_start() {
+            let (k, _) = match res {
+                Ok(kv) => kv,
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(Error::LMDB(e)),
+            };
+            let k = std::str::from_utf8(k)?;
+            match counts.last_mut() {
+                Some((last, n)) if last == k => *n += 1,
+                _ => counts.push((k.to_string(), 1)),
+            }
+        }
+
+        let pages = stats::page_stats(txn, self.db)?;
+        Ok(IndexStats::Keyword {
+            values: counts.len(),
+            postings: pages.entries,
+            largest: stats::largest(counts, top),
+            pages,
+        })
+    }
+
+    pub fn list<T: Transaction>(&self, txn: &T) -> Result<()> {
+        let mut cursor = txn.open_ro_cursor(self.db)?;
+        for res in cursor.iter_start() {
+            let (k, v) = match res {
+                Ok(kv) => kv,
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(Error::LMDB(e)),
+            };
+            println!("{}:\t{}", std::str::from_utf8(k)?, UUID::from_bytes(v)?.as_uuid())
+        }
+        Ok(())
+    }
+}
+
+/// Normalize a value for comparison: lowercased, with runs of whitespace collapsed to a single
+/// space and leading and trailing whitespace removed
+pub fn normalize(value: &str) -> String {
+    value.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Normalized key of `value`, truncated to `MAX_KEY` bytes at a character boundary
+fn encode_key(value: &str) -> String {
+    let mut key = normalize(value);
+    truncate_key(&mut key);
+    key
+}
+
+/// Shorten `key` to at most `MAX_KEY` bytes, at a character boundary
+pub(crate) fn truncate_key(key: &mut String) {
+    if key.len() > MAX_KEY {
+        let mut end = MAX_KEY;
+        while !key.is_char_boundary(end) {
+            end -= 1;
+        }
+        key.truncate(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_keys() {
+        assert_eq!(normalize("  The   The "), "the the");
+        assert_eq!(normalize("Sigur\tRós"), "sigur rós");
+
+        let long = "ö".repeat(300);
+        let key = encode_key(&long);
+        assert!(key.len() <= MAX_KEY);
+        assert!(long.starts_with(&key));
+    }
+}
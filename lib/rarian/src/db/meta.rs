@@ -7,90 +7,274 @@ use serde::de::{Deserializer, Visitor, MapAccess};
 use serde::{Serialize, Deserialize};
 
 use crate::error::{Result, Error};
+use crate::schema::{Schema, Attributetype};
+use crate::db::date::Date;
 
-//pub type Metakey = u32;
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum Metakey {
-    Title,
-    Artist,
-    Date,
-    Comment,
-    Description,
-    Album,
-    TrackNumber,
-    Albumartist,
-    Author,
+/// Apply a function taking two lists of the same type to two `Metavalue`s of the same variant
+macro_rules! zip_lists {
+    ($a:expr, $b:expr, $f:ident) => {
+        match ($a, $b) {
+            (Metavalue::Str(a), Metavalue::Str(b)) => $f(a, b).map(Metavalue::Str),
+            (Metavalue::Int(a), Metavalue::Int(b)) => $f(a, b).map(Metavalue::Int),
+            (Metavalue::Timestamp(a), Metavalue::Timestamp(b)) => $f(a, b).map(Metavalue::Timestamp),
+            (Metavalue::Date(a), Metavalue::Date(b)) => $f(a, b).map(Metavalue::Date),
+            _ => Err(Error::TypeError),
+        }
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+/// Name of a metadata attribute
+///
+/// The set of valid keys and their types is defined by the `Schema` of a database.
+pub struct Metakey(Box<str>);
+
 impl Metakey {
-    pub fn from_str(s: &str) -> Result<Metakey> {
-        match s {
-            "title" => Ok(Metakey::Title),
-            "artist" => Ok(Metakey::Artist),
-            "date" => Ok(Metakey::Date),
-            "comment" => Ok(Metakey::Comment),
-            "description" => Ok(Metakey::Description),
-            "album" => Ok(Metakey::Album),
-            "tracknumber" => Ok(Metakey::TrackNumber),
-            "albumartist" => Ok(Metakey::Albumartist),
-            "author" => Ok(Metakey::Author),
-            _ => Err(Error::BadMetakey)
+    pub fn new<S: Into<Box<str>>>(name: S) -> Self {
+        Self(name.into())
+    }
+
+    /// Resolve a key name using the attributes declared in `schema`
+    ///
+    /// Exact matches are preferred, otherwise names are compared case-insensitively.
+    pub fn from_str(s: &str, schema: &Schema) -> Result<Metakey> {
+        if let Some((k, _)) = schema.attributes.get_key_value(s) {
+            return Ok(k.clone());
         }
+        schema.attributes.keys()
+            .find(|k| k.as_str().eq_ignore_ascii_case(s))
+            .cloned()
+            .ok_or(Error::BadMetakey)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::borrow::Borrow<str> for Metakey {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Metakey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+/// The values of a single metadata attribute
 pub enum Metavalue {
-    Title(Box<[Box<str>]>),
-    Artist(Box<[Box<str>]>),
-    Date(Box<[i64]>),
-    Comment(Box<[Box<str>]>),
-    Description(Box<[Box<str>]>),
-    Album(Box<[Box<str>]>),
-    TrackNumber(Box<[i64]>),
-    Albumartist(Box<[Box<str>]>),
-    Author(Box<[Box<str>]>),
+    Str(Box<[Box<str>]>),
+    Int(Box<[i64]>),
+    /// Seconds since the UNIX epoch
+    Timestamp(Box<[i64]>),
+    /// Calendar dates, possibly only known to the year or month
+    Date(Box<[Date]>),
 }
 
 impl Metavalue {
-    pub fn to_int(&self) -> impl Iterator<Item=&i64> {
+    /// Integer values, for dates the first second they cover
+    pub fn to_int(&self) -> impl Iterator<Item=i64> + '_ {
+        let (ints, dates): (&[i64], &[Date]) = match self {
+            Self::Int(i) | Self::Timestamp(i) => (i, &[]),
+            Self::Date(d) => (&[], d),
+            Self::Str(_) => (&[], &[]),
+        };
+        ints.iter().copied().chain(dates.iter().map(Date::start))
+    }
+
+    pub fn to_date(&self) -> impl Iterator<Item=&Date> {
         match self {
-            Self::Date(i) => i.iter(),
-            Self::TrackNumber(i) => i.iter(),
+            Self::Date(d) => d.iter(),
             _ => [].iter(),
         }
     }
 
     pub fn to_str(&self) -> impl Iterator<Item=&Box<str>> {
         match self {
-            Self::Title(s) => s.iter(),
-            Self::Artist(s) => s.iter(),
-            Self::Comment(s) => s.iter(),
-            Self::Description(s) => s.iter(),
-            Self::Album(s) => s.iter(),
-            Self::Albumartist(s) => s.iter(),
-            Self::Author(s) => s.iter(),
+            Self::Str(s) => s.iter(),
             _ => [].iter(),
         }
     }
 
-    pub fn to_key(&self) -> Metakey {
+    pub fn attributetype(&self) -> Attributetype {
         match self {
-            Self::Title(_) => Metakey::Title,
-            Self::Artist(_) => Metakey::Artist,
-            Self::Date(_) => Metakey::Date,
-            Self::Comment(_) => Metakey::Comment,
-            Self::Description(_) => Metakey::Description,
-            Self::Album(_) => Metakey::Album,
-            Self::TrackNumber(_) => Metakey::TrackNumber,
-            Self::Albumartist(_) => Metakey::Albumartist,
-            Self::Author(_) => Metakey::Author,
+            Self::Str(_) => Attributetype::String,
+            Self::Int(_) => Attributetype::Int,
+            Self::Timestamp(_) => Attributetype::Timestamp,
+            Self::Date(_) => Attributetype::Date,
+        }
+    }
+
+    /// Merge the values of two `Metavalue`s for the same key into the union of both
+    ///
+    /// Values of different types disagree and return `Error::MergeConflict`.
+    pub fn merge(&self, other: &Metavalue) -> Result<Metavalue> {
+        zip_lists!(self, other, append_list).map_err(|_| Error::MergeConflict)
+    }
+
+    /// Add all values of `other` not yet present
+    pub fn append(&self, other: &Metavalue) -> Result<Metavalue> {
+        zip_lists!(self, other, append_list)
+    }
+
+    /// Remove all values of `other`. The result may be empty.
+    pub fn remove(&self, other: &Metavalue) -> Result<Metavalue> {
+        zip_lists!(self, other, remove_list)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Str(s) => s.is_empty(),
+            Self::Int(i) | Self::Timestamp(i) => i.is_empty(),
+            Self::Date(d) => d.is_empty(),
         }
     }
 }
 
 impl fmt::Display for Metavalue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::Str(s) => write!(f, "{:?}", s),
+            Self::Int(i) | Self::Timestamp(i) => write!(f, "{:?}", i),
+            Self::Date(d) => write!(f, "{:?}", d.iter().map(Date::to_string).collect::<Vec<_>>()),
+        }
+    }
+}
+
+fn append_list<T: PartialEq + Clone>(a: &[T], b: &[T]) -> Result<Box<[T]>> {
+    let mut v = a.to_vec();
+    for x in b.iter() {
+        if !v.contains(x) {
+            v.push(x.clone());
+        }
+    }
+    Ok(v.into_boxed_slice())
+}
+
+fn remove_list<T: PartialEq + Clone>(a: &[T], b: &[T]) -> Result<Box<[T]>> {
+    Ok(a.iter().filter(|x| !b.contains(x)).cloned().collect())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A single modification of an entry's metadata
+pub enum Change {
+    /// Replace all values of the key
+    Set(Metakey, Metavalue),
+    /// Add values to the key, keeping the existing ones
+    Append(Metakey, Metavalue),
+    /// Remove the given values from the key
+    Remove(Metakey, Metavalue),
+    /// Remove the key altogether
+    Clear(Metakey),
+}
+
+impl Change {
+    pub fn key(&self) -> &Metakey {
+        match self {
+            Self::Set(k, _) | Self::Append(k, _) | Self::Remove(k, _) | Self::Clear(k) => k,
+        }
+    }
+
+    /// Apply this change to a metadata map
+    pub fn apply(&self, metadata: &mut HashMap<Metakey, Metavalue>) -> Result<()> {
+        let key = self.key();
+        let new = match (self, metadata.get(key)) {
+            (Self::Set(_, v), _) | (Self::Append(_, v), None) => Some(v.clone()),
+            (Self::Append(_, v), Some(old)) => Some(old.append(v)?),
+            (Self::Remove(_, v), Some(old)) => Some(old.remove(v)?),
+            (Self::Remove(_, _), None) | (Self::Clear(_), _) => None,
+        };
+
+        match new {
+            Some(v) if !v.is_empty() => { metadata.insert(key.clone(), v); },
+            _ => { metadata.remove(key); },
+        }
+
+        Ok(())
+    }
+}
+
+/// Metadata types of databases created before attributes were defined by the schema
+///
+/// Only used to read and convert old databases and exports.
+pub(crate) mod legacy {
+    use serde::Deserialize;
+
+    use crate::schema::Attributetype;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+    pub enum Metakey {
+        Title,
+        Artist,
+        Date,
+        Comment,
+        Description,
+        Album,
+        TrackNumber,
+        Albumartist,
+        Author,
+    }
+
+    impl Metakey {
+        pub fn name(self) -> &'static str {
+            match self {
+                Self::Title => "title",
+                Self::Artist => "artist",
+                Self::Date => "date",
+                Self::Comment => "comment",
+                Self::Description => "description",
+                Self::Album => "album",
+                Self::TrackNumber => "tracknumber",
+                Self::Albumartist => "albumartist",
+                Self::Author => "author",
+            }
+        }
+
+        pub fn attributetype(self) -> Attributetype {
+            match self {
+                Self::Date | Self::TrackNumber => Attributetype::Int,
+                _ => Attributetype::String,
+            }
+        }
+
+        pub fn convert(self) -> super::Metakey {
+            super::Metakey::new(self.name())
+        }
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub enum Metavalue {
+        Title(Box<[Box<str>]>),
+        Artist(Box<[Box<str>]>),
+        Date(Box<[i64]>),
+        Comment(Box<[Box<str>]>),
+        Description(Box<[Box<str>]>),
+        Album(Box<[Box<str>]>),
+        TrackNumber(Box<[i64]>),
+        Albumartist(Box<[Box<str>]>),
+        Author(Box<[Box<str>]>),
+    }
+
+    impl Metavalue {
+        pub fn convert(self) -> (super::Metakey, super::Metavalue) {
+            use super::Metavalue::{Str, Int};
+            let (k, v) = match self {
+                Self::Title(s) => (Metakey::Title, Str(s)),
+                Self::Artist(s) => (Metakey::Artist, Str(s)),
+                Self::Date(i) => (Metakey::Date, Int(i)),
+                Self::Comment(s) => (Metakey::Comment, Str(s)),
+                Self::Description(s) => (Metakey::Description, Str(s)),
+                Self::Album(s) => (Metakey::Album, Str(s)),
+                Self::TrackNumber(i) => (Metakey::TrackNumber, Int(i)),
+                Self::Albumartist(s) => (Metakey::Albumartist, Str(s)),
+                Self::Author(s) => (Metakey::Author, Str(s)),
+            };
+            (k.convert(), v)
+        }
     }
 }
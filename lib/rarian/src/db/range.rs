@@ -1,6 +1,15 @@
 use std::iter::Iterator;
-use std::ops::RangeBounds;
-use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+
+use lmdb::{
+    Database,
+    Transaction,
+    RwTransaction,
+    WriteFlags,
+    Cursor,
+};
 
 use serde::{
     Deserialize,
@@ -9,53 +18,189 @@ use serde::{
 
 use crate::uuid::UUID;
 use crate::error::{Result, Error};
+use crate::db::stats::{self, IndexStats};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Copy, Clone)]
+/// Index over integer values
+///
+/// Values are stored as keys in their own LMDB database, encoded so that the byte-wise ordering
+/// LMDB uses matches the numeric ordering of the values. Every value maps to a sorted set of
+/// UUIDs via `DUP_SORT`, so range queries can be answered by walking a cursor from the lower bound
+/// to the upper bound.
 pub struct RangeDB {
-    db: lmdb::Database,
-    name: String,
-    pub map: BTreeMap<i64, UUID>,
+    db: Database,
 }
 
 impl RangeDB {
-    pub fn new(db: lmdb::Database, name: String, map: BTreeMap<i64, UUID>) -> Self {
-        Self { db, name, map }
+    pub fn new(db: Database) -> Self {
+        Self { db }
     }
-    pub fn range<R: RangeBounds<i64>>(&self, r: R) -> impl Iterator<Item = (&i64, &UUID)> {
-        self.map.range(r)
+
+    /// Flags the backing LMDB database has to be created with
+    pub fn flags() -> lmdb::DatabaseFlags {
+        lmdb::DatabaseFlags::DUP_SORT
     }
 
-    pub fn decode(bytes: &[u8]) -> Result<BTreeMap<i64, UUID>> {
-        bincode::deserialize(bytes).map_err(Error::Bincode)
+    /// Encode a value into an order-preserving key
+    ///
+    /// Flipping the sign bit maps `i64::MIN..=i64::MAX` onto `0..=u64::MAX` monotonically;
+    /// big-endian encoding then makes byte-wise comparison equal to numeric comparison.
+    pub fn encode_key(value: i64) -> [u8; 8] {
+        ((value as u64) ^ (1 << 63)).to_be_bytes()
     }
 
-    pub fn encode_into(&self, bytes: &mut [u8]) -> Result<()> {
-        bincode::serialize_into(bytes, &self.map).map_err(Error::Bincode)
+    pub fn decode_key(bytes: &[u8]) -> Result<i64> {
+        let b: [u8; 8] = bytes.try_into().map_err(|_| Error::MalformedKey)?;
+        Ok((u64::from_be_bytes(b) ^ (1 << 63)) as i64)
     }
 
-    pub fn encoded_size(&self) -> Result<u64> {
-        bincode::serialized_size(&self.map).map_err(Error::Bincode)
+    pub fn index(&mut self, txn: &mut RwTransaction, value: i64, uuid: UUID) -> Result<()> {
+        let key = Self::encode_key(value);
+        match txn.put(self.db, &key, &uuid.as_bytes(), WriteFlags::NO_DUP_DATA) {
+            Ok(()) | Err(lmdb::Error::KeyExist) => Ok(()),
+            Err(e) => Err(Error::LMDB(e)),
+        }
     }
 
-    pub fn empty_encoded_size() -> Result<u64> {
-        bincode::serialized_size(&BTreeMap::<u64, UUID>::new()).map_err(Error::Bincode)
+    /// Remove `uuid` from the postings of `value`. Missing postings are not an error.
+    pub fn unindex(&mut self, txn: &mut RwTransaction, value: i64, uuid: UUID) -> Result<()> {
+        let key = Self::encode_key(value);
+        match txn.del(self.db, &key, Some(&uuid.as_bytes())) {
+            Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+            Err(e) => Err(Error::LMDB(e)),
+        }
     }
 
-    pub fn empty_encode_into(bytes: &mut [u8]) -> Result<()> {
-        bincode::serialize_into(bytes, &BTreeMap::<u64, UUID>::new()).map_err(Error::Bincode)
+    /// Return all UUIDs with a value in the given range
+    pub fn range<T: Transaction, R: RangeBounds<i64>>(&self, txn: &T, r: R) -> Result<HashSet<UUID>> {
+        let mut out = HashSet::new();
+        let mut cursor = txn.open_ro_cursor(self.db)?;
+
+        let iter = match r.start_bound() {
+            Bound::Included(v) => cursor.iter_from(Self::encode_key(*v)),
+            Bound::Excluded(v) => match v.checked_add(1) {
+                Some(v) => cursor.iter_from(Self::encode_key(v)),
+                None => return Ok(out),
+            },
+            Bound::Unbounded => cursor.iter_start(),
+        };
+
+        for res in iter {
+            let (k, v) = match res {
+                Ok(kv) => kv,
+                // An empty database has nothing to iterate over
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(Error::LMDB(e)),
+            };
+            let value = Self::decode_key(k)?;
+            if !r.contains(&value) {
+                break;
+            }
+            out.insert(UUID::from_bytes(v)?);
+        }
+
+        Ok(out)
     }
 
-    pub fn index(&mut self, txn: &mut lmdb::RwTransaction, value: i64, uuid: UUID) -> Result<()> {
-        self.map.insert(value, uuid);
-        let size = self.encoded_size()? as usize;
-        let bytes = txn.reserve(self.db, &self.name.as_bytes(), size, lmdb::WriteFlags::empty())?;
-        self.encode_into(bytes)
+    /// Number of distinct values and postings, and the smallest and largest value
+    pub fn summary<T: Transaction>(&self, txn: &T) -> Result<IndexStats> {
+        let mut values = 0;
+        let mut min = None;
+        let mut max = None;
+        let mut cursor = txn.open_ro_cursor(self.db)?;
+        for res in cursor.iter_start() {
+            let (k, _) = match res {
+                Ok(kv) => kv,
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(Error::LMDB(e)),
+            };
+            let value = Self::decode_key(k)?;
+            // Keys are sorted, so a new value shows up as a change from the previous one
+            if max != Some(value) {
+                values += 1;
+            }
+            min = min.or(Some(value));
+            max = Some(value);
+        }
+
+        let pages = stats::page_stats(txn, self.db)?;
+        Ok(IndexStats::Range { values, postings: pages.entries, min, max, pages })
     }
 
-    pub fn list(&self) -> Result<()> {
-        for (v,u) in self.map.iter() {
-            println!("{}:\t{}", v, u.as_uuid())
+    pub fn list<T: Transaction>(&self, txn: &T) -> Result<()> {
+        let mut cursor = txn.open_ro_cursor(self.db)?;
+        for res in cursor.iter_start() {
+            let (k, v) = match res {
+                Ok(kv) => kv,
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(Error::LMDB(e)),
+            };
+            println!("{}:\t{}", Self::decode_key(k)?, UUID::from_bytes(v)?.as_uuid())
         }
         Ok(())
     }
 }
+
+/// Decode a range index stored as a single serialized map by older versions.
+///
+/// Both the original one-UUID-per-value format and the later set-valued format are understood.
+pub fn decode_blob(bytes: &[u8]) -> Result<BTreeMap<i64, HashSet<UUID>>> {
+    if is_legacy(bytes) {
+        let old: BTreeMap<i64, UUID> = bincode::deserialize(bytes).map_err(Error::Bincode)?;
+        Ok(old.into_iter()
+            .map(|(v, u)| {
+                let mut set = HashSet::with_capacity(1);
+                set.insert(u);
+                (v, set)
+            })
+            .collect())
+    } else {
+        bincode::deserialize(bytes).map_err(Error::Bincode)
+    }
+}
+
+/// Check if `bytes` are encoded in the one-UUID-per-value format.
+///
+/// Those maps are a length followed by that many (i64, UUID) pairs i.e. are exactly
+/// `8 + n * 24` bytes long. Set-valued maps store a (non-empty) set length and at least one UUID
+/// per value and are thus always longer than that. An empty map looks the same in both formats.
+fn is_legacy(bytes: &[u8]) -> bool {
+    if bytes.len() < 8 {
+        return false;
+    }
+    let n = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+    n != 0 && n.checked_mul(24).and_then(|l| l.checked_add(8)) == Some(bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_detection() {
+        let mut old = BTreeMap::new();
+        old.insert(1i64, UUID::from_u128(1));
+        old.insert(2i64, UUID::from_u128(2));
+        let old_bytes = bincode::serialize(&old).unwrap();
+        assert!(is_legacy(&old_bytes));
+
+        let map = decode_blob(&old_bytes).unwrap();
+        assert_eq!(map.len(), 2);
+        assert!(map[&1].contains(&UUID::from_u128(1)));
+
+        let new_bytes = bincode::serialize(&map).unwrap();
+        assert!(!is_legacy(&new_bytes));
+        assert_eq!(decode_blob(&new_bytes).unwrap(), map);
+    }
+
+    #[test]
+    fn key_order() {
+        let values = [i64::MIN, -1000, -1, 0, 1, 20, 1990, i64::MAX];
+        for w in values.windows(2) {
+            assert!(RangeDB::encode_key(w[0]) < RangeDB::encode_key(w[1]));
+        }
+        for v in values.iter() {
+            assert_eq!(RangeDB::decode_key(&RangeDB::encode_key(*v)).unwrap(), *v);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use lmdb::Transaction;
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::db::{Database, Index};
+use crate::db::term::FieldStats;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+/// Page usage of one or more LMDB databases, as reported by `mdb_stat`
+pub struct PageStats {
+    pub page_size: u32,
+    pub depth: u32,
+    pub branch_pages: usize,
+    pub leaf_pages: usize,
+    pub overflow_pages: usize,
+    pub entries: usize,
+}
+
+impl PageStats {
+    pub fn pages(&self) -> usize {
+        self.branch_pages + self.leaf_pages + self.overflow_pages
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.pages() * self.page_size as usize
+    }
+
+    /// Sum up the pages of two databases
+    pub fn combine(self, other: PageStats) -> Self {
+        Self {
+            page_size: self.page_size.max(other.page_size),
+            depth: self.depth.max(other.depth),
+            branch_pages: self.branch_pages + other.branch_pages,
+            leaf_pages: self.leaf_pages + other.leaf_pages,
+            overflow_pages: self.overflow_pages + other.overflow_pages,
+            entries: self.entries + other.entries,
+        }
+    }
+}
+
+impl From<lmdb::Stat> for PageStats {
+    fn from(s: lmdb::Stat) -> Self {
+        Self {
+            page_size: s.page_size(),
+            depth: s.depth(),
+            branch_pages: s.branch_pages(),
+            leaf_pages: s.leaf_pages(),
+            overflow_pages: s.overflow_pages(),
+            entries: s.entries(),
+        }
+    }
+}
+
+/// Page usage of a single LMDB database
+pub(crate) fn page_stats<T: Transaction>(txn: &T, db: lmdb::Database) -> Result<PageStats> {
+    Ok(txn.stat(db)?.into())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// Contents of a single index
+pub enum IndexStats {
+    Term {
+        /// Number of distinct stems
+        terms: usize,
+        /// Number of indexed entries and the sum of their lengths in stems
+        documents: FieldStats,
+        /// Stems with the most entries, i.e. the highest document frequency
+        largest: Vec<(String, usize)>,
+        pages: PageStats,
+    },
+    Range {
+        /// Number of distinct values
+        values: usize,
+        /// Number of (value, entry) pairs
+        postings: usize,
+        min: Option<i64>,
+        max: Option<i64>,
+        pages: PageStats,
+    },
+    Keyword {
+        /// Number of distinct normalized values
+        values: usize,
+        /// Number of (value, entry) pairs
+        postings: usize,
+        /// Values shared by the most entries
+        largest: Vec<(String, usize)>,
+        pages: PageStats,
+    },
+}
+
+impl IndexStats {
+    pub fn pages(&self) -> &PageStats {
+        match self {
+            IndexStats::Term { pages, .. }
+            | IndexStats::Range { pages, .. }
+            | IndexStats::Keyword { pages, .. } => pages,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// Size and contents of a database and its indices
+pub struct DatabaseStats {
+    pub name: String,
+    pub entries: usize,
+    pub files: usize,
+    pub pages: PageStats,
+    /// Per indexed attribute
+    pub indices: BTreeMap<String, IndexStats>,
+}
+
+impl Database {
+    /// Collect statistics about this database, listing up to `top` of the largest postings of
+    /// each index
+    ///
+    /// Counts of entries, files and distinct terms are kept by LMDB and cheap to get, finding
+    /// the largest postings and value ranges reads every index completely.
+    pub fn stats<T: Transaction>(&self, txn: &T, top: usize) -> Result<DatabaseStats> {
+        let entries = self.entries.stat(txn)?;
+        let files = self.filekeys.stat(txn)?;
+
+        let mut indices = BTreeMap::new();
+        for (k, i) in self.indices.iter() {
+            let s = match i {
+                Index::Term(db) => db.summary(txn, top)?,
+                Index::IntMap(db) => db.summary(txn)?,
+                Index::Keyword(db) => db.summary(txn, top)?,
+            };
+            indices.insert(k.to_string(), s);
+        }
+
+        Ok(DatabaseStats {
+            name: self.name.clone(),
+            entries: entries.entries,
+            files: files.entries,
+            pages: entries.combine(files),
+            indices,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// Page usage of the whole LMDB environment, as reported by `mdb_env_stat` and `mdb_env_info`
+pub struct EnvironmentStats {
+    /// Pages of the main database, which holds the names of all other databases
+    pub pages: PageStats,
+    pub map_size: usize,
+    /// Bytes of the map in use, including free pages not yet reused
+    pub used: usize,
+}
+
+/// Keep the `top` largest counts, largest first
+pub(crate) fn largest(mut counts: Vec<(String, usize)>, top: usize) -> Vec<(String, usize)> {
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(top);
+    counts
+}
+
+fn bytes(n: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut v = n as f64;
+    let mut u = 0;
+    while v >= 1024.0 && u < UNITS.len() - 1 {
+        v /= 1024.0;
+        u += 1;
+    }
+    if u == 0 {
+        format!("{} {}", n, UNITS[0])
+    } else {
+        format!("{:.1} {}", v, UNITS[u])
+    }
+}
+
+impl fmt::Display for PageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {} pages ({} branch, {} leaf, {} overflow), depth {}",
+            bytes(self.bytes()), self.pages(), self.branch_pages, self.leaf_pages,
+            self.overflow_pages, self.depth)
+    }
+}
+
+impl fmt::Display for DatabaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Database {}", self.name)?;
+        writeln!(f, "  {:<11}{}", "entries:", self.entries)?;
+        writeln!(f, "  {:<11}{}", "files:", self.files)?;
+        writeln!(f, "  {:<11}{}", "storage:", self.pages)?;
+        for (k, i) in self.indices.iter() {
+            match i {
+                IndexStats::Term { terms, documents, largest, .. } => {
+                    writeln!(f, "Index {} (term)", k)?;
+                    writeln!(f, "  {:<11}{}", "terms:", terms)?;
+                    let avg = if documents.count == 0 { 0.0 } else { documents.total as f64 / documents.count as f64 };
+                    writeln!(f, "  {:<11}{}, {:.1} terms on average", "documents:", documents.count, avg)?;
+                    print_largest(f, largest)?;
+                }
+                IndexStats::Range { values, postings, min, max, .. } => {
+                    writeln!(f, "Index {} (range)", k)?;
+                    writeln!(f, "  {:<11}{} distinct, {} postings", "values:", values, postings)?;
+                    if let (Some(min), Some(max)) = (min, max) {
+                        writeln!(f, "  {:<11}{}..{}", "range:", min, max)?;
+                    }
+                }
+                IndexStats::Keyword { values, postings, largest, .. } => {
+                    writeln!(f, "Index {} (keyword)", k)?;
+                    writeln!(f, "  {:<11}{} distinct, {} postings", "values:", values, postings)?;
+                    print_largest(f, largest)?;
+                }
+            }
+            writeln!(f, "  {:<11}{}", "storage:", i.pages())?;
+        }
+        Ok(())
+    }
+}
+
+fn print_largest(f: &mut fmt::Formatter<'_>, largest: &[(String, usize)]) -> fmt::Result {
+    if !largest.is_empty() {
+        writeln!(f, "  largest:")?;
+        for (k, n) in largest.iter() {
+            writeln!(f, "    {:>8}  {}", n, k)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for EnvironmentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Environment")?;
+        writeln!(f, "  {:<11}{} of {} used ({:.1}%)", "map:", bytes(self.used), bytes(self.map_size),
+            self.used as f64 * 100.0 / self.map_size as f64)?;
+        writeln!(f, "  {:<11}{}", "main db:", self.pages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn largest_counts() {
+        let counts = vec![("b".to_string(), 2), ("a".to_string(), 2), ("c".to_string(), 5), ("d".to_string(), 1)];
+        assert_eq!(largest(counts, 3), vec![("c".to_string(), 5), ("a".to_string(), 2), ("b".to_string(), 2)]);
+        assert_eq!(bytes(512), "512 B");
+        assert_eq!(bytes(10485760), "10.0 MiB");
+    }
+}
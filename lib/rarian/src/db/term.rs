@@ -17,8 +17,15 @@ use std::collections::{HashSet, HashMap};
 use std::borrow::Cow;
 
 use rust_stemmers::{Algorithm, Stemmer};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::error::{Result, Error};
+use crate::db::dbm::CursorIter;
+use crate::db::stats::{self, IndexStats};
+use crate::schema::strict;
+use bincode::Options;
 
 use crate::db::meta::{
     Metakey,
@@ -28,22 +35,38 @@ use crate::db::entry::EntryT;
 use crate::uuid::UUID;
 use crate::db::{
     EntryDB,
+    keyword,
 };
 
+/// Word offsets of a stem within the values of a single field
+pub type Positions = Vec<u32>;
+
+/// Offset added between the values of a multi-valued field so phrases can't span two values
+pub const VALUE_GAP: u32 = 1024;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Matches(HashSet<UUID>);
+/// Postings of a single stem: every UUID containing it with the positions it occurs at
+pub struct Matches(HashMap<UUID, Positions>);
+
+#[derive(Deserialize)]
+/// Postings as written before positions were recorded
+struct LegacyMatches(HashSet<UUID>);
 
 impl Matches {
-    pub fn new(set: HashSet<UUID>) -> Self {
-        Self ( set )
+    pub fn new(map: HashMap<UUID, Positions>) -> Self {
+        Self ( map )
     }
 
     pub fn empty() -> Self {
-        Self ( HashSet::with_capacity(0) )
+        Self ( HashMap::with_capacity(0) )
     }
 
     pub fn into_set(self) -> HashSet<UUID> {
-        self.0
+        self.0.into_keys().collect()
+    }
+
+    pub fn positions(&self, uuid: &UUID) -> Option<&[u32]> {
+        self.0.get(uuid).map(|p| p.as_slice())
     }
 
     pub fn encoded_size(&self) -> Result<u64> {
@@ -54,35 +77,93 @@ impl Matches {
         bincode::serialize_into(bytes, &self).map_err(Error::Bincode)
     }
 
-    pub fn decode(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).map_err(Error::Bincode)
+    /// Decode postings, also accepting the old position-less format
+    ///
+    /// Postings decoded from the old format have no positions recorded, so they never take part
+    /// in phrase or proximity matches. The returned flag is set in that case.
+    pub fn decode_compat(bytes: &[u8]) -> Result<(Self, bool)> {
+        match strict().deserialize(bytes) {
+            Ok(m) => Ok((m, false)),
+            Err(e) => match strict().deserialize::<LegacyMatches>(bytes) {
+                Ok(LegacyMatches(set)) => Ok((Self(set.into_iter().map(|u| (u, Vec::new())).collect()), true)),
+                Err(_) => Err(Error::Bincode(e)),
+            }
+        }
     }
 
-    pub fn combine(&mut self, other: &Matches) {
-        let union = self.0.union(&other.0);
-        self.0 = union.map(|x| *x).collect();
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Self::decode_compat(bytes).map(|(m, _)| m)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Term frequency saturation of BM25
+const BM25_K1: f32 = 1.2;
+/// Field length normalization of BM25
+const BM25_B: f32 = 0.75;
+
+/// Key of the `FieldStats` in the lengths database. All other keys are 16 byte UUIDs.
+const STATS_KEY: &[u8] = b"stats";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Number of UUIDs with an indexed value and the sum of their lengths in stems
+pub struct FieldStats {
+    pub count: u64,
+    pub total: u64,
+}
+
+#[derive(Clone, Debug)]
+/// Index over the stemmed words of a text field
+///
+/// Maps every stem to the UUIDs containing it and the positions it occurs at. The number of stems
+/// per UUID is kept in a second database, used to rank results.
+///
+/// Full-text indices have a third database listing the stems of every UUID, since the text they
+/// were taken from isn't stored anywhere to find them again when the entry is removed.
 pub struct TermDB {
     db: Database,
+    lengths: Database,
+    documents: Option<Database>,
+    analyzer: Analyzer,
 }
 
 impl TermDB {
-    pub fn new(db: Database) -> Self {
-        Self { db }
+    pub fn new(db: Database, lengths: Database, analyzer: Analyzer) -> Self {
+        Self { db, lengths, documents: None, analyzer }
+    }
+
+    /// A full-text index, recording the stems of each document in `documents`
+    pub fn with_documents(db: Database, lengths: Database, documents: Database, analyzer: Analyzer) -> Self {
+        Self { db, lengths, documents: Some(documents), analyzer }
+    }
+
+    /// Check if this indexes the text of files rather than attribute values
+    pub fn is_fulltext(&self) -> bool {
+        self.documents.is_some()
+    }
+
+    pub fn analyzer(&self) -> &Analyzer {
+        &self.analyzer
+    }
+
+    /// Name of the database holding the field lengths of the term index `dbname`
+    pub fn lengths_name(dbname: &str) -> String {
+        format!("{}_lengths", dbname)
     }
 
-    fn get_bytes<'txn, T: Transaction, K: AsRef<[u8]>>(self, txn: &'txn T, key: &K) -> Result<&'txn [u8]> {
+    /// Name of the database holding the stems of each document of the full-text index `dbname`
+    pub fn documents_name(dbname: &str) -> String {
+        format!("{}_documents", dbname)
+    }
+
+    fn get_bytes<'txn, T: Transaction, K: AsRef<[u8]>>(&self, txn: &'txn T, key: &K) -> Result<&'txn [u8]> {
         txn.get(self.db, key).map_err(Error::LMDB)
     }
 
-    fn reserve_bytes<'txn, K: AsRef<[u8]>>(self, txn: &'txn mut RwTransaction, key: &K, len: usize, flags: WriteFlags) -> Result<&'txn mut [u8]> {
+    fn reserve_bytes<'txn, K: AsRef<[u8]>>(&self, txn: &'txn mut RwTransaction, key: &K, len: usize, flags: WriteFlags) -> Result<&'txn mut [u8]> {
         txn.reserve(self.db, key, len as size_t, flags).map_err(Error::LMDB)
     }
 
-    pub fn get<'txn, T: Transaction>(self, txn: &'txn T, key: &str) -> Result<Matches> {
+    pub fn get<'txn, T: Transaction>(&self, txn: &'txn T, key: &str) -> Result<Matches> {
         self.get_bytes(txn, &key)
             .and_then(Matches::decode)
             .or_else(|e| match e {
@@ -91,71 +172,122 @@ impl TermDB {
             })
     }
 
-    pub fn put<'txn>(self, txn: &'txn mut RwTransaction, key: &str, m: Matches) -> Result<()>
+    pub fn put<'txn>(&self, txn: &'txn mut RwTransaction, key: &str, m: Matches) -> Result<()>
     {
         let len = m.encoded_size()? as usize;
         let buf = self.reserve_bytes(txn, &key, len, WriteFlags::empty())?;
         m.encode_into(buf)
     }
 
-    pub fn iter_start<'txn, T: Transaction>(self, txn: &'txn T) -> Result<Iter<'txn>> {
-        let mut cursor = txn.open_ro_cursor(self.db)?;
-        Ok(cursor.iter_start())
+    pub fn iter_start<'txn, T: Transaction>(&self, txn: &'txn T) -> Result<CursorIter<'txn>> {
+        CursorIter::start(txn, self.db)
     }
 
-    pub fn insert_match<'txn>(&mut self, txn: &'txn mut RwTransaction, key: &str, uuid: UUID) -> Result<bool> {
-        match self.get(txn, key) {
-            Ok(matches) => {
-                let mut matches = matches.into_set();
-                let r = matches.insert(uuid);
-                self.put(txn, key, Matches::new(matches))?;
-
-                Ok(r)
+    /// Check if the postings are stored in the old format without positions
+    ///
+    /// Only the first stem is looked at since an index is always written in a single format.
+    pub fn is_legacy<T: Transaction>(&self, txn: &T) -> Result<bool> {
+        match self.iter_start(txn)?.next() {
+            Some(r) => {
+                let (_, v) = r?;
+                Ok(Matches::decode_compat(v)?.1)
             }
-            Err(Error::LMDB(lmdb::Error::NotFound)) => {
-                let mut matches = HashSet::new();
-                let r = matches.insert(uuid);
-                self.put(txn, key, Matches::new(matches))?;
+            None => Ok(false),
+        }
+    }
 
-                Ok(r)
-            }
-            Err(e) => return Err(e),
+    /// Remove all postings and lengths
+    pub fn clear(&self, txn: &mut RwTransaction) -> Result<()> {
+        txn.clear_db(self.db)?;
+        if let Some(documents) = self.documents {
+            txn.clear_db(documents)?;
         }
+        txn.clear_db(self.lengths).map_err(Error::LMDB)
     }
 
-    pub fn insert_matches<'txn>(&mut self, txn: &'txn mut RwTransaction, key: &str, other: &Matches) -> Result<()> {
-        match self.get(txn, key) {
-            Ok(mut m) => {
-                m.combine(other);
-                self.put(txn, key, m)?;
+    /// Set the positions of `uuid` in the postings of `key`, replacing any previous ones
+    pub fn insert_match<'txn>(&mut self, txn: &'txn mut RwTransaction, key: &str, uuid: UUID, positions: Positions) -> Result<bool> {
+        let Matches(mut matches) = self.get(txn, key)?;
+        let r = matches.insert(uuid, positions).is_none();
+        self.put(txn, key, Matches::new(matches))?;
 
-                Ok(())
-            }
-            Err(Error::LMDB(lmdb::Error::NotFound)) => {
-                self.put(txn, key, other.clone())?;
+        Ok(r)
+    }
 
-                Ok(())
+    /// Remove `uuid` from the matches of `key`, deleting the key once no matches are left.
+    pub fn remove_match<'txn>(&mut self, txn: &'txn mut RwTransaction, key: &str, uuid: UUID) -> Result<bool> {
+        let Matches(mut matches) = self.get(txn, key)?;
+        let r = matches.remove(&uuid).is_some();
+        if matches.is_empty() {
+            match txn.del(self.db, &key, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => {},
+                Err(e) => return Err(Error::LMDB(e)),
             }
-            Err(e) => return Err(e),
+        } else if r {
+            self.put(txn, key, Matches::new(matches))?;
         }
+
+        Ok(r)
     }
 
-    pub fn index<'txn>(&mut self, txn: &'txn mut RwTransaction, term: String, uuid: UUID) -> Result<()> {
-        let s = Stemmer::create(Algorithm::English);
+    /// Index all values of a field of `uuid`
+    pub fn index<'txn, 'a, I>(&mut self, txn: &'txn mut RwTransaction, values: I, uuid: UUID) -> Result<()>
+        where I: IntoIterator<Item=&'a str>
+    {
+        let mut length = 0;
+        for (stem, positions) in self.analyzer.positions(values) {
+            length += positions.len() as u32;
+            self.insert_match(txn, &stem, uuid, positions)?;
+        }
 
-        let title = term.to_lowercase();
-        let words = title.split_whitespace();
-        let wordsc = words.map(|s| s.trim_matches(|c: char| !c.is_alphanumeric()));
-        let wordstems = wordsc.map(|w| s.stem(w));
+        self.set_length(txn, uuid, Some(length))
+    }
 
-        let fillwords = wordstems.filter(|s| !is_stopword(s));
-        let filtered = fillwords.filter(|s| !s.is_empty());
+    pub fn unindex<'txn, 'a, I>(&mut self, txn: &'txn mut RwTransaction, values: I, uuid: UUID) -> Result<()>
+        where I: IntoIterator<Item=&'a str>
+    {
+        for (stem, _) in self.analyzer.positions(values) {
+            self.remove_match(txn, &stem, uuid)?;
+        }
 
-        for stem in filtered {
-            self.insert_match(txn, &stem, uuid)?;
+        self.set_length(txn, uuid, None)
+    }
+
+    /// Index `text` as the document of `uuid`, replacing the one indexed before
+    pub fn index_document(&mut self, txn: &mut RwTransaction, text: &str, uuid: UUID) -> Result<()> {
+        let documents = self.documents.ok_or(Error::TypeError)?;
+        self.unindex_document(txn, uuid)?;
+
+        let positions = self.analyzer.positions(std::iter::once(text));
+        if positions.is_empty() {
+            return Ok(());
+        }
+        // Recorded first, so postings written before a failure can still be removed again
+        let stems: Vec<&String> = positions.keys().collect();
+        txn.put(documents, &uuid.as_bytes(), &bincode::serialize(&stems)?, WriteFlags::empty())?;
+
+        let mut length = 0;
+        for (stem, positions) in positions {
+            length += positions.len() as u32;
+            self.insert_match(txn, &stem, uuid, positions)?;
         }
+        self.set_length(txn, uuid, Some(length))
+    }
 
-        Ok(())
+    /// Remove the document of `uuid`, if one was indexed
+    pub fn unindex_document(&mut self, txn: &mut RwTransaction, uuid: UUID) -> Result<()> {
+        let documents = self.documents.ok_or(Error::TypeError)?;
+        let stems: Vec<String> = match txn.get(documents, &uuid.as_bytes()) {
+            Ok(b) => bincode::deserialize(b)?,
+            Err(lmdb::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(Error::LMDB(e)),
+        };
+        for stem in stems.iter() {
+            self.remove_match(txn, stem, uuid)?;
+        }
+
+        txn.del(documents, &uuid.as_bytes(), None)?;
+        self.set_length(txn, uuid, None)
     }
 
     pub fn list<'txn, T: Transaction>(&self, txn: &'txn T) -> Result<()> {
@@ -172,11 +304,220 @@ impl TermDB {
         Ok(())
     }
 
-    pub fn lookup<'txn, T: Transaction>(&self, txn: &'txn T, term: &str) -> Result<Matches> {
-        let s = Stemmer::create(Algorithm::English);
-        let stem = s.stem(term);
+    /// All UUIDs containing `term`
+    ///
+    /// Terms the tokenizer splits into several words, like "AC/DC", have to match as a phrase.
+    pub fn lookup<'txn, T: Transaction>(&self, txn: &'txn T, term: &str) -> Result<HashSet<UUID>> {
+        let query = self.analyzer.tokens(term);
+        match query.as_slice() {
+            [] => Ok(HashSet::new()),
+            [(_, stem)] => Ok(self.get(txn, stem)?.into_set()),
+            _ => self.phrase(txn, term),
+        }
+    }
+
+    /// All UUIDs containing the words of `text` in order and next to each other
+    pub fn phrase<T: Transaction>(&self, txn: &T, text: &str) -> Result<HashSet<UUID>> {
+        let query = self.analyzer.tokens(text);
+        let found = self.candidates(txn, query.iter().map(|(_, s)| s.as_str()))?;
+        Ok(found.into_iter()
+            .filter(|(_, p)| match_phrase(&query, p))
+            .map(|(u, _)| u)
+            .collect())
+    }
+
+    /// All UUIDs containing all words of `text` within a window of `distance` positions
+    pub fn near<T: Transaction>(&self, txn: &T, text: &str, distance: u32) -> Result<HashSet<UUID>> {
+        let query = self.analyzer.tokens(text);
+        let found = self.candidates(txn, query.iter().map(|(_, s)| s.as_str()))?;
+        Ok(found.into_iter()
+            .filter(|(_, p)| match_near(&query, distance, p))
+            .map(|(u, _)| u)
+            .collect())
+    }
+
+    /// All UUIDs containing a stem starting with `prefix`
+    pub fn prefix<T: Transaction>(&self, txn: &T, prefix: &str) -> Result<HashSet<UUID>> {
+        self.union(txn, &self.prefix_stems(txn, prefix)?)
+    }
+
+    /// All UUIDs containing a stem matching `pattern`
+    ///
+    /// `*` matches any number of characters, `?` exactly one.
+    pub fn wildcard<T: Transaction>(&self, txn: &T, pattern: &str) -> Result<HashSet<UUID>> {
+        self.union(txn, &self.wildcard_stems(txn, pattern)?)
+    }
+
+    /// All UUIDs containing a stem at most `max_edits` insertions, deletions or substitutions
+    /// away from the stem of `term`
+    pub fn fuzzy<T: Transaction>(&self, txn: &T, term: &str, max_edits: u32) -> Result<HashSet<UUID>> {
+        self.union(txn, &self.fuzzy_stems(txn, term, max_edits)?)
+    }
+
+    pub fn prefix_stems<T: Transaction>(&self, txn: &T, prefix: &str) -> Result<Vec<String>> {
+        let prefix = prefix.to_lowercase();
+        self.scan_keys(txn, &prefix, |_| true)
+    }
+
+    pub fn wildcard_stems<T: Transaction>(&self, txn: &T, pattern: &str) -> Result<Vec<String>> {
+        let pattern = pattern.to_lowercase();
+        let prefix = match pattern.find(['*', '?']) {
+            Some(i) => &pattern[..i],
+            None => &pattern[..],
+        };
+        self.scan_keys(txn, prefix, |stem| glob_match(&pattern, stem))
+    }
+
+    pub fn fuzzy_stems<T: Transaction>(&self, txn: &T, term: &str, max_edits: u32) -> Result<Vec<String>> {
+        let stem = self.analyzer.query_stem(term);
+        self.scan_keys(txn, "", |key| within_edits(&stem, key, max_edits))
+    }
+
+    fn union<T: Transaction>(&self, txn: &T, stems: &[String]) -> Result<HashSet<UUID>> {
+        let mut out = HashSet::new();
+        for stem in stems {
+            out.extend(self.get(txn, stem)?.into_set());
+        }
+        Ok(out)
+    }
+
+    /// All stems starting with `prefix` that `pred` accepts
+    ///
+    /// Stems are sorted, so only the keys sharing the prefix have to be walked.
+    fn scan_keys<T: Transaction, F: Fn(&str) -> bool>(&self, txn: &T, prefix: &str, pred: F) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+        // LMDB rejects empty keys, even just for positioning a cursor
+        let iter = if prefix.is_empty() {
+            CursorIter::start(txn, self.db)?
+        } else {
+            CursorIter::from(txn, self.db, prefix)?
+        };
+        for r in iter {
+            let (k, _) = r?;
+            let k = std::str::from_utf8(k)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            if pred(k) {
+                out.push(k.to_string());
+            }
+        }
+        Ok(out)
+    }
+
+    /// Number of stems indexed for `uuid`
+    pub fn length<T: Transaction>(&self, txn: &T, uuid: &UUID) -> Result<Option<u32>> {
+        match txn.get(self.lengths, &uuid.as_bytes()) {
+            Ok(b) => Ok(Some(bincode::deserialize(b)?)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(Error::LMDB(e)),
+        }
+    }
+
+    /// Number of indexed UUIDs and the sum of their lengths
+    pub fn stats<T: Transaction>(&self, txn: &T) -> Result<FieldStats> {
+        match txn.get(self.lengths, &STATS_KEY) {
+            Ok(b) => Ok(bincode::deserialize(b)?),
+            Err(lmdb::Error::NotFound) => Ok(FieldStats::default()),
+            Err(e) => Err(Error::LMDB(e)),
+        }
+    }
+
+    /// Record the length of `uuid`, or remove it if `None`, keeping the totals up to date
+    fn set_length(&self, txn: &mut RwTransaction, uuid: UUID, length: Option<u32>) -> Result<()> {
+        let mut stats = self.stats(txn)?;
+        if let Some(old) = self.length(txn, &uuid)? {
+            stats.count -= 1;
+            stats.total -= old as u64;
+        }
+
+        match length {
+            Some(l) => {
+                stats.count += 1;
+                stats.total += l as u64;
+                txn.put(self.lengths, &uuid.as_bytes(), &bincode::serialize(&l)?, WriteFlags::empty())?;
+            }
+            None => match txn.del(self.lengths, &uuid.as_bytes(), None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => {},
+                Err(e) => return Err(Error::LMDB(e)),
+            }
+        }
+
+        txn.put(self.lengths, &STATS_KEY, &bincode::serialize(&stats)?, WriteFlags::empty())?;
+        Ok(())
+    }
+
+    /// BM25 score of each of `uuids` for the given stems
+    pub fn score<T: Transaction>(&self, txn: &T, stems: &[String], uuids: &HashSet<UUID>) -> Result<HashMap<UUID, f32>> {
+        let stats = self.stats(txn)?;
+        let mut out = HashMap::new();
+        if stats.count == 0 {
+            return Ok(out);
+        }
+        let n = stats.count as f32;
+        let avg = stats.total as f32 / n;
+
+        for stem in stems {
+            let Matches(m) = self.get(txn, stem)?;
+            let df = m.len() as f32;
+            let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+            for uuid in uuids.iter() {
+                let positions = match m.get(uuid) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                // Postings from before positions were recorded still count once
+                let tf = positions.len().max(1) as f32;
+                let len = self.length(txn, uuid)?.map_or(avg, |l| l as f32);
+                let s = idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * len / avg));
+                *out.entry(*uuid).or_insert(0.0) += s;
+            }
+        }
 
-        self.get(txn, &stem)
+        Ok(out)
+    }
+
+    /// Number of stems, indexed entries and the `top` stems with the most entries
+    pub fn summary<T: Transaction>(&self, txn: &T, top: usize) -> Result<IndexStats> {
+        let mut counts = Vec::new();
+        for r in self.iter_start(txn)? {
+            let (k, v) = r?;
+            let Matches(m) = Matches::decode(v)?;
+            counts.push((std::str::from_utf8(k)?.to_string(), m.len()));
+        }
+
+        let postings = stats::page_stats(txn, self.db)?;
+        Ok(IndexStats::Term {
+            terms: postings.entries,
+            documents: self.stats(txn)?,
+            largest: stats::largest(counts, top),
+            pages: postings.combine(stats::page_stats(txn, self.lengths)?),
+        })
+    }
+
+    /// Collect the positions of all given stems for the UUIDs that contain every one of them
+    fn candidates<'a, T: Transaction, I>(&self, txn: &T, stems: I) -> Result<HashMap<UUID, HashMap<String, Positions>>>
+        where I: IntoIterator<Item=&'a str>
+    {
+        let mut out: Option<HashMap<UUID, HashMap<String, Positions>>> = None;
+        for stem in stems {
+            let Matches(m) = self.get(txn, stem)?;
+            let mut next = HashMap::new();
+            for (uuid, positions) in m {
+                let mut p = match out {
+                    None => HashMap::new(),
+                    Some(ref mut out) => match out.remove(&uuid) {
+                        Some(p) => p,
+                        None => continue,
+                    },
+                };
+                p.insert(stem.to_string(), positions);
+                next.insert(uuid, p);
+            }
+            out = Some(next);
+        }
+
+        Ok(out.unwrap_or_default())
     }
 }
 
@@ -192,6 +533,416 @@ lazy_static! {
     };
 }
 
-fn is_stopword(word: &str) -> bool {
-    STOPWORDS.contains(word)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How text is split into words
+pub enum Segmentation {
+    /// Split at whitespace and trim punctuation off both ends of every word
+    #[default]
+    Whitespace,
+    /// Split at Unicode word boundaries (UAX #29), also splitting at inner punctuation
+    Unicode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+/// Pipeline turning text into lowercased words, run before stopwords and stemming
+///
+/// The default matches how term indices were always tokenized, so existing indices keep working
+/// without a rebuild.
+pub struct Tokenizer {
+    pub segmentation: Segmentation,
+    /// Remove accents and other combining marks, so "Björk" is found searching for "Bjork"
+    pub strip_diacritics: bool,
+    /// Index runs of CJK characters as overlapping pairs of characters. Only used with
+    /// `Segmentation::Unicode`.
+    pub cjk_bigrams: bool,
+}
+
+impl Tokenizer {
+    /// Split a text into normalized words together with their word offset in the text
+    ///
+    /// Words may be empty if they consisted only of punctuation; they still take up an offset.
+    pub fn words(&self, text: &str) -> Vec<(u32, String)> {
+        let words = match self.segmentation {
+            Segmentation::Whitespace => text.split_whitespace()
+                .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()))
+                .enumerate()
+                .map(|(i, w)| (i as u32, w.to_string()))
+                .collect(),
+            Segmentation::Unicode => self.segment(text),
+        };
+
+        words.into_iter().map(|(i, w)| (i, self.normalize(&w))).collect()
+    }
+
+    /// Case fold a single word and strip its diacritics if configured to
+    pub fn normalize(&self, word: &str) -> String {
+        let word = word.to_lowercase();
+        if self.strip_diacritics {
+            word.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect()
+        } else {
+            word
+        }
+    }
+
+    fn segment(&self, text: &str) -> Vec<(u32, String)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        for chunk in text.split_whitespace() {
+            let start = pos;
+            let mut parts = 0;
+            let mut cjk = String::new();
+            for word in chunk.unicode_words() {
+                if word.chars().all(is_cjk) {
+                    cjk.push_str(word);
+                    continue;
+                }
+                self.push_cjk(&mut cjk, &mut pos, &mut out);
+                out.push((pos, word.to_string()));
+                pos += 1;
+                parts += 1;
+            }
+            self.push_cjk(&mut cjk, &mut pos, &mut out);
+
+            // "AC/DC" or "hip-hop" are also indexed joined, so they can be searched for either way
+            if parts > 1 && pos - start == parts {
+                let joined: String = out[out.len() - parts as usize..].iter()
+                    .map(|(_, w)| w.as_str())
+                    .collect();
+                out.push((start, joined));
+            }
+        }
+        out
+    }
+
+    /// Emit a run of CJK characters as bigrams or single characters
+    fn push_cjk(&self, run: &mut String, pos: &mut u32, out: &mut Vec<(u32, String)>) {
+        let chars: Vec<char> = run.chars().collect();
+        if self.cjk_bigrams && chars.len() > 1 {
+            for pair in chars.windows(2) {
+                out.push((*pos, pair.iter().collect()));
+                *pos += 1;
+            }
+        } else {
+            for c in chars {
+                out.push((*pos, c.to_string()));
+                *pos += 1;
+            }
+        }
+        run.clear();
+    }
+}
+
+/// Han ideographs, kana and hangul, which aren't separated by spaces
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{20000}'..='\u{2fa1f}')
+}
+
+#[derive(Debug, Clone)]
+/// Turns text into the stems stored in a term index
+///
+/// The same analyzer has to be used for indexing and querying, otherwise query terms won't
+/// match the indexed stems.
+pub struct Analyzer {
+    /// Stemming algorithm, or `None` to keep words as they are
+    language: Option<Algorithm>,
+    /// Custom stopwords replacing the built-in list
+    stopwords: Option<HashSet<String>>,
+    tokenizer: Tokenizer,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self::new(Some(Algorithm::English), None, Tokenizer::default())
+    }
+}
+
+impl Analyzer {
+    /// Without custom stopwords the built-in list is used for English. There are no built-in
+    /// lists for other languages.
+    pub fn new(language: Option<Algorithm>, stopwords: Option<&[String]>, tokenizer: Tokenizer) -> Self {
+        let stopwords = stopwords.map(|s| s.iter().map(|w| tokenizer.normalize(w)).collect());
+        Self { language, stopwords, tokenizer }
+    }
+
+    fn is_stopword(&self, word: &str) -> bool {
+        match self.stopwords {
+            Some(ref s) => s.contains(word),
+            None => self.language == Some(Algorithm::English) && STOPWORDS.contains(word),
+        }
+    }
+
+    fn stem<'a>(&self, stemmer: &Option<Stemmer>, word: &'a str) -> Cow<'a, str> {
+        match stemmer {
+            Some(s) => s.stem(word),
+            None => Cow::Borrowed(word),
+        }
+    }
+
+    /// Stem a single search term the same way indexed text is stemmed
+    pub fn query_stem(&self, term: &str) -> String {
+        let s = self.language.map(Stemmer::create);
+        let mut stem = self.stem(&s, &self.tokenizer.normalize(term)).into_owned();
+        keyword::truncate_key(&mut stem);
+        stem
+    }
+
+    /// Split a text into the stems it is indexed under
+    pub fn stems(&self, term: &str) -> Vec<String> {
+        self.tokens(term).into_iter().map(|(_, s)| s).collect()
+    }
+
+    /// Split a text into stems together with their word offset in the text
+    ///
+    /// Stopwords are dropped but still counted, so the offsets of the remaining stems keep the
+    /// distance they had in the original text. Stems are cut to `keyword::MAX_KEY` bytes to fit
+    /// into an LMDB key, e.g. for URLs or hashes in the text of a file.
+    pub fn tokens(&self, term: &str) -> Vec<(u32, String)> {
+        let s = self.language.map(Stemmer::create);
+
+        let words = self.tokenizer.words(term);
+        let fillwords = words.iter().filter(|(_, w)| !self.is_stopword(w));
+        let wordstems = fillwords.map(|(i, w)| (*i, self.stem(&s, w)));
+
+        let stemfilter = wordstems.filter(|(_, s)| !self.is_stopword(s));
+        let filtered = stemfilter.filter(|(_, s)| !s.is_empty());
+
+        filtered.map(|(i, s)| {
+            let mut s = s.into_owned();
+            keyword::truncate_key(&mut s);
+            (i, s)
+        }).collect()
+    }
+
+    /// Positions of every stem in all values of a field
+    ///
+    /// Each value starts `VALUE_GAP` positions after the previous one.
+    pub fn positions<'a, I: IntoIterator<Item=&'a str>>(&self, values: I) -> HashMap<String, Positions> {
+        let mut out: HashMap<String, Positions> = HashMap::new();
+        let mut offset = 0;
+        for value in values {
+            let t = self.tokens(value);
+            let len = t.iter().map(|(i, _)| i + 1).max().unwrap_or(0);
+            for (i, stem) in t {
+                out.entry(stem).or_default().push(offset + i);
+            }
+            offset += len + VALUE_GAP;
+        }
+        out
+    }
+}
+
+/// Check if the `query` tokens occur with the same relative offsets in `positions`
+pub fn match_phrase(query: &[(u32, String)], positions: &HashMap<String, Positions>) -> bool {
+    let (first, rest) = match query.split_first() {
+        Some(x) => x,
+        None => return false,
+    };
+    let starts = match positions.get(&first.1) {
+        Some(p) => p,
+        None => return false,
+    };
+
+    starts.iter().any(|start| rest.iter().all(|(i, stem)| {
+        let want = start + (i - first.0);
+        positions.get(stem).is_some_and(|p| p.contains(&want))
+    }))
+}
+
+/// Check if all `query` stems occur within a window spanning at most `distance` positions
+pub fn match_near(query: &[(u32, String)], distance: u32, positions: &HashMap<String, Positions>) -> bool {
+    let stems: Vec<&str> = {
+        let mut s: Vec<&str> = query.iter().map(|(_, s)| s.as_str()).collect();
+        s.sort_unstable();
+        s.dedup();
+        s
+    };
+    if stems.is_empty() {
+        return false;
+    }
+
+    // All occurrences of the query stems in text order, tagged with the stem they belong to
+    let mut hits = Vec::new();
+    for (n, stem) in stems.iter().enumerate() {
+        match positions.get(*stem) {
+            Some(p) => hits.extend(p.iter().map(|pos| (*pos, n))),
+            None => return false,
+        }
+    }
+    hits.sort_unstable();
+
+    // Sliding window over the hits, looking for one that contains every stem
+    let mut counts = vec![0usize; stems.len()];
+    let mut covered = 0;
+    let mut lo = 0;
+    for &(pos, n) in hits.iter() {
+        if counts[n] == 0 {
+            covered += 1;
+        }
+        counts[n] += 1;
+        while hits[lo].0 + distance < pos {
+            let m = hits[lo].1;
+            counts[m] -= 1;
+            if counts[m] == 0 {
+                covered -= 1;
+            }
+            lo += 1;
+        }
+        if covered == stems.len() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Match `s` against a pattern where `*` matches any number of characters and `?` exactly one
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    let (mut pi, mut si) = (0, 0);
+    // Position of the last `*` and the input position it was tried at, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((sp, ss)) = star {
+            // Let the `*` swallow one more character
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Check if the Levenshtein distance between `a` and `b` is at most `max`
+pub fn within_edits(a: &str, b: &str, max: u32) -> bool {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let max = max as usize;
+    if a.len().abs_diff(b.len()) > max {
+        return false;
+    }
+
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = Vec::with_capacity(b.len() + 1);
+        cur.push(i + 1);
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + if ca == cb { 0 } else { 1 };
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        // Distances only grow from here on
+        if cur.iter().all(|d| *d > max) {
+            return false;
+        }
+        prev = cur;
+    }
+    prev[b.len()] <= max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrase_positions() {
+        let a = Analyzer::default();
+        let tokens = |t| a.tokens(t);
+        let p = a.positions(vec!["The Dark Side of the Moon", "Dark Star"]);
+        assert_eq!(p["dark"], vec![1, 6 + VALUE_GAP]);
+
+        assert!(match_phrase(&tokens("the dark side"), &p));
+        assert!(match_phrase(&tokens("side of the moon"), &p));
+        assert!(!match_phrase(&tokens("dark moon"), &p));
+        // Values are separated, phrases don't cross from one into the next
+        assert!(!match_phrase(&tokens("moon dark"), &p));
+    }
+
+    #[test]
+    fn near_positions() {
+        let a = Analyzer::default();
+        let tokens = |t| a.tokens(t);
+        let p = a.positions(vec!["The Dark Side of the Moon"]);
+        assert!(match_near(&tokens("moon dark"), 4, &p));
+        assert!(!match_near(&tokens("moon dark"), 3, &p));
+        assert!(!match_near(&tokens("moon star"), 10, &p));
+    }
+
+    #[test]
+    fn languages() {
+        let de = Analyzer::new(Some(Algorithm::German), Some(&["der".to_string(), "und".to_string()]), Tokenizer::default());
+        assert_eq!(de.stems("Der Mond und die Sterne"), vec!["mond", "die", "stern"]);
+        assert_eq!(de.query_stem("Sternen"), "stern");
+
+        let exact = Analyzer::new(None, None, Tokenizer::default());
+        assert_eq!(exact.stems("The Running Man"), vec!["the", "running", "man"]);
+        assert_eq!(Analyzer::default().stems("The Running Man"), vec!["run", "man"]);
+    }
+
+    #[test]
+    fn tokenizers() {
+        let unicode = Tokenizer { segmentation: Segmentation::Unicode, strip_diacritics: true, cjk_bigrams: true };
+        let a = Analyzer::new(None, Some(&[]), unicode);
+        assert_eq!(a.tokens("AC/DC"), vec![(0, "ac".to_string()), (1, "dc".to_string()), (0, "acdc".to_string())]);
+        assert_eq!(a.stems("Björk – Homogenic"), vec!["bjork", "homogenic"]);
+        assert_eq!(a.query_stem("BJÖRK"), "bjork");
+        assert_eq!(a.stems("hip-hop"), vec!["hip", "hop", "hiphop"]);
+        assert_eq!(a.stems("千と千尋の神隠し"), vec!["千と", "と千", "千尋", "尋の", "の神", "神隠", "隠し"]);
+
+        let p = a.positions(vec!["Highway to Hell by AC/DC"]);
+        assert!(match_phrase(&a.tokens("ac-dc"), &p));
+        assert!(match_phrase(&a.tokens("千尋"), &a.positions(vec!["千と千尋の神隠し"])));
+
+        // The old behaviour stays the default
+        let a = Analyzer::new(None, Some(&[]), Tokenizer::default());
+        assert_eq!(a.stems("AC/DC Björk hip-hop"), vec!["ac/dc", "björk", "hip-hop"]);
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match("lev*an", "leviathan"));
+        assert!(glob_match("lev*an", "levan"));
+        assert!(glob_match("l?v*", "leviathan"));
+        assert!(!glob_match("lev*an", "leviathans"));
+        assert!(!glob_match("l?v", "lev*"));
+    }
+
+    #[test]
+    fn edit_distance() {
+        assert!(within_edits("tchaikovski", "tchaikovski", 0));
+        assert!(within_edits("tchaikovski", "tschaikowski", 2));
+        assert!(!within_edits("tchaikovski", "tschaikowsky", 2));
+        assert!(within_edits("", "ab", 2));
+    }
+
+    #[test]
+    fn legacy_postings() {
+        let mut set = HashSet::new();
+        set.insert(UUID::from_u128(1));
+        set.insert(UUID::from_u128(2));
+        let bytes = bincode::serialize(&set).unwrap();
+        let (m, legacy) = Matches::decode_compat(&bytes).unwrap();
+        assert!(legacy);
+        assert_eq!(m.into_set(), set);
+
+        let mut map = HashMap::new();
+        map.insert(UUID::from_u128(1), vec![0, 4]);
+        let bytes = bincode::serialize(&Matches::new(map.clone())).unwrap();
+        assert_eq!(Matches::decode_compat(&bytes).unwrap(), (Matches::new(map), false));
+    }
 }
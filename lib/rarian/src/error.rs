@@ -1,8 +1,12 @@
 use std::io;
 use std::str;
+use std::fmt;
 use json;
 use uuid;
 
+use crate::db::meta::Metakey;
+use crate::schema::Attributetype;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -15,15 +19,81 @@ pub enum Error {
     Utf8(str::Utf8Error),
     UUID(uuid::Error),
     MalformedUUID,
+    MalformedKey,
     QueryType,
+    /// A query filter can't be applied to an attribute of that type
+    QueryTypeMismatch {
+        key: Metakey,
+        expected: Attributetype,
+        filter: String,
+    },
     QueryIterating,
-    QueryUnbalanced,
+    /// A parenthesis in the query is not matched
+    QueryUnbalanced {
+        column: usize,
+    },
     QueryUnexpectedEOS,
+    /// The query does not follow the grammar
+    QuerySyntax {
+        column: usize,
+        message: String,
+    },
     QueryBadInt(std::num::ParseIntError),
+    /// A date that isn't in one of the understood formats or doesn't exist
+    BadDate(String),
     BadMetakey,
     TypeError,
+    /// A value does not have the type the schema declares for its attribute
+    TypeMismatch {
+        key: Metakey,
+        expected: Attributetype,
+        found: Attributetype,
+    },
+    /// The attribute is not declared in the schema
+    UnknownAttribute(Metakey),
+    /// The attribute only indexes the text of files and can't have a value
+    NotStored(Metakey),
     MergeConflict,
     TriplicateEntry,
+    /// A file is not valid in the format it claims to be in
+    Malformed {
+        format: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::QueryTypeMismatch { key, expected, filter } =>
+                write!(f, "{}: expected {}, found {}", key, expected, filter),
+            Error::QueryUnbalanced { column } =>
+                write!(f, "column {}: unbalanced parenthesis", column),
+            Error::QueryUnexpectedEOS =>
+                write!(f, "unexpected end of query"),
+            Error::QuerySyntax { column, message } =>
+                write!(f, "column {}: {}", column, message),
+            Error::TypeMismatch { key, expected, found } =>
+                write!(f, "{}: expected {} value, found {} value", key, expected, found),
+            Error::BadDate(s) =>
+                write!(f, "invalid date {:?}", s),
+            Error::Malformed { format, message } =>
+                write!(f, "malformed {} file: {}", format, message),
+            Error::UnknownAttribute(key) =>
+                write!(f, "{}: attribute is not declared in the schema", key),
+            Error::NotStored(key) =>
+                write!(f, "{}: attribute is indexed from the text of files and has no values", key),
+            e => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl Error {
+    /// Whether the LMDB map ran out of space. The transaction has to be aborted then, see
+    /// `DBManager::write_with`.
+    pub fn is_map_full(&self) -> bool {
+        matches!(self, Error::LMDB(lmdb::Error::MapFull))
+    }
 }
 
 impl From<bincode::Error> for Error {
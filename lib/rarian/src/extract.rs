@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, BufReader};
+use std::path::Path;
+
+use crate::db::date::{Date, Precision};
+use crate::db::entry::{EntryT, FileT, FileKey, FormatKey};
+use crate::db::meta::{Metakey, Metavalue};
+use crate::error::{Result, Error};
+use crate::schema::{Schema, Attributetype};
+
+pub mod vorbis;
+pub mod id3;
+pub mod mp4;
+pub mod epub;
+pub mod gameboy;
+pub mod pdf;
+pub mod exiftool;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Metadata read from a single file
+///
+/// Keys are the lowercased tag names of the file format mapped to the usual attribute names
+/// where there is one, e.g. `TIT2` becomes `title`. They are not checked against any schema yet,
+/// use `conform` before storing them.
+pub struct Extracted {
+    pub metadata: HashMap<Metakey, Metavalue>,
+    pub format: HashMap<FormatKey, Box<str>>,
+    /// Start of the body text of documents, for full-text indexing. Not part of the entry.
+    pub text: String,
+}
+
+impl Extracted {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add values for `key`, keeping the ones already present. Values of a different type than
+    /// the present ones are dropped.
+    pub fn push(&mut self, key: &str, value: Metavalue) {
+        if value.is_empty() {
+            return;
+        }
+        let key = Metakey::new(key);
+        let value = match self.metadata.get(&key) {
+            Some(old) => match old.append(&value) {
+                Ok(v) => v,
+                Err(_) => return,
+            },
+            None => value,
+        };
+        self.metadata.insert(key, value);
+    }
+
+    /// Add a string, ignoring surrounding whitespace and NUL padding
+    pub fn push_str(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if !value.is_empty() {
+            self.push(key, Metavalue::Str(vec![value.into()].into_boxed_slice()));
+        }
+    }
+
+    pub fn push_int(&mut self, key: &str, value: i64) {
+        self.push(key, Metavalue::Int(vec![value].into_boxed_slice()));
+    }
+
+    pub fn push_date(&mut self, key: &str, value: Date) {
+        self.push(key, Metavalue::Date(vec![value].into_boxed_slice()));
+    }
+
+    /// Add a textual tag, parsing the values of attributes that are usually numbers or dates
+    ///
+    /// Track and disc numbers are often written as `3/12` or `3 of 12`, only the first number is
+    /// kept.
+    pub fn push_tag(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        match key {
+            "tracknumber" | "discnumber" => {
+                let n = value.split(|c: char| !c.is_ascii_digit()).next().and_then(|n| n.parse().ok());
+                match n {
+                    Some(n) => self.push_int(key, n),
+                    None => self.push_str(key, value),
+                }
+            }
+            "date" => match value.parse() {
+                Ok(d) => self.push_date(key, d),
+                Err(_) => self.push_str(key, value),
+            },
+            _ => self.push_str(key, value),
+        }
+    }
+
+    /// Append body text, keeping at most `limit` bytes in total. Returns whether there's room
+    /// for more.
+    pub fn push_text(&mut self, text: &str, limit: usize) -> bool {
+        let text = text.trim();
+        if text.is_empty() {
+            return self.text.len() < limit;
+        }
+        if !self.text.is_empty() && self.text.len() < limit {
+            self.text.push(' ');
+        }
+        let mut end = text.len().min(limit.saturating_sub(self.text.len()));
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text.push_str(&text[..end]);
+        self.text.len() < limit
+    }
+
+    pub fn set_format<S: ToString>(&mut self, key: FormatKey, value: S) {
+        self.format.insert(key, value.to_string().into_boxed_str());
+    }
+
+    /// Add everything `other` found that's not known yet
+    pub fn merge(&mut self, other: Extracted) {
+        for (k, v) in other.metadata {
+            self.push(k.as_str(), v);
+        }
+        for (k, v) in other.format {
+            self.format.entry(k).or_insert(v);
+        }
+        if self.text.is_empty() {
+            self.text = other.text;
+        }
+    }
+
+    /// Keep only the attributes declared in `schema`, converting values to the declared type
+    ///
+    /// Keys are matched case-insensitively. Values that can't be converted are dropped.
+    pub fn conform(&mut self, schema: &Schema) {
+        let metadata = std::mem::take(&mut self.metadata);
+        for (k, v) in metadata {
+            let k = match Metakey::from_str(k.as_str(), schema) {
+                Ok(k) => k,
+                Err(_) => continue,
+            };
+            let atype = match schema.attribute(&k) {
+                Some(a) => a.atype,
+                None => continue,
+            };
+            if let Some(v) = convert(v, atype) {
+                self.push(k.as_str(), v);
+            }
+        }
+    }
+
+    pub fn into_entry(self, key: FileKey) -> EntryT {
+        EntryT::new(FileT::new(key, self.format), self.metadata)
+    }
+}
+
+/// Convert `value` to `atype`, `None` if none of the values can be
+fn convert(value: Metavalue, atype: Attributetype) -> Option<Metavalue> {
+    if value.attributetype() == atype {
+        return Some(value);
+    }
+
+    let dates = |s: &[Box<str>]| -> Vec<Date> { s.iter().filter_map(|s| s.parse().ok()).collect() };
+    let v = match (value, atype) {
+        (Metavalue::Date(d), Attributetype::Timestamp) =>
+            Metavalue::Timestamp(d.iter().map(Date::start).collect()),
+        // Databases from before dates had their own type store the year
+        (Metavalue::Date(d), Attributetype::Int) =>
+            Metavalue::Int(d.iter().map(|d| d.year() as i64).collect()),
+        (Metavalue::Str(s), Attributetype::Int) =>
+            Metavalue::Int(s.iter().filter_map(|s| s.trim().parse().ok()).collect()),
+        (Metavalue::Str(s), Attributetype::Date) =>
+            Metavalue::Date(dates(&s).into_boxed_slice()),
+        (Metavalue::Str(s), Attributetype::Timestamp) =>
+            Metavalue::Timestamp(dates(&s).iter().map(Date::start).collect()),
+        (Metavalue::Int(i), Attributetype::String) =>
+            Metavalue::Str(i.iter().map(|i| i.to_string().into_boxed_str()).collect()),
+        (Metavalue::Date(d), Attributetype::String) =>
+            Metavalue::Str(d.iter().map(|d| d.to_string().into_boxed_str()).collect()),
+        (Metavalue::Timestamp(t), Attributetype::String) =>
+            Metavalue::Str(t.iter()
+                .filter_map(|t| Date::new(*t, Precision::Second).ok())
+                .map(|d| d.to_string().into_boxed_str())
+                .collect()),
+        _ => return None,
+    };
+    if v.is_empty() { None } else { Some(v) }
+}
+
+/// Anything that can be read from and seeked in, such as a file
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Reads the metadata of some kinds of files
+pub trait Extractor {
+    /// Name used in log messages
+    fn name(&self) -> &'static str;
+
+    /// MIME types of the files this extractor understands
+    fn mime_types(&self) -> &[&'static str];
+
+    /// Lowercase extensions of the files this extractor understands, for files whose type can't
+    /// be recognized by their contents
+    fn extensions(&self) -> &[&'static str] {
+        &[]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted>;
+
+    /// Extract up to `limit` bytes of body text as well, for extractors of documents
+    fn set_text_limit(&mut self, _limit: usize) {}
+
+    fn read_path(&self, path: &Path) -> Result<Extracted> {
+        let mut r = BufReader::new(File::open(path)?);
+        self.read(&mut r)
+    }
+}
+
+/// Set of extractors to choose from by the type of a file
+///
+/// The MIME type of a file is recognized by its first bytes. Extractors for that type are tried
+/// first, then the ones for the extension of the file and finally the fallback, until one of them
+/// finds any metadata.
+pub struct Registry {
+    extractors: Vec<Box<dyn Extractor>>,
+    fallback: Option<Box<dyn Extractor>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self { extractors: Vec::new(), fallback: None }
+    }
+
+    /// All native extractors, without a fallback
+    pub fn builtin() -> Self {
+        let mut r = Self::new();
+        r.register(vorbis::Flac);
+        r.register(vorbis::Ogg);
+        r.register(id3::Id3);
+        r.register(mp4::Mp4);
+        r.register(epub::Epub::default());
+        r.register(pdf::Pdf::default());
+        r.register(gameboy::GameBoy);
+        r
+    }
+
+    /// Add an extractor, preferring it over the ones registered before for the same types
+    pub fn register<E: Extractor + 'static>(&mut self, e: E) {
+        self.extractors.insert(0, Box::new(e));
+    }
+
+    /// Have all extractors that can read body text read up to `limit` bytes of it
+    pub fn set_text_limit(&mut self, limit: usize) {
+        for e in self.extractors.iter_mut().chain(self.fallback.iter_mut()) {
+            e.set_text_limit(limit);
+        }
+    }
+
+    /// Set the extractor to try for all files the others can't read, e.g. `exiftool::Exiftool`
+    pub fn set_fallback(&mut self, e: Option<Box<dyn Extractor>>) {
+        self.fallback = e;
+    }
+
+    /// Extractors to try for a file of type `mime` with extension `ext`, in order
+    pub fn candidates(&self, mime: Option<&str>, ext: Option<&str>) -> Vec<&dyn Extractor> {
+        let by_mime = self.extractors.iter()
+            .filter(|e| mime.is_some_and(|m| e.mime_types().contains(&m)));
+        let by_ext = self.extractors.iter()
+            .filter(|e| ext.is_some_and(|x| e.extensions().iter().any(|e| e.eq_ignore_ascii_case(x))));
+
+        let mut out: Vec<&dyn Extractor> = Vec::new();
+        for e in by_mime.chain(by_ext).chain(self.fallback.iter()) {
+            if !out.iter().any(|o| o.name() == e.name()) {
+                out.push(e.as_ref());
+            }
+        }
+        out
+    }
+
+    /// Read the metadata of `path` with the first extractor that can
+    ///
+    /// Returns `None` if there is no extractor for the type of the file, or the error of the last
+    /// one tried if none succeeded.
+    pub fn extract(&self, path: &Path) -> Result<Option<Extracted>> {
+        let mime = mime_type(&mut BufReader::new(File::open(path)?))?;
+        let ext = path.extension().and_then(|e| e.to_str());
+        self.try_each(mime, ext, |e| e.read_path(path))
+    }
+
+    /// Read the metadata of a file with the first extractor that can
+    ///
+    /// `ext` is the extension of the file, if it has a name at all.
+    pub fn read(&self, r: &mut dyn ReadSeek, ext: Option<&str>) -> Result<Option<Extracted>> {
+        let mime = mime_type(r)?;
+        self.try_each(mime, ext, |e| {
+            r.seek(SeekFrom::Start(0))?;
+            e.read(r)
+        })
+    }
+
+    /// Results with nothing but the format don't stop the search, another extractor, e.g. the
+    /// fallback, might know more. The first of them is returned if none does.
+    fn try_each<F>(&self, mime: Option<&str>, ext: Option<&str>, mut f: F) -> Result<Option<Extracted>>
+        where F: FnMut(&dyn Extractor) -> Result<Extracted>
+    {
+        let mut err = None;
+        let mut empty: Option<Extracted> = None;
+        for e in self.candidates(mime, ext) {
+            match f(e) {
+                Ok(mut x) => {
+                    if let Some(m) = mime {
+                        x.format.entry(FormatKey::MimeType).or_insert_with(|| m.into());
+                    }
+                    if x.metadata.is_empty() && x.text.is_empty() {
+                        debug!("{} found no metadata", e.name());
+                        empty.get_or_insert(x);
+                        continue;
+                    }
+                    if let Some(mut first) = empty {
+                        first.merge(x);
+                        x = first;
+                    }
+                    return Ok(Some(x));
+                }
+                Err(e2) => {
+                    debug!("{} could not read file: {}", e.name(), e2);
+                    err = Some(e2);
+                }
+            }
+        }
+        match (empty, err) {
+            (Some(x), _) => Ok(Some(x)),
+            (None, Some(e)) => Err(e),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Recognize the type of a file by its first bytes, leaving the reader at the start
+pub fn mime_type<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Option<&'static str>> {
+    // Game Boy ROMs are only recognized by the logo in their header
+    let mut magic = [0; gameboy::HEADER_LEN];
+    r.seek(SeekFrom::Start(0))?;
+    let n = read_up_to(r, &mut magic)?;
+    r.seek(SeekFrom::Start(0))?;
+    let magic = &magic[..n];
+
+    let mime = if magic.starts_with(b"fLaC") {
+        "audio/flac"
+    } else if magic.starts_with(b"OggS") {
+        "audio/ogg"
+    } else if magic.starts_with(b"ID3") || id3::is_frame_header(magic) {
+        "audio/mpeg"
+    } else if magic.len() >= 12 && &magic[4..8] == b"ftyp" {
+        match &magic[8..12] {
+            b"M4A " | b"M4B " | b"M4P " => "audio/mp4",
+            b"qt  " => "video/quicktime",
+            _ => "video/mp4",
+        }
+    } else if magic.starts_with(b"PK\x03\x04") {
+        // EPUBs start with an uncompressed file `mimetype` saying so
+        if magic.get(30..58) == Some(b"mimetypeapplication/epub+zip") {
+            "application/epub+zip"
+        } else {
+            "application/zip"
+        }
+    } else if magic.starts_with(b"%PDF-") {
+        "application/pdf"
+    } else if magic.get(0x104..0x134) == Some(&gameboy::LOGO[..]) {
+        gameboy::mime_type(magic)
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(mime))
+}
+
+/// Fill `buf` as far as the reader allows, returning the number of bytes read
+pub(crate) fn read_up_to<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+pub(crate) fn malformed<S: Into<String>>(format: &'static str, message: S) -> Error {
+    Error::Malformed { format, message: message.into() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Attribute;
+
+    #[test]
+    fn conformed() {
+        let mut x = Extracted::new();
+        x.push_tag("title", "Leviathan\0");
+        x.push_tag("tracknumber", "3/12");
+        x.push_tag("date", "1999-06");
+        x.push_tag("discnumber", "1");
+        x.push_tag("genre", "Metal");
+        x.push_tag("title", "Leviathan");
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["Leviathan".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("tracknumber")], Metavalue::Int(vec![3].into_boxed_slice()));
+
+        let mut attributes = HashMap::new();
+        attributes.insert(Metakey::new("Title"), Attribute::new(Attributetype::String, None));
+        attributes.insert(Metakey::new("tracknumber"), Attribute::new(Attributetype::String, None));
+        attributes.insert(Metakey::new("date"), Attribute::new(Attributetype::Int, None));
+        let schema = Schema {
+            name: "test".to_string(),
+            description: String::new(),
+            version: (0, 1),
+            attributes,
+        };
+        x.conform(&schema);
+        assert_eq!(x.metadata.len(), 3);
+        assert_eq!(x.metadata[&Metakey::new("Title")], Metavalue::Str(vec!["Leviathan".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("tracknumber")], Metavalue::Str(vec!["3".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")], Metavalue::Int(vec![1999].into_boxed_slice()));
+    }
+
+    struct Fixed(&'static str, bool);
+
+    impl Extractor for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn mime_types(&self) -> &[&'static str] {
+            &["audio/mpeg"]
+        }
+
+        fn extensions(&self) -> &[&'static str] {
+            &["gb"]
+        }
+
+        fn read(&self, _: &mut dyn ReadSeek) -> Result<Extracted> {
+            if !self.1 {
+                return Err(malformed("test", "unreadable"));
+            }
+            let mut x = Extracted::new();
+            x.push_str("title", self.0);
+            Ok(x)
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        use std::io::Cursor;
+
+        let mut r = Registry::builtin();
+        // An MP3 without ID3 tag
+        let x = r.read(&mut Cursor::new(vec![0xff, 0xfb, 0x90, 0x64]), None).unwrap().unwrap();
+        assert_eq!(&*x.format[&FormatKey::MimeType], "audio/mpeg");
+        assert!(x.metadata.is_empty());
+        assert!(r.read(&mut Cursor::new(b"unknown".to_vec()), Some("txt")).unwrap().is_none());
+        // UTF-16 text, whose byte order mark looks like an MPEG frame at first
+        assert!(r.read(&mut Cursor::new(b"\xff\xfeh\0i\0".to_vec()), Some("txt")).unwrap().is_none());
+
+        r.register(Fixed("rom", true));
+        let x = r.read(&mut Cursor::new(b"unknown".to_vec()), Some("GB")).unwrap().unwrap();
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["rom".into()].into_boxed_slice()));
+        assert!(!x.format.contains_key(&FormatKey::MimeType));
+
+        // Registered later, so tried first
+        r.register(Fixed("broken", false));
+        r.set_fallback(Some(Box::new(Fixed("fallback", true))));
+        let names: Vec<_> = r.candidates(Some("audio/mpeg"), Some("gb")).iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["broken", "rom", "id3", "gameboy", "fallback"]);
+        let x = r.read(&mut Cursor::new(b"unknown".to_vec()), None).unwrap().unwrap();
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["fallback".into()].into_boxed_slice()));
+        // Files the builtin extractors find nothing in are handed on to the fallback
+        let mut r = Registry::builtin();
+        r.set_fallback(Some(Box::new(Fixed("fallback", true))));
+        let x = r.read(&mut Cursor::new(vec![0xff, 0xfb, 0x90, 0x64]), Some("mp3")).unwrap().unwrap();
+        assert_eq!(&*x.format[&FormatKey::MimeType], "audio/mpeg");
+        assert_eq!(&*x.format[&FormatKey::Container], "MP3");
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["fallback".into()].into_boxed_slice()));
+    }
+}
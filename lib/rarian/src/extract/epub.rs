@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use crate::db::entry::FormatKey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed};
+
+/// Largest file read from the archive
+const MAX_XML: u64 = 4 * 1024 * 1024;
+
+/// Dublin Core elements of the package metadata and the attributes they are stored as
+const ELEMENTS: [(&str, &str); 7] = [
+    ("title", "title"),
+    ("creator", "author"),
+    ("date", "date"),
+    ("description", "description"),
+    ("language", "language"),
+    ("publisher", "publisher"),
+    ("subject", "subject"),
+];
+
+/// MARC relator codes of creators and the attributes they are stored as. Creators without a role
+/// are authors.
+const ROLES: [(&str, &str); 6] = [
+    ("aut", "author"),
+    ("edt", "editor"),
+    ("trl", "translator"),
+    ("ill", "illustrator"),
+    ("nrt", "narrator"),
+    ("pbl", "publisher"),
+];
+
+/// Extractor for EPUB books
+#[derive(Default)]
+pub struct Epub {
+    text: usize,
+}
+
+impl Extractor for Epub {
+    fn name(&self) -> &'static str {
+        "epub"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["application/epub+zip"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["epub"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read(r, self.text)
+    }
+
+    fn set_text_limit(&mut self, limit: usize) {
+        self.text = limit;
+    }
+}
+
+/// Read the metadata of the package document of an EPUB, and up to `text` bytes of the text of
+/// its documents in reading order
+pub fn read<R: Read + Seek + ?Sized>(r: &mut R, text: usize) -> Result<Extracted> {
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, "application/epub+zip");
+    x.set_format(FormatKey::Container, "EPUB");
+
+    let mut zip = ZipArchive::new(r).map_err(zip_error)?;
+    let container = read_file(&mut zip, "META-INF/container.xml")?;
+    let path = rootfile(&container)?;
+    let opf = read_file(&mut zip, &path)?;
+    let package = package(&opf)?;
+
+    for e in package.metadata.iter() {
+        match e.name.as_str() {
+            "identifier" => if let Some(isbn) = isbn(e, &package) {
+                x.push_str("isbn", &isbn);
+            },
+            "creator" | "contributor" => {
+                // EPUB 2 has the role as attribute, EPUB 3 refines the element with a meta
+                let role = e.attribute("role").or_else(|| package.refinement(e, "role"));
+                let key = match role {
+                    None if e.name == "creator" => "author",
+                    None => "contributor",
+                    Some(r) => ROLES.iter().find(|(code, _)| code.eq_ignore_ascii_case(r)).map_or("contributor", |(_, k)| k),
+                };
+                x.push_tag(key, &e.text);
+            }
+            name => if let Some((_, key)) = ELEMENTS.iter().find(|(n, _)| *n == name) {
+                x.push_tag(key, &e.text);
+            },
+        }
+    }
+
+    if text > 0 {
+        for href in package.spine.iter() {
+            // Missing or unparseable documents are skipped, the metadata is what matters
+            let body = match read_file(&mut zip, &resolve(&path, href)) {
+                Ok(b) => b,
+                Err(_) => continue,
+            };
+            if !x.push_text(&xhtml_text(&body), text) {
+                break;
+            }
+        }
+    }
+
+    Ok(x)
+}
+
+/// An element of the package metadata
+struct Element {
+    /// Local name, without the `dc:` prefix
+    name: String,
+    /// Attributes by their local name
+    attributes: Vec<(String, String)>,
+    text: String,
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// The parts of a package document that are used
+struct Package {
+    metadata: Vec<Element>,
+    /// Paths of the documents in reading order, relative to the package document
+    spine: Vec<String>,
+}
+
+impl Package {
+    /// Text of the EPUB 3 `meta` refining `e` with `property`
+    fn refinement(&self, e: &Element, property: &str) -> Option<&str> {
+        let id = e.attribute("id")?;
+        self.metadata.iter()
+            .filter(|m| m.name == "meta" && m.attribute("property") == Some(property))
+            .find(|m| m.attribute("refines").and_then(|r| r.strip_prefix('#')) == Some(id))
+            .map(|m| m.text.as_str())
+    }
+}
+
+/// The identifier as ISBN without hyphens, if it is one
+fn isbn(e: &Element, package: &Package) -> Option<String> {
+    let text = e.text.trim().to_ascii_uppercase();
+    let scheme = e.attribute("scheme").is_some_and(|s| s.eq_ignore_ascii_case("isbn"))
+        // ONIX codes for ISBN-10 and ISBN-13
+        || matches!(package.refinement(e, "identifier-type"), Some("02") | Some("15"));
+    let value = match text.strip_prefix("URN:ISBN:").or_else(|| text.strip_prefix("ISBN:")) {
+        Some(v) => v,
+        None if scheme => &text,
+        None => return None,
+    };
+
+    let isbn: String = value.chars().filter(|c| !matches!(c, '-' | ' ')).collect();
+    let b = isbn.as_bytes();
+    let valid = match b.len() {
+        10 => b[..9].iter().all(u8::is_ascii_digit) && (b[9].is_ascii_digit() || b[9] == b'X'),
+        13 => b.iter().all(u8::is_ascii_digit),
+        _ => false,
+    };
+    if valid { Some(isbn) } else { None }
+}
+
+/// Path of the package document inside the archive
+fn rootfile(container: &[u8]) -> Result<String> {
+    let mut reader = Reader::from_reader(container);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"rootfile" => {
+                for a in e.attributes().flatten() {
+                    if a.key.local_name().as_ref() == b"full-path" {
+                        return a.unescape_value().map(|v| v.into_owned()).map_err(xml_error);
+                    }
+                }
+            }
+            Ok(Event::Eof) => return Err(malformed("EPUB", "container.xml names no rootfile")),
+            Err(e) => return Err(xml_error(e)),
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+/// Read the metadata, manifest and spine of a package document
+fn package(opf: &[u8]) -> Result<Package> {
+    let mut reader = Reader::from_reader(opf);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut metadata = Vec::new();
+    let mut manifest = HashMap::new();
+    let mut idrefs = Vec::new();
+    let mut in_metadata = false;
+    let mut current: Option<Element> = None;
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                if name == "metadata" {
+                    in_metadata = true;
+                } else if in_metadata && current.is_none() {
+                    current = Some(Element { name, attributes: attributes(&e), text: String::new() });
+                }
+            }
+            Ok(Event::Empty(e)) => {
+                let attributes = attributes(&e);
+                let get = |n: &str| attributes.iter().find(|(k, _)| k == n).map(|(_, v)| v.clone());
+                match e.local_name().as_ref() {
+                    b"item" => if let (Some(id), Some(href)) = (get("id"), get("href")) {
+                        manifest.insert(id, href);
+                    },
+                    b"itemref" => idrefs.extend(get("idref")),
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(t)) => if let Some(e) = current.as_mut() {
+                e.text.push_str(&t.unescape().map_err(xml_error)?);
+            },
+            Ok(Event::CData(t)) => if let Some(e) = current.as_mut() {
+                e.text.push_str(&String::from_utf8_lossy(&t));
+            },
+            Ok(Event::End(e)) => {
+                let name = e.local_name();
+                if name.as_ref() == b"metadata" {
+                    in_metadata = false;
+                }
+                if current.as_ref().is_some_and(|c| c.name.as_bytes() == name.as_ref()) {
+                    metadata.extend(current.take());
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_error(e)),
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    let spine = idrefs.iter().filter_map(|id| manifest.get(id).cloned()).collect();
+    Ok(Package { metadata, spine })
+}
+
+fn attributes(e: &BytesStart) -> Vec<(String, String)> {
+    e.attributes().flatten()
+        .map(|a| {
+            let name = String::from_utf8_lossy(a.key.local_name().as_ref()).into_owned();
+            let value = a.unescape_value().map(|v| v.into_owned())
+                .unwrap_or_else(|_| String::from_utf8_lossy(&a.value).into_owned());
+            (name, value)
+        })
+        .collect()
+}
+
+/// Path inside the archive of `href`, relative to the package document at `base`
+fn resolve(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or(href);
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for p in href.split('/') {
+        match p {
+            "." | "" => {}
+            ".." => { parts.pop(); }
+            p => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+/// Text content of an XHTML document, ignoring its head, scripts and styles
+fn xhtml_text(b: &[u8]) -> String {
+    let mut reader = Reader::from_reader(b);
+    reader.trim_text(true);
+    reader.check_end_names(false);
+    let mut buf = Vec::new();
+    let mut text = String::new();
+    let mut skip = 0usize;
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => if matches!(e.local_name().as_ref(), b"head" | b"script" | b"style") {
+                skip += 1;
+            },
+            Ok(Event::End(e)) => if matches!(e.local_name().as_ref(), b"head" | b"script" | b"style") {
+                skip = skip.saturating_sub(1);
+            },
+            // XHTML may use entities of HTML that are not defined in XML, they're mostly spaces
+            Ok(Event::Text(t)) if skip == 0 => {
+                let t = t.unescape_with(|_| Some(" "))
+                    .map(|t| t.into_owned())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&t).into_owned());
+                if !text.is_empty() {
+                    text.push(' ');
+                }
+                text.push_str(&t);
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    text
+}
+
+fn read_file<R: Read + Seek>(zip: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+    let f = zip.by_name(name).map_err(zip_error)?;
+    let mut b = Vec::new();
+    f.take(MAX_XML).read_to_end(&mut b)?;
+    Ok(b)
+}
+
+fn zip_error(e: ZipError) -> crate::error::Error {
+    malformed("EPUB", e.to_string())
+}
+
+fn xml_error(e: quick_xml::Error) -> crate::error::Error {
+    malformed("EPUB", e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::{ZipWriter, FileOptions};
+    use zip::CompressionMethod;
+    use crate::db::meta::{Metakey, Metavalue};
+
+    fn epub(opf: &str, chapter: &str) -> Cursor<Vec<u8>> {
+        let mut w = ZipWriter::new(Cursor::new(Vec::new()));
+        let o = FileOptions::default().compression_method(CompressionMethod::Stored);
+        w.start_file("mimetype", o).unwrap();
+        w.write_all(b"application/epub+zip").unwrap();
+        w.start_file("META-INF/container.xml", o).unwrap();
+        w.write_all(br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#).unwrap();
+        w.start_file("OEBPS/content.opf", o).unwrap();
+        w.write_all(opf.as_bytes()).unwrap();
+        w.start_file("OEBPS/text/chapter1.xhtml", o).unwrap();
+        w.write_all(chapter.as_bytes()).unwrap();
+        let mut c = w.finish().unwrap();
+        c.set_position(0);
+        c
+    }
+
+    #[test]
+    fn package_metadata() {
+        let mut f = epub(r##"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata>
+    <dc:title>The Left Hand of Darkness</dc:title>
+    <dc:creator opf:role="aut">Ursula K. Le Guin</dc:creator>
+    <dc:creator id="ill">Jane Doe</dc:creator>
+    <meta refines="#ill" property="role" scheme="marc:relators">ill</meta>
+    <dc:identifier opf:scheme="ISBN">0-441-47812-3</dc:identifier>
+    <dc:identifier>urn:isbn:978-0-441-47812-5</dc:identifier>
+    <dc:identifier>urn:uuid:a6c0e3e2-1d3e-4c3b-9a3e-0b4a4c3b9a3e</dc:identifier>
+    <dc:date>1969-03</dc:date>
+    <dc:description><![CDATA[A <i>classic</i>.]]></dc:description>
+    <dc:language>en</dc:language>
+  </metadata>
+  <manifest>
+    <item id="c1" href="text/chapter1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="text/missing.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine><itemref idref="c2"/><itemref idref="c1"/></spine>
+</package>"##, r#"<?xml version="1.0"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter 1</title><style>p {}</style></head>
+<body><h1>A Parade in Erhenrang</h1><p>I&#8217;ll make my report&nbsp;as if I told a story</p></body></html>"#);
+        assert_eq!(crate::extract::mime_type(&mut f).unwrap(), Some("application/epub+zip"));
+        let x = read(&mut f, 40).unwrap();
+        let s = |v: &str| Metavalue::Str(vec![v.into()].into_boxed_slice());
+        assert_eq!(x.metadata[&Metakey::new("title")], s("The Left Hand of Darkness"));
+        assert_eq!(x.metadata[&Metakey::new("author")], s("Ursula K. Le Guin"));
+        assert_eq!(x.metadata[&Metakey::new("description")], s("A <i>classic</i>."));
+        assert_eq!(x.metadata[&Metakey::new("language")], s("en"));
+        assert_eq!(x.metadata[&Metakey::new("illustrator")], s("Jane Doe"));
+        assert_eq!(x.metadata[&Metakey::new("isbn")], Metavalue::Str(vec!["0441478123".into(), "9780441478125".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")].to_date().next().unwrap().to_string(), "1969-03");
+        assert_eq!(x.text, "A Parade in Erhenrang I\u{2019}ll make my rep");
+    }
+
+    #[test]
+    fn invalid_isbn() {
+        let mut f = epub(r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/" version="3.0">
+  <metadata>
+    <dc:title>Broken</dc:title>
+    <dc:identifier>urn:isbn:12345678é</dc:identifier>
+    <dc:identifier>isbn:978-0-441-4781X-5</dc:identifier>
+  </metadata>
+</package>"#, "");
+        let x = read(&mut f, 0).unwrap();
+        assert!(!x.metadata.contains_key(&Metakey::new("isbn")));
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use serde_json::Value;
+
+use crate::db::entry::FormatKey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed};
+
+/// Tags exiftool reports and the attributes they are stored as
+const TAGS: [(&str, &str); 15] = [
+    ("Title", "title"),
+    ("Artist", "artist"),
+    ("Album", "album"),
+    ("Albumartist", "albumartist"),
+    ("AlbumArtist", "albumartist"),
+    ("TrackNumber", "tracknumber"),
+    ("Track", "tracknumber"),
+    ("Comment", "comment"),
+    ("Genre", "genre"),
+    ("Author", "author"),
+    ("Creator", "author"),
+    ("Description", "description"),
+    ("Publisher", "publisher"),
+    ("Language", "language"),
+    ("Subject", "subject"),
+];
+
+/// Date tags, most precise first
+const DATES: [&str; 4] = ["DateTimeOriginal", "Date", "CreateDate", "Year"];
+
+/// Extractor running `exiftool`, which knows about almost any kind of file
+///
+/// Fails with an `Io` error if exiftool isn't installed. As it claims no types it's only used as
+/// fallback.
+pub struct Exiftool;
+
+impl Extractor for Exiftool {
+    fn name(&self) -> &'static str {
+        "exiftool"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Pipe the file into exiftool
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        let mut b = Vec::new();
+        r.read_to_end(&mut b)?;
+
+        let mut child = Command::new("exiftool")
+            .args(["-j", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        // exiftool may stop reading early, so write from another thread and ignore errors
+        let writer = child.stdin.take().map(|mut stdin| thread::spawn(move || stdin.write_all(&b)));
+        let output = child.wait_with_output()?;
+        if let Some(w) = writer {
+            let _ = w.join();
+        }
+        parse(&output.stdout)
+    }
+
+    fn read_path(&self, path: &Path) -> Result<Extracted> {
+        let output = Command::new("exiftool")
+            .arg("-j")
+            .arg(path)
+            .output()?;
+        parse(&output.stdout)
+    }
+}
+
+fn parse(json: &[u8]) -> Result<Extracted> {
+    let mut tags: Vec<HashMap<String, Value>> = serde_json::from_slice(json)
+        .map_err(|e| malformed("exiftool output", e.to_string()))?;
+    let tags = tags.pop().ok_or_else(|| malformed("exiftool output", "no results"))?;
+    Ok(convert(&tags))
+}
+
+fn convert(tags: &HashMap<String, Value>) -> Extracted {
+    let mut x = Extracted::new();
+    if let Some(Value::String(m)) = tags.get("MIMEType") {
+        x.set_format(FormatKey::MimeType, m);
+    }
+
+    for (tag, key) in TAGS.iter() {
+        for v in tags.get(*tag).map(values).unwrap_or_default() {
+            x.push_tag(key, &v);
+        }
+    }
+
+    let date = DATES.iter()
+        .filter_map(|t| tags.get(*t))
+        .flat_map(values)
+        .find_map(|v| v.parse().ok());
+    if let Some(d) = date {
+        x.push_date("date", d);
+    }
+
+    x
+}
+
+/// exiftool gives multiple values as an array and anything that looks like a number as one
+fn values(v: &Value) -> Vec<String> {
+    match v {
+        Value::String(s) => vec![s.clone()],
+        Value::Number(n) => vec![n.to_string()],
+        Value::Array(a) => a.iter().flat_map(values).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::meta::{Metakey, Metavalue};
+
+    #[test]
+    fn json_output() {
+        let tags: Vec<HashMap<String, Value>> = serde_json::from_str(r#"[{
+            "SourceFile": "a.flac",
+            "MIMEType": "audio/flac",
+            "Title": 1984,
+            "Artist": ["Van Halen", "Eddie Van Halen"],
+            "TrackNumber": "1 of 9",
+            "Year": 1984,
+            "Date": "1984:01:09"
+        }]"#).unwrap();
+        let x = convert(&tags[0]);
+        assert_eq!(&*x.format[&FormatKey::MimeType], "audio/flac");
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["1984".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("artist")], Metavalue::Str(vec!["Van Halen".into(), "Eddie Van Halen".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("tracknumber")], Metavalue::Int(vec![1].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")].to_date().next().unwrap().to_string(), "1984-01-09");
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::{Read, Seek};
+
+use zip::ZipArchive;
+
+use crate::db::entry::FormatKey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed, read_up_to};
+
+/// Length of the cartridge header, counted from the start of the ROM
+pub const HEADER_LEN: usize = 0x150;
+
+/// The Nintendo logo every cartridge has to show at 0x104 to boot
+pub const LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+/// Extensions of ROM dumps, also used to find them in archives
+const EXTENSIONS: [&str; 5] = ["gb", "gbc", "cgb", "sgb", "dmg"];
+
+/// Cartridge types by the code at 0x147
+const CARTRIDGES: [(u8, &str); 32] = [
+    (0x00, "ROM"),
+    (0x01, "MBC1"),
+    (0x02, "MBC1+RAM"),
+    (0x03, "MBC1+RAM+BATT"),
+    (0x05, "MBC2"),
+    (0x06, "MBC2+BATT"),
+    (0x08, "ROM+RAM"),
+    (0x09, "ROM+RAM+BATT"),
+    (0x0b, "MMMO1"),
+    (0x0c, "MMMO1+RAM"),
+    (0x0d, "MMMO1+RAM+BATT"),
+    (0x0f, "MBC3+BATT+RTC"),
+    (0x10, "MBC3+RAM+BATT+RTC"),
+    (0x11, "MBC3"),
+    (0x12, "MBC3+RAM"),
+    (0x13, "MBC3+RAM+BATT"),
+    (0x15, "MBC4"),
+    (0x16, "MBC4+RAM"),
+    (0x17, "MBC4+RAM+BATT"),
+    (0x19, "MBC5"),
+    (0x1a, "MBC5+RAM"),
+    (0x1b, "MBC5+RAM+BATT"),
+    (0x1c, "MBC5+RUMBLE"),
+    (0x1d, "MBC5+RAM+RUMBLE"),
+    (0x1e, "MBC5+RAM+BATT+RUMBLE"),
+    (0x22, "MBC7+RAM+BATT"),
+    (0x55, "GG"),
+    (0x56, "GS3"),
+    (0xfc, "CAMERA"),
+    (0xfd, "TAMA5"),
+    (0xfe, "HuC3"),
+    (0xff, "HuC1"),
+];
+
+/// ROM sizes in bits by the code at 0x148
+const ROM_SIZES: [(u8, &str); 11] = [
+    (0x00, "256Kb"),
+    (0x01, "512Kb"),
+    (0x02, "1Mb"),
+    (0x03, "2Mb"),
+    (0x04, "4Mb"),
+    (0x05, "8Mb"),
+    (0x06, "16Mb"),
+    (0x07, "32Mb"),
+    (0x52, "9Mb"),
+    (0x53, "10Mb"),
+    (0x54, "12Mb"),
+];
+
+/// RAM sizes in bits by the code at 0x149
+const RAM_SIZES: [(u8, &str); 5] = [
+    (0x00, "NO"),
+    (0x01, "16Kb"),
+    (0x02, "64Kb"),
+    (0x03, "256Kb"),
+    (0x04, "1Mb"),
+];
+
+/// Extractor for Game Boy and Game Boy Color ROMs, also inside zip archives
+pub struct GameBoy;
+
+impl Extractor for GameBoy {
+    fn name(&self) -> &'static str {
+        "gameboy"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["application/x-gameboy-rom", "application/x-gameboy-color-rom", "application/zip"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["gb", "gbc", "cgb", "sgb", "dmg", "zip"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read(r)
+    }
+}
+
+/// Read the cartridge header of a ROM, or of the first ROM in a zip archive
+pub fn read<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Extracted> {
+    let mut header = [0; HEADER_LEN];
+    let n = read_up_to(r, &mut header)?;
+    if header.starts_with(b"PK\x03\x04") {
+        r.seek(std::io::SeekFrom::Start(0))?;
+        return read_zip(r);
+    }
+    if n < HEADER_LEN {
+        return Err(malformed("Game Boy ROM", "file shorter than the cartridge header"));
+    }
+
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, mime_type(&header));
+    parse(&header, &mut x);
+    Ok(x)
+}
+
+fn read_zip<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Extracted> {
+    let mut zip = ZipArchive::new(r).map_err(|e| malformed("Game Boy ROM", e.to_string()))?;
+    let name = zip.file_names()
+        .find(|n| n.rsplit_once('.').is_some_and(|(_, e)| EXTENSIONS.iter().any(|x| x.eq_ignore_ascii_case(e))))
+        .map(str::to_string)
+        .ok_or_else(|| malformed("Game Boy ROM", "no ROM in zip archive"))?;
+    let f = zip.by_name(&name).map_err(|e| malformed("Game Boy ROM", e.to_string()))?;
+
+    let mut header = [0; HEADER_LEN];
+    if read_up_to(&mut f.take(HEADER_LEN as u64), &mut header)? < HEADER_LEN {
+        return Err(malformed("Game Boy ROM", "file shorter than the cartridge header"));
+    }
+
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, "application/zip");
+    x.set_format(FormatKey::Container, "ZIP");
+    parse(&header, &mut x);
+    Ok(x)
+}
+
+/// Whether the header is the one of a Game Boy Color game
+pub fn mime_type(header: &[u8]) -> &'static str {
+    if header.get(0x143).is_some_and(|c| c & 0x80 != 0) {
+        "application/x-gameboy-color-rom"
+    } else {
+        "application/x-gameboy-rom"
+    }
+}
+
+fn parse(h: &[u8; HEADER_LEN], x: &mut Extracted) {
+    // The title is padded with NUL, anything not printable is replaced
+    let title: String = h[0x134..0x143].iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if (32..=126).contains(&c) { c as char } else { ' ' })
+        .collect();
+    x.push_str("title", &title);
+
+    let lookup = |table: &[(u8, &'static str)], code: u8| table.iter().find(|(c, _)| *c == code).map(|(_, v)| *v);
+    if let Some(t) = lookup(&CARTRIDGES, h[0x147]) {
+        x.push_str("cartridgetype", t);
+    }
+    if let Some(s) = lookup(&ROM_SIZES, h[0x148]) {
+        x.push_str("romsize", s);
+    }
+    if let Some(s) = lookup(&RAM_SIZES, h[0x149]) {
+        x.push_str("ramsize", s);
+    }
+    x.push_str("cgb", if h[0x143] & 0x80 != 0 { "YES" } else { "NO" });
+
+    // Newer games use the two characters at 0x144 instead of the old code
+    let licensee = if h[0x14b] == 0x33 {
+        String::from_utf8_lossy(&h[0x144..0x146]).into_owned()
+    } else {
+        format!("{:02X}", h[0x14b])
+    };
+    x.push_str("licensee", &licensee);
+
+    // The complement at 0x14d makes the header sum up to -25
+    let sum = h[0x134..0x14e].iter().fold(25u8, |s, &c| s.wrapping_add(c));
+    x.push_str("checksumvalid", if sum == 0 { "YES" } else { "NO" });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::{ZipWriter, FileOptions};
+    use crate::db::meta::{Metakey, Metavalue};
+
+    fn rom() -> Vec<u8> {
+        let mut r = vec![0; 0x8000];
+        r[0x104..0x134].copy_from_slice(&LOGO);
+        r[0x134..0x13f].copy_from_slice(b"POKEMON RED");
+        r[0x144..0x146].copy_from_slice(b"01");
+        r[0x146] = 0x03;
+        r[0x147] = 0x13;
+        r[0x148] = 0x05;
+        r[0x149] = 0x03;
+        r[0x14b] = 0x33;
+        r[0x14d] = r[0x134..0x14d].iter().fold(0u8, |s, &c| s.wrapping_sub(c).wrapping_sub(1));
+        r
+    }
+
+    #[test]
+    fn header() {
+        let s = |v: &str| Metavalue::Str(vec![v.into()].into_boxed_slice());
+        let mut f = Cursor::new(rom());
+        assert_eq!(crate::extract::mime_type(&mut f).unwrap(), Some("application/x-gameboy-rom"));
+        let x = read(&mut f).unwrap();
+        assert_eq!(x.metadata[&Metakey::new("title")], s("POKEMON RED"));
+        assert_eq!(x.metadata[&Metakey::new("cartridgetype")], s("MBC3+RAM+BATT"));
+        assert_eq!(x.metadata[&Metakey::new("romsize")], s("8Mb"));
+        assert_eq!(x.metadata[&Metakey::new("ramsize")], s("256Kb"));
+        assert_eq!(x.metadata[&Metakey::new("cgb")], s("NO"));
+        assert_eq!(x.metadata[&Metakey::new("licensee")], s("01"));
+        assert_eq!(x.metadata[&Metakey::new("checksumvalid")], s("YES"));
+
+        let mut r = rom();
+        r[0x143] = 0x80;
+        let x = read(&mut Cursor::new(r)).unwrap();
+        assert_eq!(&*x.format[&FormatKey::MimeType], "application/x-gameboy-color-rom");
+        assert_eq!(x.metadata[&Metakey::new("cgb")], s("YES"));
+        assert_eq!(x.metadata[&Metakey::new("checksumvalid")], s("NO"));
+    }
+
+    #[test]
+    fn zipped() {
+        let mut w = ZipWriter::new(Cursor::new(Vec::new()));
+        w.start_file("readme.txt", FileOptions::default()).unwrap();
+        w.write_all(b"Gotta catch 'em all").unwrap();
+        w.start_file("Pokemon Red.GB", FileOptions::default()).unwrap();
+        w.write_all(&rom()).unwrap();
+        let mut f = w.finish().unwrap();
+
+        let x = crate::extract::Registry::builtin().read(&mut f, Some("zip")).unwrap().unwrap();
+        assert_eq!(&*x.format[&FormatKey::MimeType], "application/zip");
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["POKEMON RED".into()].into_boxed_slice()));
+    }
+}
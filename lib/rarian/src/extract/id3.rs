@@ -0,0 +1,318 @@
+use std::io::Read;
+
+use crate::db::entry::FormatKey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed, read_up_to};
+
+/// Text frames and the attributes they are stored as, by their ID3v2.2 and ID3v2.3/4 names
+const FRAMES: [(&str, &str); 21] = [
+    ("TT2", "title"), ("TIT2", "title"),
+    ("TP1", "artist"), ("TPE1", "artist"),
+    ("TP2", "albumartist"), ("TPE2", "albumartist"),
+    ("TAL", "album"), ("TALB", "album"),
+    ("TRK", "tracknumber"), ("TRCK", "tracknumber"),
+    ("TPA", "discnumber"), ("TPOS", "discnumber"),
+    ("TCO", "genre"), ("TCON", "genre"),
+    ("TCM", "composer"), ("TCOM", "composer"),
+    ("TPB", "publisher"), ("TPUB", "publisher"),
+    // Versions before 2.4 only have the year in a frame of its own
+    ("TYE", "date"), ("TYER", "date"), ("TDRC", "date"),
+];
+
+/// Extractor for MP3 files
+pub struct Id3;
+
+impl Extractor for Id3 {
+    fn name(&self) -> &'static str {
+        "id3"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["audio/mpeg", "audio/mp3"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["mp3"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read(r)
+    }
+}
+
+/// Read the ID3v2 tag at the start of an MP3 file
+///
+/// Versions 2.2, 2.3 and 2.4 are supported. Compressed and encrypted frames are skipped. Files
+/// starting right with an MPEG audio frame have no tag, that's not an error.
+pub fn read<R: Read + ?Sized>(r: &mut R) -> Result<Extracted> {
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, "audio/mpeg");
+    x.set_format(FormatKey::Container, "MP3");
+
+    let mut h = [0; 10];
+    let n = read_up_to(r, &mut h)?;
+    if is_frame_header(&h[..n]) {
+        return Ok(x);
+    }
+    if n < 10 || &h[..3] != b"ID3" {
+        return Err(malformed("ID3", "missing ID3 marker"));
+    }
+    let version = h[3];
+    let flags = h[5];
+    if !(2..=4).contains(&version) {
+        return Err(malformed("ID3", format!("unsupported version 2.{}", version)));
+    }
+
+    // Read as far as the file goes instead of trusting the size with an allocation
+    let size = syncsafe(&h[6..10]) as usize;
+    let mut tag = Vec::new();
+    r.take(size as u64).read_to_end(&mut tag)?;
+    if tag.len() < size {
+        return Err(malformed("ID3", "tag longer than the file"));
+    }
+    // Version 2.4 unsynchronises every frame on its own
+    if flags & 0x80 != 0 && version < 4 {
+        tag = resync(&tag);
+    }
+
+    let mut b = &tag[..];
+    if flags & 0x40 != 0 {
+        // ID3v2.2 used this flag for compression, which was never specified
+        if version == 2 {
+            return Ok(x);
+        }
+        let size = b.get(..4).ok_or_else(|| malformed("ID3", "truncated extended header"))?;
+        let len = if version == 3 { be(size).checked_add(4) } else { Some(syncsafe(size)) };
+        b = match len.and_then(|l| b.get(l as usize..)) {
+            Some(b) => b,
+            None => return Err(malformed("ID3", "extended header longer than the tag")),
+        };
+    }
+
+    let (idlen, hlen) = if version == 2 { (3, 6) } else { (4, 10) };
+    while b.len() >= hlen && b[0] != 0 {
+        let id = String::from_utf8_lossy(&b[..idlen]).into_owned();
+        let len = match version {
+            2 => be(&b[3..6]),
+            3 => be(&b[4..8]),
+            _ => syncsafe(&b[4..8]),
+        } as usize;
+        if b.len() < hlen + len {
+            break;
+        }
+        let mut data = &b[hlen..hlen + len];
+        let frame_flags = if version == 2 { 0 } else { b[9] };
+        b = &b[hlen + len..];
+
+        let owned;
+        match version {
+            3 => {
+                // Compressed, encrypted
+                if frame_flags & 0xc0 != 0 {
+                    continue;
+                }
+                if frame_flags & 0x20 != 0 {
+                    data = data.get(1..).unwrap_or(&[]);
+                }
+            }
+            4 => {
+                if frame_flags & 0x0c != 0 {
+                    continue;
+                }
+                if frame_flags & 0x40 != 0 {
+                    data = data.get(1..).unwrap_or(&[]);
+                }
+                if frame_flags & 0x01 != 0 {
+                    data = data.get(4..).unwrap_or(&[]);
+                }
+                if frame_flags & 0x02 != 0 {
+                    owned = resync(data);
+                    data = &owned;
+                }
+            }
+            _ => {}
+        }
+
+        frame(&id, data, &mut x);
+    }
+
+    Ok(x)
+}
+
+/// Check if `h` starts with the header of an MPEG audio frame
+///
+/// Besides the sync bits, the version, layer, bitrate and sampling rate must not be reserved
+/// values. Layer I with CRC isn't accepted either, its header starts like the byte order mark of
+/// UTF-16 text and such files hardly exist.
+pub fn is_frame_header(h: &[u8]) -> bool {
+    if h.len() < 4 || h[0] != 0xff || h[1] & 0xe0 != 0xe0 {
+        return false;
+    }
+    let version = (h[1] >> 3) & 0x03;
+    let layer = (h[1] >> 1) & 0x03;
+    let bitrate = h[2] >> 4;
+    let rate = (h[2] >> 2) & 0x03;
+    version != 0x01 && layer != 0x00 && bitrate != 0x0f && rate != 0x03 && h[1] != 0xfe
+}
+
+fn frame(id: &str, data: &[u8], x: &mut Extracted) {
+    if data.is_empty() {
+        return;
+    }
+    if let Some(&(_, key)) = FRAMES.iter().find(|(f, _)| *f == id) {
+        for v in decode(data[0], &data[1..]).split('\0') {
+            let v = if key == "genre" { genre(v) } else { v };
+            x.push_tag(key, v);
+        }
+        return;
+    }
+
+    match id {
+        // Encoding, language, description and the comment itself
+        "COM" | "COMM" if data.len() > 4 => {
+            let s = decode(data[0], &data[4..]);
+            let (desc, text) = s.split_once('\0').unwrap_or(("", &s));
+            // iTunes stores its own data in comments with a description
+            if desc.is_empty() {
+                x.push_str("comment", text);
+            }
+        }
+        // User defined text, the description is used as key
+        "TXX" | "TXXX" => {
+            let s = decode(data[0], &data[1..]);
+            if let Some((desc, text)) = s.split_once('\0') {
+                let key = desc.to_lowercase();
+                let key = match key.as_str() {
+                    "album artist" => "albumartist",
+                    k => k,
+                };
+                if !key.is_empty() {
+                    for v in text.split('\0') {
+                        x.push_tag(key, v);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Genres used to be referenced by their ID3v1 number as in `(17)`, those can't be stored
+fn genre(v: &str) -> &str {
+    let v = v.trim();
+    if v.starts_with('(') {
+        match v.find(')') {
+            Some(i) => &v[i + 1..],
+            None => v,
+        }
+    } else if v.bytes().all(|b| b.is_ascii_digit()) {
+        ""
+    } else {
+        v
+    }
+}
+
+/// Decode text in one of the encodings ID3 allows
+fn decode(encoding: u8, b: &[u8]) -> String {
+    match encoding {
+        // ISO-8859-1
+        0 => b.iter().map(|&c| c as char).collect(),
+        // UTF-16 with byte order mark, and UTF-16BE
+        1 | 2 => {
+            let mut le = false;
+            let mut units = Vec::with_capacity(b.len() / 2);
+            for c in b.chunks_exact(2) {
+                let u = if le { u16::from_le_bytes([c[0], c[1]]) } else { u16::from_be_bytes([c[0], c[1]]) };
+                match u {
+                    0xfeff => {},
+                    // A byte order mark read the wrong way around
+                    0xfffe => le = !le,
+                    u => units.push(u),
+                }
+            }
+            std::char::decode_utf16(units)
+                .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+                .collect()
+        }
+        _ => String::from_utf8_lossy(b).into_owned(),
+    }
+}
+
+/// Undo unsynchronisation, which inserts a zero byte after every 0xFF
+fn resync(b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(b.len());
+    for (i, &c) in b.iter().enumerate() {
+        if c == 0 && i > 0 && b[i - 1] == 0xff {
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn be(b: &[u8]) -> u32 {
+    b.iter().fold(0, |n, &c| n << 8 | c as u32)
+}
+
+/// Integers with the top bit of every byte unset, so they can't be mistaken for a frame sync
+fn syncsafe(b: &[u8]) -> u32 {
+    b.iter().fold(0, |n, &c| n << 7 | (c & 0x7f) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::db::meta::{Metakey, Metavalue};
+
+    fn encode_frame(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut f = id.as_bytes().to_vec();
+        f.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        f.extend_from_slice(&[0, 0]);
+        f.extend_from_slice(data);
+        f
+    }
+
+    #[test]
+    fn v23() {
+        let mut frames = Vec::new();
+        frames.extend(encode_frame("TIT2", b"\x00Bl\xe5"));
+        // UTF-16 little endian with byte order mark
+        frames.extend(encode_frame("TPE1", b"\x01\xff\xfeO\x00p\x00e\x00t\x00h\x00"));
+        frames.extend(encode_frame("TRCK", b"\x037/10"));
+        frames.extend(encode_frame("TCON", b"\x00(9)Metal"));
+        frames.extend(encode_frame("COMM", b"\x00engiTunNORM\x00 0000\x00"));
+        frames.extend(encode_frame("COMM", b"\x00eng\x00Recorded live"));
+        frames.extend(encode_frame("TXXX", b"\x00ALBUM ARTIST\x00Opeth"));
+        frames.extend_from_slice(&[0; 16]);
+
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let n = frames.len() as u32;
+        tag.extend_from_slice(&[(n >> 21) as u8 & 0x7f, (n >> 14) as u8 & 0x7f, (n >> 7) as u8 & 0x7f, n as u8 & 0x7f]);
+        tag.extend(frames);
+
+        let x = read(&mut Cursor::new(tag)).unwrap();
+        let s = |v: &str| Metavalue::Str(vec![v.into()].into_boxed_slice());
+        assert_eq!(x.metadata[&Metakey::new("title")], s("Blå"));
+        assert_eq!(x.metadata[&Metakey::new("artist")], s("Opeth"));
+        assert_eq!(x.metadata[&Metakey::new("albumartist")], s("Opeth"));
+        assert_eq!(x.metadata[&Metakey::new("genre")], s("Metal"));
+        assert_eq!(x.metadata[&Metakey::new("comment")], s("Recorded live"));
+        assert_eq!(x.metadata[&Metakey::new("tracknumber")], Metavalue::Int(vec![7].into_boxed_slice()));
+    }
+
+    #[test]
+    fn broken_header() {
+        // Extended header size overflowing when the size field is added
+        let tag = b"ID3\x03\x00\x40\x00\x00\x00\x04\xff\xff\xff\xfd".to_vec();
+        assert!(read(&mut Cursor::new(tag)).is_err());
+        // Tag of 256 MiB in a file of only the header
+        assert!(read(&mut Cursor::new(b"ID3\x04\x00\x00\x7f\x7f\x7f\x7f".to_vec())).is_err());
+    }
+
+    #[test]
+    fn unsynchronised() {
+        assert_eq!(resync(&[0xff, 0x00, 0xe0, 0x00, 0xff]), vec![0xff, 0xe0, 0x00, 0xff]);
+        assert_eq!(syncsafe(&[0x00, 0x00, 0x02, 0x01]), 257);
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::db::entry::FormatKey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed};
+
+/// Largest `moov` atom that is read into memory
+const MAX_MOOV: u64 = 64 * 1024 * 1024;
+
+/// iTunes metadata items and the attributes they are stored as
+const ITEMS: [(&[u8], &str); 11] = [
+    (b"\xa9nam", "title"),
+    (b"\xa9ART", "artist"),
+    (b"aART", "albumartist"),
+    (b"\xa9alb", "album"),
+    (b"\xa9day", "date"),
+    (b"\xa9cmt", "comment"),
+    (b"desc", "description"),
+    (b"\xa9gen", "genre"),
+    (b"\xa9wrt", "composer"),
+    (b"trkn", "tracknumber"),
+    (b"disk", "discnumber"),
+];
+
+/// Extractor for MP4 and QuickTime files
+pub struct Mp4;
+
+impl Extractor for Mp4 {
+    fn name(&self) -> &'static str {
+        "mp4"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["video/mp4", "audio/mp4", "audio/x-m4a", "video/quicktime"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["mp4", "m4a", "m4b", "m4v", "mov"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read(r)
+    }
+}
+
+/// Read the iTunes style metadata and track information of an MP4 file
+///
+/// Only the `moov` atom is read, the media data is skipped.
+pub fn read<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Extracted> {
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::Container, "MP4");
+
+    let end = r.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    let mut moov = None;
+    while end.saturating_sub(pos) >= 8 {
+        r.seek(SeekFrom::Start(pos))?;
+        let mut h = [0; 8];
+        r.read_exact(&mut h)?;
+        let (size, hlen) = match u32::from_be_bytes([h[0], h[1], h[2], h[3]]) {
+            0 => (end - pos, 8),
+            1 => {
+                let mut l = [0; 8];
+                r.read_exact(&mut l)?;
+                (u64::from_be_bytes(l), 16)
+            }
+            n => (n as u64, 8),
+        };
+        if size < hlen {
+            return Err(malformed("MP4", "atom smaller than its header"));
+        }
+        if &h[4..8] == b"moov" {
+            if size > MAX_MOOV {
+                return Err(malformed("MP4", "moov atom too large"));
+            }
+            let mut b = vec![0; (size - hlen) as usize];
+            r.read_exact(&mut b)?;
+            moov = Some(b);
+            break;
+        }
+        pos = pos.saturating_add(size);
+    }
+    let moov = moov.ok_or_else(|| malformed("MP4", "no moov atom"))?;
+
+    let mut video = false;
+    for (kind, body) in atoms(&moov) {
+        match kind {
+            b"mvhd" => duration(body, &mut x),
+            b"trak" => video |= track(body, &mut x),
+            b"udta" => {
+                for (kind, body) in atoms(body) {
+                    if kind == b"meta" {
+                        meta(body, &mut x);
+                    }
+                }
+            }
+            b"meta" => meta(body, &mut x),
+            _ => {}
+        }
+    }
+    x.set_format(FormatKey::MimeType, if video { "video/mp4" } else { "audio/mp4" });
+
+    Ok(x)
+}
+
+/// Child atoms of an atom, ending at the first one that doesn't fit
+fn atoms(mut b: &[u8]) -> impl Iterator<Item=(&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        if b.len() < 8 {
+            return None;
+        }
+        let size = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+        let size = if size == 0 { b.len() } else { size };
+        if size < 8 || size > b.len() {
+            return None;
+        }
+        let atom = (&b[4..8], &b[8..size]);
+        b = &b[size..];
+        Some(atom)
+    })
+}
+
+fn child<'a>(b: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    atoms(b).find(|(k, _)| *k == kind).map(|(_, b)| b)
+}
+
+fn be(b: &[u8]) -> u64 {
+    b.iter().fold(0, |n, &c| n << 8 | c as u64)
+}
+
+/// Playing time from the movie header
+fn duration(b: &[u8], x: &mut Extracted) {
+    let (scale, duration) = match b.first() {
+        Some(0) if b.len() >= 20 => (be(&b[12..16]), be(&b[16..20])),
+        Some(1) if b.len() >= 32 => (be(&b[20..24]), be(&b[24..32])),
+        _ => return,
+    };
+    if let Some(secs) = duration.saturating_add(scale / 2).checked_div(scale) {
+        x.set_format(FormatKey::Duration, secs);
+    }
+}
+
+/// Sample rate and channels of an audio track. Returns whether this is a video track.
+fn track(b: &[u8], x: &mut Extracted) -> bool {
+    let mdia = match child(b, b"mdia") {
+        Some(b) => b,
+        None => return false,
+    };
+    let handler = child(mdia, b"hdlr").and_then(|h| h.get(8..12));
+    if handler == Some(b"vide") {
+        return true;
+    }
+    if handler != Some(b"soun") || x.format.contains_key(&FormatKey::SampleRate) {
+        return false;
+    }
+
+    // The first sample description in mdia/minf/stbl/stsd, after version, flags and count
+    let entry = child(mdia, b"minf")
+        .and_then(|b| child(b, b"stbl"))
+        .and_then(|b| child(b, b"stsd"))
+        .and_then(|b| b.get(8..))
+        .and_then(|b| atoms(b).next());
+    if let Some((_, e)) = entry {
+        if e.len() >= 28 {
+            x.set_format(FormatKey::Channels, be(&e[16..18]));
+            // 16.16 fixed point
+            x.set_format(FormatKey::SampleRate, be(&e[24..26]));
+        }
+    }
+    false
+}
+
+/// Items of the `ilst` in a `meta` atom
+fn meta(b: &[u8], x: &mut Extracted) {
+    // meta is a full atom with version and flags before its children
+    let ilst = match b.get(4..).and_then(|b| child(b, b"ilst")) {
+        Some(b) => b,
+        None => return,
+    };
+
+    for (kind, item) in atoms(ilst) {
+        let key = if kind == b"----" {
+            // Freeform items name themselves
+            match child(item, b"name").and_then(|n| n.get(4..)) {
+                Some(n) => String::from_utf8_lossy(n).to_lowercase(),
+                None => continue,
+            }
+        } else {
+            match ITEMS.iter().find(|(k, _)| *k == kind) {
+                Some((_, key)) => key.to_string(),
+                None => continue,
+            }
+        };
+
+        for (k, data) in atoms(item) {
+            // Type indicator and locale come before the value
+            if k != b"data" || data.len() < 8 {
+                continue;
+            }
+            let value = &data[8..];
+            match be(&data[1..4]) {
+                1 => x.push_tag(&key, &String::from_utf8_lossy(value)),
+                2 => {
+                    let units: Vec<u16> = value.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+                    x.push_tag(&key, &String::from_utf16_lossy(&units));
+                }
+                // Signed big endian integers
+                21 if !value.is_empty() && value.len() <= 8 => {
+                    let n = value.iter().skip(1).fold(value[0] as i8 as i64, |n, &c| n << 8 | c as i64);
+                    x.push_int(&key, n);
+                }
+                // Track and disc numbers are a number and total after two bytes of padding
+                0 if (key == "tracknumber" || key == "discnumber") && value.len() >= 4 => {
+                    x.push_int(&key, be(&value[2..4]) as i64);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::db::meta::{Metakey, Metavalue};
+
+    fn atom(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut a = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        a.extend_from_slice(kind);
+        a.extend_from_slice(body);
+        a
+    }
+
+    fn data(kind: u8, value: &[u8]) -> Vec<u8> {
+        let mut d = vec![0, 0, 0, kind, 0, 0, 0, 0];
+        d.extend_from_slice(value);
+        atom(b"data", &d)
+    }
+
+    #[test]
+    fn itunes() {
+        let mut ilst = atom(b"\xa9nam", &data(1, b"Ghost of Perdition"));
+        ilst.extend(atom(b"trkn", &data(0, &[0, 0, 0, 1, 0, 6, 0, 0])));
+        ilst.extend(atom(b"\xa9day", &data(1, b"2005-08-30T07:00:00Z")));
+        let mut meta = vec![0; 4];
+        meta.extend(atom(b"ilst", &ilst));
+        let udta = atom(b"udta", &atom(b"meta", &meta));
+
+        let mut mvhd = vec![0; 20];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&630500u32.to_be_bytes());
+        let mut moov = atom(b"mvhd", &mvhd);
+        moov.extend(udta);
+
+        let mut f = atom(b"ftyp", b"M4A \0\0\0\0");
+        f.extend(atom(b"mdat", &[0; 64]));
+        f.extend(atom(b"moov", &moov));
+
+        let x = read(&mut Cursor::new(f)).unwrap();
+        assert_eq!(&*x.format[&FormatKey::MimeType], "audio/mp4");
+        assert_eq!(&*x.format[&FormatKey::Duration], "631");
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["Ghost of Perdition".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("tracknumber")], Metavalue::Int(vec![1].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")].to_date().next().unwrap().year(), 2005);
+    }
+}
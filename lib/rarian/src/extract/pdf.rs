@@ -0,0 +1,879 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom};
+
+use flate2::read::ZlibDecoder;
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::db::date::Date;
+use crate::db::entry::FormatKey;
+use crate::db::meta::Metakey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed};
+
+/// Part of a file that is read from its start. Of larger files the last `TAIL` bytes are read as
+/// well, that's where the trailer is.
+const MAX_PDF: u64 = 64 * 1024 * 1024;
+const TAIL: u64 = 1024 * 1024;
+
+/// Largest decompressed stream
+const MAX_STREAM: u64 = 16 * 1024 * 1024;
+
+/// Deepest nesting of arrays and dictionaries that is parsed
+const MAX_DEPTH: usize = 32;
+
+/// Entries of the document information dictionary and the attributes they are stored as
+const INFO: [(&str, &str); 5] = [
+    ("Title", "title"),
+    ("Author", "author"),
+    ("Subject", "description"),
+    ("Keywords", "subject"),
+    ("CreationDate", "date"),
+];
+
+/// XMP properties and the attributes they are stored as, by their usual prefixed names
+const XMP: [(&str, &str); 8] = [
+    ("dc:title", "title"),
+    ("dc:creator", "author"),
+    ("dc:description", "description"),
+    ("dc:subject", "subject"),
+    ("dc:language", "language"),
+    ("dc:publisher", "publisher"),
+    ("dc:date", "date"),
+    ("xmp:CreateDate", "date"),
+];
+
+/// Extractor for PDF documents
+///
+/// Metadata is taken from the XMP packet of the document, the information dictionary fills in
+/// what's missing. Text is only found in content streams that aren't compressed or use
+/// `FlateDecode`, and only for fonts with a single byte encoding.
+#[derive(Default)]
+pub struct Pdf {
+    text: usize,
+}
+
+impl Extractor for Pdf {
+    fn name(&self) -> &'static str {
+        "pdf"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["application/pdf"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["pdf"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read(r, self.text)
+    }
+
+    fn set_text_limit(&mut self, limit: usize) {
+        self.text = limit;
+    }
+}
+
+/// Read the metadata of a PDF document, and up to `text` bytes of the text of its pages
+pub fn read<R: Read + Seek + ?Sized>(r: &mut R, text: usize) -> Result<Extracted> {
+    let doc = Document::load(r)?;
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, "application/pdf");
+    x.set_format(FormatKey::Container, "PDF");
+
+    // Strings of encrypted documents can't be read without the key
+    if doc.trailer("Encrypt").is_some() {
+        return Ok(x);
+    }
+
+    if let Some(m) = doc.metadata() {
+        xmp(&m, &mut x);
+    }
+    doc.info(&mut x);
+    if text > 0 {
+        doc.text(&mut x, text);
+    }
+    Ok(x)
+}
+
+type Dict = HashMap<String, Object>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Object {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Real(f64),
+    Str(Vec<u8>),
+    Name(String),
+    Array(Vec<Object>),
+    Dict(Dict),
+    Ref(u32, u16),
+    /// Operators of content streams and anything else that isn't an object
+    Keyword(String),
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, 0 | 9 | 10 | 12 | 13 | 32)
+}
+
+fn is_regular(c: u8) -> bool {
+    !is_space(c) && !matches!(c, b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%')
+}
+
+struct Lexer<'a> {
+    b: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(b: &'a [u8], pos: usize) -> Self {
+        Self { b, pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.b.get(self.pos).copied()
+    }
+
+    /// Skip whitespace and comments
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if is_space(c) {
+                self.pos += 1;
+            } else if c == b'%' {
+                while self.peek().is_some_and(|c| c != b'\n' && c != b'\r') {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn token(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.peek().is_some_and(is_regular) {
+            self.pos += 1;
+        }
+        &self.b[start..self.pos]
+    }
+
+    /// The next object, `None` at the end of the input or where it's broken beyond repair
+    fn object(&mut self, depth: usize) -> Option<Object> {
+        self.skip_space();
+        let c = self.peek()?;
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match c {
+            b'(' => {
+                self.pos += 1;
+                Some(Object::Str(self.literal()))
+            }
+            b'<' if self.b.get(self.pos + 1) == Some(&b'<') => {
+                self.pos += 2;
+                let mut d = Dict::new();
+                loop {
+                    self.skip_space();
+                    match self.peek()? {
+                        b'>' if self.b.get(self.pos + 1) == Some(&b'>') => {
+                            self.pos += 2;
+                            return Some(Object::Dict(d));
+                        }
+                        b'/' => {
+                            let key = match self.object(depth + 1)? {
+                                Object::Name(n) => n,
+                                _ => return None,
+                            };
+                            let value = self.object(depth + 1)?;
+                            d.insert(key, value);
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'<' => {
+                self.pos += 1;
+                Some(Object::Str(self.hex()))
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut a = Vec::new();
+                loop {
+                    self.skip_space();
+                    if self.peek()? == b']' {
+                        self.pos += 1;
+                        return Some(Object::Array(a));
+                    }
+                    a.push(self.object(depth + 1)?);
+                }
+            }
+            b'/' => {
+                self.pos += 1;
+                Some(Object::Name(name(self.token())))
+            }
+            c if !is_regular(c) => {
+                // Stray delimiters, which would otherwise stop anything reading on
+                self.pos += 1;
+                Some(Object::Keyword((c as char).to_string()))
+            }
+            _ => {
+                let t = self.token();
+                Some(self.number(t).unwrap_or_else(|| match t {
+                    b"true" => Object::Bool(true),
+                    b"false" => Object::Bool(false),
+                    b"null" => Object::Null,
+                    t => Object::Keyword(String::from_utf8_lossy(t).into_owned()),
+                }))
+            }
+        }
+    }
+
+    /// A number, or a reference if it's followed by a generation and `R`
+    fn number(&mut self, t: &[u8]) -> Option<Object> {
+        let s = std::str::from_utf8(t).ok()?;
+        if s.contains('.') {
+            return s.parse().ok().map(Object::Real);
+        }
+        let n: i64 = s.parse().ok()?;
+
+        let start = self.pos;
+        self.skip_space();
+        let generation = self.token();
+        self.skip_space();
+        if self.token() == b"R" {
+            let generation = std::str::from_utf8(generation).ok().and_then(|g| g.parse().ok());
+            if let (Ok(num), Some(generation)) = (u32::try_from(n), generation) {
+                return Some(Object::Ref(num, generation));
+            }
+        }
+        self.pos = start;
+        Some(Object::Int(n))
+    }
+
+    /// A literal string, after its opening parenthesis
+    fn literal(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut depth = 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                b'(' => {
+                    depth += 1;
+                    out.push(c);
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    out.push(c);
+                }
+                b'\\' => {
+                    let e = match self.peek() {
+                        Some(e) => e,
+                        None => break,
+                    };
+                    self.pos += 1;
+                    match e {
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'b' => out.push(8),
+                        b'f' => out.push(12),
+                        b'0'..=b'7' => {
+                            let mut n = (e - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'7') => {
+                                        n = n * 8 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            out.push(n as u8);
+                        }
+                        // A backslash at the end of a line continues the string on the next
+                        b'\r' => if self.peek() == Some(b'\n') {
+                            self.pos += 1;
+                        },
+                        b'\n' => {}
+                        e => out.push(e),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// A hexadecimal string, after its opening bracket
+    fn hex(&mut self) -> Vec<u8> {
+        let mut digits = Vec::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            if c == b'>' {
+                break;
+            }
+            if let Some(d) = (c as char).to_digit(16) {
+                digits.push(d as u8);
+            }
+        }
+        // A missing last digit is taken to be 0
+        digits.chunks(2).map(|d| d[0] << 4 | d.get(1).copied().unwrap_or(0)).collect()
+    }
+}
+
+/// A name without its slash, with `#xx` escapes decoded
+fn name(t: &[u8]) -> String {
+    let mut out = Vec::with_capacity(t.len());
+    let mut i = 0;
+    while i < t.len() {
+        let escaped = std::str::from_utf8(t.get(i + 1..i + 3).unwrap_or(&[])).ok()
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match escaped {
+            Some(c) if t[i] == b'#' => {
+                out.push(c);
+                i += 3;
+            }
+            _ => {
+                out.push(t[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn find(b: &[u8], pat: &[u8]) -> Option<usize> {
+    b.windows(pat.len()).position(|w| w == pat)
+}
+
+fn rfind(b: &[u8], pat: &[u8]) -> Option<usize> {
+    b.windows(pat.len()).rposition(|w| w == pat)
+}
+
+/// A PDF file, with its objects found by scanning for them
+///
+/// The cross-reference table isn't used, it's often wrong and the scan finds objects in files
+/// that were cut short as well.
+struct Document {
+    b: Vec<u8>,
+    /// Where the objects start, right after `obj`. Objects defined again by incremental updates
+    /// are found at their last definition.
+    offsets: HashMap<(u32, u16), usize>,
+    /// Objects stored in object streams
+    compressed: HashMap<u32, Object>,
+}
+
+impl Document {
+    fn load<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Self> {
+        let len = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(0))?;
+        let mut b = Vec::new();
+        (&mut *r).take(MAX_PDF).read_to_end(&mut b)?;
+        if len > MAX_PDF {
+            r.seek(SeekFrom::Start(len.saturating_sub(TAIL).max(MAX_PDF)))?;
+            r.read_to_end(&mut b)?;
+        }
+        // Some files have junk before the header
+        if find(&b[..b.len().min(1024)], b"%PDF-").is_none() {
+            return Err(malformed("PDF", "missing %PDF header"));
+        }
+
+        let mut offsets = HashMap::new();
+        let mut i = 0;
+        while let Some(p) = find(&b[i..], b"obj") {
+            let p = i + p;
+            i = p + 3;
+            if p == 0 || !is_space(b[p - 1]) || b.get(p + 3).is_some_and(|&c| is_regular(c)) {
+                continue;
+            }
+            let mut j = p;
+            let generation = number_before(&b, &mut j);
+            let num = number_before(&b, &mut j);
+            if let (Some(num), Some(generation)) = (num, generation) {
+                if let (Ok(num), Ok(generation)) = (u32::try_from(num), u16::try_from(generation)) {
+                    offsets.insert((num, generation), p + 3);
+                }
+            }
+        }
+
+        let mut doc = Self { b, offsets, compressed: HashMap::new() };
+        doc.compressed = doc.object_streams();
+        Ok(doc)
+    }
+
+    /// Parse the objects of all object streams
+    fn object_streams(&self) -> HashMap<u32, Object> {
+        let mut starts: Vec<usize> = self.offsets.values().copied().collect();
+        starts.sort_unstable();
+
+        let mut objects = HashMap::new();
+        let mut i = 0;
+        while let Some(p) = find(&self.b[i..], b"/ObjStm") {
+            let p = i + p;
+            i = p + 7;
+            // The object the type is part of
+            let start = match starts.iter().rev().find(|&&s| s <= p) {
+                Some(&s) => s,
+                None => continue,
+            };
+            let (dict, data) = match self.stream(start) {
+                Some(s) => s,
+                None => continue,
+            };
+            let (n, first) = match (dict.get("N"), dict.get("First")) {
+                (Some(Object::Int(n)), Some(Object::Int(f))) => (*n, *f as usize),
+                _ => continue,
+            };
+
+            let mut header = Lexer::new(&data, 0);
+            for _ in 0..n {
+                let (num, offset) = match (header.object(0), header.object(0)) {
+                    (Some(Object::Int(num)), Some(Object::Int(offset))) => (num, offset as usize),
+                    _ => break,
+                };
+                let o = Lexer::new(&data, first.saturating_add(offset)).object(0);
+                if let (Ok(num), Some(o)) = (u32::try_from(num), o) {
+                    objects.insert(num, o);
+                }
+            }
+        }
+        objects
+    }
+
+    fn object(&self, num: u32, generation: u16) -> Option<Object> {
+        match self.offsets.get(&(num, generation)) {
+            Some(&off) => Lexer::new(&self.b, off).object(0),
+            // Objects in object streams always have generation 0
+            None => self.compressed.get(&num).cloned(),
+        }
+    }
+
+    /// The object `o` refers to, or `o` itself
+    fn resolve(&self, o: Option<&Object>) -> Option<Object> {
+        match o? {
+            Object::Ref(num, generation) => self.object(*num, *generation),
+            o => Some(o.clone()),
+        }
+    }
+
+    /// Dictionary and decoded data of the stream object starting at `off`
+    fn stream(&self, off: usize) -> Option<(Dict, Vec<u8>)> {
+        let mut l = Lexer::new(&self.b, off);
+        let dict = match l.object(0)? {
+            Object::Dict(d) => d,
+            _ => return None,
+        };
+        l.skip_space();
+        if !self.b.get(l.pos..).is_some_and(|s| s.starts_with(b"stream")) {
+            return None;
+        }
+        let mut start = l.pos + 6;
+        if self.b.get(start) == Some(&b'\r') {
+            start += 1;
+        }
+        if self.b.get(start) == Some(&b'\n') {
+            start += 1;
+        }
+
+        let len = match self.resolve(dict.get("Length")) {
+            Some(Object::Int(n)) if n >= 0 && start.saturating_add(n as usize) <= self.b.len() => n as usize,
+            _ => find(&self.b[start..], b"endstream")?,
+        };
+        let data = decode(&dict, &self.b[start..start + len])?;
+        Some((dict, data))
+    }
+
+    fn stream_ref(&self, o: &Object) -> Option<Vec<u8>> {
+        match o {
+            Object::Ref(num, generation) => {
+                let off = *self.offsets.get(&(*num, *generation))?;
+                self.stream(off).map(|(_, data)| data)
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of `key` in the last trailer or cross-reference stream that has it
+    fn trailer(&self, key: &str) -> Option<Object> {
+        let pat = format!("/{}", key);
+        let mut end = self.b.len();
+        while let Some(p) = rfind(&self.b[..end], pat.as_bytes()) {
+            end = p;
+            let after = p + pat.len();
+            if self.b.get(after).is_some_and(|&c| is_regular(c)) {
+                continue;
+            }
+            match Lexer::new(&self.b, after).object(0) {
+                Some(o @ Object::Ref(..)) | Some(o @ Object::Dict(_)) => return Some(o),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn catalog(&self) -> Option<Dict> {
+        match self.resolve(self.trailer("Root").as_ref()) {
+            Some(Object::Dict(d)) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// The XMP packet of the document
+    fn metadata(&self) -> Option<Vec<u8>> {
+        self.stream_ref(self.catalog()?.get("Metadata")?)
+    }
+
+    /// Add the entries of the information dictionary that XMP didn't have
+    fn info(&self, x: &mut Extracted) {
+        let info = match self.resolve(self.trailer("Info").as_ref()) {
+            Some(Object::Dict(d)) => d,
+            _ => return,
+        };
+        for (entry, key) in INFO.iter() {
+            if x.metadata.contains_key(&Metakey::new(*key)) {
+                continue;
+            }
+            let s = match self.resolve(info.get(*entry)) {
+                Some(Object::Str(s)) => text_string(&s),
+                _ => continue,
+            };
+            match *key {
+                "date" => if let Some(d) = date(&s) {
+                    x.push_date(key, d);
+                },
+                "subject" => for k in s.split([',', ';']) {
+                    x.push_str(key, k);
+                },
+                _ => x.push_str(key, &s),
+            }
+        }
+    }
+
+    /// Add the text of the pages in order, until there's `limit` bytes of it
+    fn text(&self, x: &mut Extracted, limit: usize) {
+        let pages = match self.catalog().and_then(|c| c.get("Pages").cloned()) {
+            Some(p) => p,
+            None => return,
+        };
+        let mut stack = vec![pages];
+        let mut seen = HashSet::new();
+        while let Some(node) = stack.pop() {
+            let (num, generation) = match node {
+                Object::Ref(num, generation) => (num, generation),
+                _ => continue,
+            };
+            if !seen.insert((num, generation)) {
+                continue;
+            }
+            let d = match self.object(num, generation) {
+                Some(Object::Dict(d)) => d,
+                _ => continue,
+            };
+            if let Some(Object::Array(kids)) = self.resolve(d.get("Kids")) {
+                stack.extend(kids.into_iter().rev());
+                continue;
+            }
+
+            // The contents are a stream or an array of them, which may be an object of its own
+            let contents = match d.get("Contents") {
+                Some(Object::Array(a)) => a.clone(),
+                Some(o @ Object::Ref(num, generation)) => match self.object(*num, *generation) {
+                    Some(Object::Array(a)) => a,
+                    _ => vec![o.clone()],
+                },
+                _ => continue,
+            };
+            let mut content = Vec::new();
+            for c in contents.iter() {
+                if let Some(data) = self.stream_ref(c) {
+                    content.extend(data);
+                    content.push(b'\n');
+                }
+            }
+            if !x.push_text(&content_text(&content), limit) {
+                return;
+            }
+        }
+    }
+}
+
+/// Read back an object number or generation, moving `j` to its start
+fn number_before(b: &[u8], j: &mut usize) -> Option<u64> {
+    while *j > 0 && is_space(b[*j - 1]) {
+        *j -= 1;
+    }
+    let end = *j;
+    while *j > 0 && b[*j - 1].is_ascii_digit() {
+        *j -= 1;
+    }
+    if *j == end || end - *j > 10 {
+        return None;
+    }
+    std::str::from_utf8(&b[*j..end]).ok()?.parse().ok()
+}
+
+/// Apply the filters of a stream. Only `FlateDecode` is supported.
+fn decode(dict: &Dict, raw: &[u8]) -> Option<Vec<u8>> {
+    let filters = match dict.get("Filter") {
+        None => Vec::new(),
+        Some(Object::Name(n)) => vec![n.clone()],
+        Some(Object::Array(a)) => a.iter()
+            .map(|f| match f {
+                Object::Name(n) => Some(n.clone()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+
+    let mut data = raw.to_vec();
+    for f in filters {
+        match f.as_str() {
+            "FlateDecode" | "Fl" => {
+                // Keep what could be decompressed of damaged streams
+                let mut out = Vec::new();
+                let res = ZlibDecoder::new(&data[..]).take(MAX_STREAM).read_to_end(&mut out);
+                if res.is_err() && out.is_empty() {
+                    return None;
+                }
+                data = out;
+            }
+            _ => return None,
+        }
+    }
+    Some(data)
+}
+
+/// Decode a text string, which is UTF-16 or UTF-8 with byte order mark, or PDFDocEncoding
+///
+/// PDFDocEncoding is taken to be Latin-1, they only differ in rarely used characters.
+fn text_string(s: &[u8]) -> String {
+    if let Some(s) = s.strip_prefix(b"\xfe\xff") {
+        let units: Vec<u16> = s.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&units)
+    } else if let Some(s) = s.strip_prefix(b"\xef\xbb\xbf") {
+        String::from_utf8_lossy(s).into_owned()
+    } else {
+        s.iter().map(|&c| c as char).collect()
+    }
+}
+
+/// Parse a date like `D:19990612201500+02'00'`, everything after the year is optional
+fn date(s: &str) -> Option<Date> {
+    let s = s.trim();
+    let s = s.strip_prefix("D:").unwrap_or(s);
+    let len = s.bytes().take_while(u8::is_ascii_digit).count();
+    let (d, rest) = s.split_at(len);
+
+    let iso = match len {
+        4 => d.to_string(),
+        6 | 7 => format!("{}-{}", &d[..4], &d[4..6]),
+        8..=11 => format!("{}-{}-{}", &d[..4], &d[4..6], &d[6..8]),
+        _ if len >= 12 => {
+            let secs = if len >= 14 { &d[12..14] } else { "00" };
+            let rest = rest.trim();
+            let offset = match rest.chars().next() {
+                Some('Z') => "Z".to_string(),
+                Some(sign @ '+') | Some(sign @ '-') => {
+                    let mut parts = rest[1..].split('\'').filter(|p| !p.is_empty());
+                    let hours = parts.next()?;
+                    format!("{}{}:{}", sign, hours, parts.next().unwrap_or("00"))
+                }
+                _ => String::new(),
+            };
+            format!("{}-{}-{}T{}:{}:{}{}", &d[..4], &d[4..6], &d[6..8], &d[8..10], &d[10..12], secs, offset)
+        }
+        _ => return None,
+    };
+    iso.parse().ok()
+}
+
+fn xmp_key(name: &[u8]) -> Option<&'static str> {
+    XMP.iter().find(|(n, _)| n.as_bytes() == name).map(|(_, k)| *k)
+}
+
+/// Read the properties of an XMP packet
+fn xmp(b: &[u8], x: &mut Extracted) {
+    let mut reader = Reader::from_reader(b);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut open: Vec<Vec<u8>> = Vec::new();
+
+    // Simple properties may be given as attributes of the description
+    let attributes = |e: &BytesStart, x: &mut Extracted| {
+        if e.name().as_ref() != b"rdf:Description" {
+            return;
+        }
+        for a in e.attributes().flatten() {
+            if let (Some(key), Ok(v)) = (xmp_key(a.key.as_ref()), a.unescape_value()) {
+                xmp_push(x, key, &v);
+            }
+        }
+    };
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => {
+                attributes(&e, x);
+                open.push(e.name().as_ref().to_vec());
+            }
+            Ok(Event::Empty(e)) => attributes(&e, x),
+            Ok(Event::End(_)) => {
+                open.pop();
+            }
+            Ok(Event::Text(t)) => {
+                // Values of arrays are in rdf:li elements inside the property
+                let key = open.iter().rev()
+                    .find(|n| !n.starts_with(b"rdf:"))
+                    .and_then(|n| xmp_key(n));
+                if let (Some(key), Ok(v)) = (key, t.unescape()) {
+                    xmp_push(x, key, &v);
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+fn xmp_push(x: &mut Extracted, key: &str, value: &str) {
+    // There's a date of the document and one of the file, the first is used
+    if key == "date" && x.metadata.contains_key(&Metakey::new(key)) {
+        return;
+    }
+    x.push_tag(key, value);
+}
+
+/// Text shown by the operators of a content stream
+fn content_text(b: &[u8]) -> String {
+    let mut l = Lexer::new(b, 0);
+    let mut operands = Vec::new();
+    let mut text = String::new();
+    while let Some(o) = l.object(0) {
+        let op = match o {
+            Object::Keyword(op) => op,
+            o => {
+                operands.push(o);
+                continue;
+            }
+        };
+        match op.as_str() {
+            "Tj" | "'" | "\"" => if let Some(Object::Str(s)) = operands.last() {
+                shown(&mut text, s);
+            },
+            "TJ" => if let Some(Object::Array(a)) = operands.last() {
+                for o in a {
+                    match o {
+                        Object::Str(s) => shown(&mut text, s),
+                        // Large adjustments separate words
+                        Object::Int(n) if *n < -200 => text.push(' '),
+                        Object::Real(n) if *n < -200.0 => text.push(' '),
+                        _ => {}
+                    }
+                }
+            },
+            "Td" | "TD" | "T*" | "ET" => text.push(' '),
+            // Inline image data, up to its end
+            "ID" => match b.get(l.pos..).and_then(|s| find(s, b"EI")) {
+                Some(p) => l.pos += p + 2,
+                None => break,
+            },
+            _ => {}
+        }
+        operands.clear();
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Strings mostly made of control characters are glyph IDs of fonts with a two byte encoding,
+/// which can't be decoded without the font
+fn shown(text: &mut String, s: &[u8]) {
+    let control = s.iter().filter(|&&c| c < 0x20 && !is_space(c)).count();
+    if control * 2 > s.len() {
+        return;
+    }
+    text.extend(s.iter().filter(|&&c| c >= 0x20).map(|&c| c as char));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use crate::db::meta::Metavalue;
+
+    fn compress(b: &[u8]) -> Vec<u8> {
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(b).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn stream(num: u32, dict: &str, data: &[u8]) -> Vec<u8> {
+        let mut o = format!("{} 0 obj\n<< {} /Length {} >>\nstream\n", num, dict, data.len()).into_bytes();
+        o.extend_from_slice(data);
+        o.extend_from_slice(b"\nendstream\nendobj\n");
+        o
+    }
+
+    #[test]
+    fn info_and_xmp() {
+        let content = compress(b"BT /F1 12 Tf 72 712 Td (Hello, \\(PDF\\)) Tj T* [(Wor) -20 (ld) -500 (again)] TJ ET");
+        let metadata = br#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreateDate="2003-11-01T12:00:00Z">
+<dc:title><rdf:Alt><rdf:li xml:lang="x-default">Dune</rdf:li></rdf:Alt></dc:title>
+</rdf:Description></rdf:RDF></x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+        let mut f = b"%PDF-1.5\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        f.extend_from_slice(b"1 0 obj\n<< /Type /Catalog /Pages 2 0 R /Metadata 5 0 R >>\nendobj\n");
+        f.extend_from_slice(b"2 0 obj\n<< /Type /Pages /Kids [3 0 R] /Count 1 >>\nendobj\n");
+        f.extend_from_slice(b"3 0 obj\n<< /Type /Page /Parent 2 0 R /Contents [4 0 R] >>\nendobj\n");
+        f.extend(stream(4, "/Filter /FlateDecode", &content));
+        f.extend(stream(5, "/Type /Metadata /Subtype /XML", metadata));
+        f.extend_from_slice(b"6 0 obj\n<< /Title (Ignored) /Author <FEFF004600720061006E006B> /Subject (A desert planet)\n\
+            /Keywords (sand; spice) /CreationDate (D:19650801120000+02'00') >>\nendobj\n");
+        f.extend_from_slice(b"trailer\n<< /Size 7 /Root 1 0 R /Info 6 0 R >>\n%%EOF\n");
+
+        let mut f = Cursor::new(f);
+        assert_eq!(crate::extract::mime_type(&mut f).unwrap(), Some("application/pdf"));
+        let x = read(&mut f, 1024).unwrap();
+        let s = |v: &str| Metavalue::Str(vec![v.into()].into_boxed_slice());
+        assert_eq!(x.metadata[&Metakey::new("title")], s("Dune"));
+        assert_eq!(x.metadata[&Metakey::new("author")], s("Frank"));
+        assert_eq!(x.metadata[&Metakey::new("description")], s("A desert planet"));
+        assert_eq!(x.metadata[&Metakey::new("subject")], Metavalue::Str(vec!["sand".into(), "spice".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")].to_date().next().unwrap().year(), 2003);
+        assert_eq!(x.text, "Hello, (PDF) World again");
+    }
+
+    #[test]
+    fn object_streams() {
+        let objects = b"2 0 << /Title (Compressed) /CreationDate (D:196508) >>";
+        let mut f = b"%PDF-1.5\n".to_vec();
+        f.extend(stream(1, "/Type /ObjStm /N 1 /First 4 /Filter [/FlateDecode]", &compress(objects)));
+        f.extend(stream(3, "/Type /XRef /Size 4 /Info 2 0 R", b""));
+        f.extend_from_slice(b"startxref\n0\n%%EOF\n");
+
+        let x = read(&mut Cursor::new(f), 0).unwrap();
+        assert_eq!(x.metadata[&Metakey::new("title")], Metavalue::Str(vec!["Compressed".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")].to_date().next().unwrap().to_string(), "1965-08");
+        assert!(x.text.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let f = b"%PDF-1.5\n1 0 obj <</Type/ObjStm>".to_vec();
+        let x = crate::extract::Registry::builtin().read(&mut Cursor::new(f), Some("pdf")).unwrap().unwrap();
+        assert_eq!(&*x.format[&FormatKey::MimeType], "application/pdf");
+        assert!(x.metadata.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::db::entry::FormatKey;
+use crate::error::Result;
+use crate::extract::{Extracted, Extractor, ReadSeek, malformed};
+
+/// Largest Ogg packet that is read, comment packets with embedded cover art can be big
+const MAX_PACKET: usize = 16 * 1024 * 1024;
+
+/// Extractor for FLAC files
+pub struct Flac;
+
+impl Extractor for Flac {
+    fn name(&self) -> &'static str {
+        "flac"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["audio/flac", "audio/x-flac"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["flac"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read_flac(r)
+    }
+}
+
+/// Extractor for Ogg Vorbis and Opus files
+pub struct Ogg;
+
+impl Extractor for Ogg {
+    fn name(&self) -> &'static str {
+        "ogg"
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["audio/ogg", "audio/opus", "audio/vorbis"]
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["ogg", "oga", "opus"]
+    }
+
+    fn read(&self, r: &mut dyn ReadSeek) -> Result<Extracted> {
+        read_ogg(r)
+    }
+}
+
+/// Read the STREAMINFO and VORBIS_COMMENT blocks of a FLAC file
+pub fn read_flac<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Extracted> {
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, "audio/flac");
+    x.set_format(FormatKey::Container, "FLAC");
+
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(malformed("FLAC", "missing fLaC marker"));
+    }
+
+    loop {
+        let mut h = [0; 4];
+        r.read_exact(&mut h)?;
+        let last = h[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, h[1], h[2], h[3]]) as usize;
+        match h[0] & 0x7f {
+            0 | 4 => {
+                let mut b = vec![0; len];
+                r.read_exact(&mut b)?;
+                if h[0] & 0x7f == 0 {
+                    streaminfo(&b, &mut x)?;
+                } else {
+                    comments(&b, &mut x)?;
+                }
+            }
+            // Pictures, seek tables, padding, ...
+            _ => { r.seek(SeekFrom::Current(len as i64))?; }
+        }
+        if last {
+            break;
+        }
+    }
+
+    Ok(x)
+}
+
+fn streaminfo(b: &[u8], x: &mut Extracted) -> Result<()> {
+    if b.len() < 18 {
+        return Err(malformed("FLAC", "STREAMINFO block too short"));
+    }
+    let rate = (b[10] as u64) << 12 | (b[11] as u64) << 4 | (b[12] as u64) >> 4;
+    let channels = ((b[12] >> 1) & 0x07) + 1;
+    let samples = ((b[13] & 0x0f) as u64) << 32 | u32::from_be_bytes([b[14], b[15], b[16], b[17]]) as u64;
+
+    x.set_format(FormatKey::Channels, channels);
+    if rate > 0 {
+        x.set_format(FormatKey::SampleRate, rate);
+        // The number of samples is unknown if zero
+        if samples > 0 {
+            x.set_format(FormatKey::Duration, (samples + rate / 2) / rate);
+        }
+    }
+    Ok(())
+}
+
+/// Read the identification and comment headers of the first logical stream of an Ogg file
+///
+/// Vorbis and Opus streams are understood, other codecs only yield the container format.
+pub fn read_ogg<R: Read + ?Sized>(r: &mut R) -> Result<Extracted> {
+    let mut x = Extracted::new();
+    x.set_format(FormatKey::MimeType, "audio/ogg");
+    x.set_format(FormatKey::Container, "Ogg");
+
+    let mut packets = Packets { r, serial: None, segments: Vec::new() };
+    let head = packets.next()?;
+    if head.starts_with(b"\x01vorbis") && head.len() >= 16 {
+        x.set_format(FormatKey::Channels, head[11]);
+        x.set_format(FormatKey::SampleRate, u32::from_le_bytes([head[12], head[13], head[14], head[15]]));
+        let tags = packets.next()?;
+        if let Some(b) = tags.strip_prefix(b"\x03vorbis") {
+            comments(b, &mut x)?;
+        }
+    } else if head.starts_with(b"OpusHead") && head.len() >= 10 {
+        x.set_format(FormatKey::Channels, head[9]);
+        // Opus is always decoded at 48kHz, the header only records the rate of the input
+        x.set_format(FormatKey::SampleRate, 48000);
+        let tags = packets.next()?;
+        if let Some(b) = tags.strip_prefix(b"OpusTags") {
+            comments(b, &mut x)?;
+        }
+    }
+
+    Ok(x)
+}
+
+/// Packets of the first logical stream of an Ogg file
+struct Packets<'a, R: ?Sized> {
+    r: &'a mut R,
+    serial: Option<[u8; 4]>,
+    /// Lacing values of the current page not yet read
+    segments: Vec<u8>,
+}
+
+impl<'a, R: Read + ?Sized> Packets<'a, R> {
+    fn next(&mut self) -> Result<Vec<u8>> {
+        let mut packet = Vec::new();
+        loop {
+            if self.segments.is_empty() {
+                self.page()?;
+                continue;
+            }
+            let len = self.segments.remove(0);
+            let start = packet.len();
+            packet.resize(start + len as usize, 0);
+            self.r.read_exact(&mut packet[start..])?;
+            if packet.len() > MAX_PACKET {
+                return Err(malformed("Ogg", "header packet too large"));
+            }
+            // A lacing value below 255 ends the packet
+            if len < 255 {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Read the next page header of our stream, skipping pages of other streams
+    fn page(&mut self) -> Result<()> {
+        loop {
+            let mut h = [0; 27];
+            self.r.read_exact(&mut h)?;
+            if &h[..4] != b"OggS" {
+                return Err(malformed("Ogg", "missing page capture pattern"));
+            }
+            let mut serial = [0; 4];
+            serial.copy_from_slice(&h[14..18]);
+            let mut segments = vec![0; h[26] as usize];
+            self.r.read_exact(&mut segments)?;
+
+            if *self.serial.get_or_insert(serial) == serial {
+                self.segments = segments;
+                return Ok(());
+            }
+            let len: u64 = segments.iter().map(|&s| s as u64).sum();
+            std::io::copy(&mut self.r.take(len), &mut std::io::sink())?;
+        }
+    }
+}
+
+/// Parse a Vorbis comment header into `x`
+///
+/// Field names are case-insensitive and stored lowercased, so `TITLE` becomes `title`.
+pub fn comments(mut b: &[u8], x: &mut Extracted) -> Result<()> {
+    let vendor = u32le(&mut b)?;
+    take(&mut b, vendor)?;
+    let count = u32le(&mut b)?;
+    for _ in 0..count {
+        let len = u32le(&mut b)?;
+        let c = String::from_utf8_lossy(take(&mut b, len)?);
+        if let Some((k, v)) = c.split_once('=') {
+            let k = k.to_lowercase();
+            let k = match k.as_str() {
+                "album artist" => "albumartist",
+                k => k,
+            };
+            x.push_tag(k, v);
+        }
+    }
+    Ok(())
+}
+
+fn take<'a>(b: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if b.len() < n {
+        return Err(malformed("Vorbis comment", "truncated"));
+    }
+    let (h, t) = b.split_at(n);
+    *b = t;
+    Ok(h)
+}
+
+fn u32le(b: &mut &[u8]) -> Result<usize> {
+    let h = take(b, 4)?;
+    Ok(u32::from_le_bytes([h[0], h[1], h[2], h[3]]) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::db::meta::{Metakey, Metavalue};
+
+    fn comment_block(fields: &[&str]) -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(&4u32.to_le_bytes());
+        b.extend_from_slice(b"test");
+        b.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        for f in fields {
+            b.extend_from_slice(&(f.len() as u32).to_le_bytes());
+            b.extend_from_slice(f.as_bytes());
+        }
+        b
+    }
+
+    #[test]
+    fn flac() {
+        let mut f = b"fLaC".to_vec();
+        // 44.1kHz, stereo, 16 bit, 441000 samples
+        let mut info = vec![0; 34];
+        info[10..14].copy_from_slice(&[0x0a, 0xc4, 0x42, 0xf0]);
+        info[14..18].copy_from_slice(&441000u32.to_be_bytes());
+        f.extend_from_slice(&[0, 0, 0, 34]);
+        f.extend_from_slice(&info);
+        f.extend_from_slice(&[1, 0, 0, 4, 0, 0, 0, 0]);
+        let c = comment_block(&["TITLE=Leviathan", "Artist=Mastodon", "TRACKNUMBER=2/13", "DATE=2004", "ALBUM ARTIST=Mastodon"]);
+        f.push(0x84);
+        f.extend_from_slice(&(c.len() as u32).to_be_bytes()[1..]);
+        f.extend_from_slice(&c);
+
+        let x = read_flac(&mut Cursor::new(f)).unwrap();
+        assert_eq!(&*x.format[&FormatKey::SampleRate], "44100");
+        assert_eq!(&*x.format[&FormatKey::Channels], "2");
+        assert_eq!(&*x.format[&FormatKey::Duration], "10");
+        assert_eq!(x.metadata[&Metakey::new("artist")], Metavalue::Str(vec!["Mastodon".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("albumartist")], Metavalue::Str(vec!["Mastodon".into()].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("tracknumber")], Metavalue::Int(vec![2].into_boxed_slice()));
+        assert_eq!(x.metadata[&Metakey::new("date")].to_date().next().unwrap().year(), 2004);
+
+        assert!(read_flac(&mut Cursor::new(b"fLaC\x84\0\0\x10".to_vec())).is_err());
+    }
+}
@@ -15,6 +15,9 @@ pub mod query;
 
 pub mod schema;
 
+// Reading metadata from files
+pub mod extract;
+
 mod uuid;
 
 use std::path::Path;
@@ -22,6 +25,7 @@ use std::collections::HashMap;
 use std::mem;
 
 use error::Result;
+pub use error::Error;
 use db::{dbm::DBManager, EntryDB};
 use db::entry::EntryT;
 use query::Query;
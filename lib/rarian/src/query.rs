@@ -1,18 +1,22 @@
 use std::str::Chars;
-use std::collections::HashSet;
-use std::ops::Bound;
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
 use std::convert::TryInto;
 
 pub use lmdb::Transaction;
 
 use nom::{
     IResult,
-    sequence::delimited,
-    character::complete::char,
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::{char, digit1},
+    sequence::{delimited, separated_pair, terminated},
 };
 
 use crate::error::*;
-use crate::db::meta::Metakey;
+use crate::db::meta::{Metakey, Metavalue};
+use crate::schema::{Schema, Attributetype};
 
 use crate::db::{
     Database,
@@ -20,6 +24,9 @@ use crate::db::{
     EntryDB,
     RangeDB,
     entry::EntryT,
+    date::{self, Date},
+    term,
+    keyword,
 };
 
 use crate::uuid::UUID;
@@ -38,17 +45,71 @@ use crate::db::dbm::{
 //   OR
 //
 // Transformers (get a single set)
-//   Sorting: Sort Set of UUIDs
+//   Sorting: Sort Set of UUIDs (by relevance or attributes: `Querier::ranked`)
 //   Filter: filter by something
+//
+// Aggregates (summarize a set)
+//   Facets: Count values per attribute: `Querier::facets`
 
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub enum Filter {
     TermExists(String),
     IntInRange(Bound<i64>, Bound<i64>),
+    /// All words of the text next to each other in the given order
+    Phrase(String),
+    /// All words of the text within a window of at most this many positions
+    Near(String, u32),
+    /// Any word starting with the given text
+    Prefix(String),
+    /// Any word matching a pattern with `*` and `?` wildcards
+    Wildcard(String),
+    /// Any word at most this many edits away from the given one
+    Fuzzy(String, u32),
+    /// A whole value equal to the given one, ignoring case and repeated whitespace
+    Equals(String),
+}
+
+impl Filter {
+    /// Check if this filter can be applied to values of type `t`
+    pub fn accepts(&self, t: Attributetype) -> bool {
+        match self {
+            Filter::TermExists(_) | Filter::Phrase(_) | Filter::Near(_, _)
+                | Filter::Prefix(_) | Filter::Wildcard(_) | Filter::Fuzzy(_, _)
+                | Filter::Equals(_) => t == Attributetype::String,
+            Filter::IntInRange(_, _) => t.is_int(),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn bound(b: &Bound<i64>) -> String {
+            match b {
+                Bound::Included(i) | Bound::Excluded(i) => i.to_string(),
+                Bound::Unbounded => String::new(),
+            }
+        }
+        match self {
+            Filter::TermExists(t) => write!(f, "term {:?}", t),
+            Filter::IntInRange(l, u) => write!(f, "range [{}..{}]", bound(l), bound(u)),
+            Filter::Phrase(t) => write!(f, "phrase {:?}", t),
+            Filter::Near(t, n) => write!(f, "proximity {:?}~{}", t, n),
+            Filter::Prefix(t) => write!(f, "prefix {:?}", t),
+            Filter::Wildcard(t) => write!(f, "wildcard {:?}", t),
+            Filter::Fuzzy(t, n) => write!(f, "fuzzy term {:?}~{}", t, n),
+            Filter::Equals(t) => write!(f, "value {:?}", t),
+        }
+    }
 }
 
 pub type Target = Metakey;
 
+/// Attribute searched by terms without an explicit target
+pub const DEFAULT_TARGET: &str = "title";
+
+/// Edit distance allowed by a fuzzy term without an explicit one, as in `tchaikovski~`
+pub const DEFAULT_EDITS: u32 = 2;
+
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub enum QueryT {
     F(Filter, Target),
@@ -60,20 +121,291 @@ pub enum QueryT {
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub struct Query {
     root: QueryT,
+    sort: Vec<SortKey>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+/// Attribute to order query results by
+pub struct SortKey {
+    pub key: Metakey,
+    pub descending: bool,
+}
+
+impl Query {
+    pub fn new(root: QueryT) -> Self {
+        Self { root, sort: Vec::new(), limit: None, offset: 0 }
+    }
+
+    /// Order results by `key`. Keys added later break ties of the earlier ones.
+    pub fn add_sort(&mut self, key: Metakey, descending: bool) -> &mut Self {
+        self.sort.push(SortKey { key, descending });
+        self
+    }
+
+    /// Return at most `limit` results
+    pub fn set_limit(&mut self, limit: usize) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skip the first `offset` results
+    pub fn set_offset(&mut self, offset: usize) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Check that every filter is applied to an attribute of a matching type
+    pub fn typecheck(&self, schema: &Schema) -> Result<()> {
+        for k in self.sort.iter() {
+            if schema.attribute(&k.key).is_none() {
+                return Err(Error::UnknownAttribute(k.key.clone()));
+            }
+        }
+        typecheck_t(&self.root, schema)
+    }
+}
+
+/// Number of ranges the values of int attributes are grouped into by `Querier::facets`
+pub const FACET_BUCKETS: i64 = 10;
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+/// Histogram of the values of an attribute over a set of results
+pub enum Facet {
+    /// Number of results per value, most common first
+    Values(Vec<(String, usize)>),
+    /// Number of results per range of values, from lowest to highest
+    Ranges(Vec<Bucket>),
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+/// Number of results with a value between `lower` and `upper`, both inclusive
+pub struct Bucket {
+    pub lower: i64,
+    pub upper: i64,
+    pub count: usize,
+}
+
+impl Facet {
+    fn ranges(counts: &HashMap<i64, usize>) -> Self {
+        let (min, max) = match (counts.keys().min(), counts.keys().max()) {
+            (Some(min), Some(max)) => (*min, *max),
+            _ => return Facet::Ranges(Vec::new()),
+        };
+
+        // Computed in i128 since the span of two i64 can overflow
+        let width = ((max as i128 - min as i128) / FACET_BUCKETS as i128) + 1;
+        let mut buckets: Vec<Bucket> = (0..FACET_BUCKETS as i128)
+            .map(|n| min as i128 + n * width)
+            .take_while(|lower| *lower <= max as i128)
+            .map(|lower| Bucket {
+                lower: lower as i64,
+                upper: (lower + width - 1).min(max as i128) as i64,
+                count: 0,
+            })
+            .collect();
+        for (v, c) in counts.iter() {
+            let n = ((*v as i128 - min as i128) / width) as usize;
+            buckets[n].count += c;
+        }
+
+        Facet::Ranges(buckets)
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+/// Value an entry is sorted by. Only values of the same attribute are ever compared.
+enum SortValue {
+    Int(i64),
+    Str(String),
+}
+
+impl SortValue {
+    /// Multi-valued attributes are sorted by their first value
+    fn first(v: &Metavalue) -> Option<Self> {
+        match v.to_int().next() {
+            Some(i) => Some(SortValue::Int(i)),
+            None => v.to_str().next().map(|s| SortValue::Str(s.to_lowercase())),
+        }
+    }
+}
+
+/// Compare the sort values of two entries. Entries missing a value always sort last.
+fn compare_sorted(keys: &[SortKey], a: &[Option<SortValue>], b: &[Option<SortValue>]) -> Ordering {
+    for (k, (x, y)) in keys.iter().zip(a.iter().zip(b.iter())) {
+        let o = match (x, y) {
+            (Some(x), Some(y)) if k.descending => y.cmp(x),
+            (Some(x), Some(y)) => x.cmp(y),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        if o != Ordering::Equal {
+            return o;
+        }
+    }
+    Ordering::Equal
+}
+
+fn typecheck_t(query: &QueryT, schema: &Schema) -> Result<()> {
+    match query {
+        QueryT::F(filter, target) => {
+            let attr = schema.attribute(target)
+                .ok_or_else(|| Error::UnknownAttribute(target.clone()))?;
+            if !filter.accepts(attr.atype) {
+                return Err(Error::QueryTypeMismatch {
+                    key: target.clone(),
+                    expected: attr.atype,
+                    filter: filter.to_string(),
+                });
+            }
+            Ok(())
+        }
+        QueryT::OR(a, b) | QueryT::AND(a, b) => {
+            typecheck_t(a, schema)?;
+            typecheck_t(b, schema)
+        }
+        QueryT::NOT(a) => typecheck_t(a, schema),
+    }
 }
 
 pub struct Querier<'env, T> {
     txn: &'env T,
     db: &'env Database,
+    unindexed: Vec<Target>,
 }
 
 impl<'env, T: Transaction> Querier<'env, T> {
     pub fn new(txn: &'env T, db: &'env Database) -> Self {
-        Self { txn, db }
+        Self { txn, db, unindexed: Vec::new() }
     }
+
+    /// Targets that had to be searched by scanning all entries because they have no index
+    pub fn unindexed(&self) -> &[Target] {
+        &self.unindexed
+    }
+
+    /// Run a query, returning all matches. Sort keys, limit and offset only apply to `ranked`.
     pub fn run(&mut self, query: Query) -> Result<HashSet<UUID>> {
+        query.typecheck(&self.db.schema)?;
         self.run_t(query.root)
     }
+
+    /// Run a query and order the matches, applying its limit and offset
+    ///
+    /// Matches are ordered by the sort keys of the query first and by relevance after that.
+    /// Text filters on term indices are scored using BM25, weighted by the boost of the
+    /// attribute. Ranges, filters on unindexed attributes and negated parts of the query don't
+    /// contribute to the score.
+    pub fn ranked(&mut self, query: Query) -> Result<Vec<(UUID, f32)>> {
+        query.typecheck(&self.db.schema)?;
+        let matches = self.run_t(query.root.clone())?;
+
+        let mut scores: HashMap<UUID, f32> = matches.iter().map(|u| (*u, 0.0)).collect();
+        self.score_t(&query.root, &matches, &mut scores)?;
+
+        let mut out: Vec<(UUID, f32)> = scores.into_iter().collect();
+        out.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal).then(a.0.cmp(&b.0)));
+        if !query.sort.is_empty() {
+            out = self.sort(out, &query.sort)?;
+        }
+
+        Ok(out.into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Count the values of each of `keys` over a set of results
+    ///
+    /// Every entry counts once per distinct value. String attributes are counted per value,
+    /// int and timestamp attributes are counted in `FACET_BUCKETS` ranges of equal width.
+    pub fn facets<'a, I>(&self, results: I, keys: &[Metakey]) -> Result<Vec<(Metakey, Facet)>>
+        where I: IntoIterator<Item=&'a UUID>
+    {
+        let mut strs: Vec<HashMap<String, usize>> = vec![HashMap::new(); keys.len()];
+        let mut ints: Vec<HashMap<i64, usize>> = vec![HashMap::new(); keys.len()];
+        for k in keys.iter() {
+            if self.db.schema.attribute(k).is_none() {
+                return Err(Error::UnknownAttribute(k.clone()));
+            }
+        }
+
+        for uuid in results {
+            let e = self.db.lookup(self.txn, uuid)?;
+            for (n, k) in keys.iter().enumerate() {
+                let v = match e.metadata.get(k) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let distinct: HashSet<&str> = v.to_str().map(|s| &**s).collect();
+                for s in distinct {
+                    *strs[n].entry(s.to_string()).or_insert(0) += 1;
+                }
+                let distinct: HashSet<i64> = v.to_int().collect();
+                for i in distinct {
+                    *ints[n].entry(i).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Ok(keys.iter().zip(strs.into_iter().zip(ints))
+            .map(|(k, (strs, ints))| {
+                let is_int = self.db.schema.attribute(k).is_some_and(|a| a.atype.is_int());
+                let facet = if is_int {
+                    Facet::ranges(&ints)
+                } else {
+                    let mut values: Vec<(String, usize)> = strs.into_iter().collect();
+                    values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                    Facet::Values(values)
+                };
+                (k.clone(), facet)
+            })
+            .collect())
+    }
+
+    /// Stable sort of `results` by the given keys
+    fn sort(&self, results: Vec<(UUID, f32)>, keys: &[SortKey]) -> Result<Vec<(UUID, f32)>> {
+        let mut decorated = Vec::with_capacity(results.len());
+        for (uuid, score) in results {
+            let e = self.db.lookup(self.txn, &uuid)?;
+            let values: Vec<Option<SortValue>> = keys.iter()
+                .map(|k| e.metadata.get(&k.key).and_then(SortValue::first))
+                .collect();
+            decorated.push((values, uuid, score));
+        }
+
+        decorated.sort_by(|a, b| compare_sorted(keys, &a.0, &b.0));
+        Ok(decorated.into_iter().map(|(_, u, s)| (u, s)).collect())
+    }
+
+    fn score_t(&self, query: &QueryT, matches: &HashSet<UUID>, scores: &mut HashMap<UUID, f32>) -> Result<()> {
+        match query {
+            QueryT::F(filter, target) => {
+                if let Some(Index::Term(db)) = self.db.indices.get(target) {
+                    let stems = match filter {
+                        Filter::TermExists(text) | Filter::Phrase(text) | Filter::Near(text, _) => db.analyzer().stems(text),
+                        Filter::Prefix(prefix) => db.prefix_stems(self.txn, prefix)?,
+                        Filter::Wildcard(pattern) => db.wildcard_stems(self.txn, pattern)?,
+                        Filter::Fuzzy(term, n) => db.fuzzy_stems(self.txn, term, *n)?,
+                        Filter::IntInRange(_, _) | Filter::Equals(_) => return Ok(()),
+                    };
+                    let boost = self.db.schema.attribute(target).map_or(1.0, |a| a.boost);
+                    for (uuid, s) in db.score(self.txn, &stems, matches)? {
+                        *scores.entry(uuid).or_insert(0.0) += boost * s;
+                    }
+                }
+                Ok(())
+            }
+            QueryT::OR(a, b) | QueryT::AND(a, b) => {
+                self.score_t(a, matches, scores)?;
+                self.score_t(b, matches, scores)
+            }
+            QueryT::NOT(_) => Ok(()),
+        }
+    }
+
     fn run_t(&mut self, query: QueryT) -> Result<HashSet<UUID>> {
         // TODO: Keep the error path instead of bubbling it up throwing away that info
         match query {
@@ -99,7 +431,7 @@ impl<'env, T: Transaction> Querier<'env, T> {
     }
 
     pub fn filter(&mut self, filter: Filter, target: Target) -> Result<HashSet<UUID>> {
-        // 1: Check schema if that filter is valid for that target (typecheck)
+        // 1: Check schema if that filter is valid for that target (typecheck, done in `run`)
         // 2: Figure out where the index for that target is (if any!)
         // 3a: If there is an index, do a fast indexed search
         // 3b: If there is no index, do a slow iterating search
@@ -109,103 +441,599 @@ impl<'env, T: Transaction> Querier<'env, T> {
 
         if let Some(i) = self.db.indices.get(&target) {
             match (i,filter) {
+                // Only the start of dates is indexed. Those starting in the range overlap it, ones
+                // starting up to `MAX_SPAN` before it may still end in it.
+                (Index::IntMap(db), Filter::IntInRange(lower,upper)) if self.is_date(&target) => {
+                    let mut found = db.range(self.txn, (lower,upper))?;
+                    let before = match lower {
+                        Bound::Included(l) => Some((Bound::Included(l.saturating_sub(date::MAX_SPAN)), Bound::Excluded(l))),
+                        Bound::Excluded(l) => Some((Bound::Included(l.saturating_sub(date::MAX_SPAN)), Bound::Included(l))),
+                        Bound::Unbounded => None,
+                    };
+                    if let Some(before) = before {
+                        for uuid in db.range(self.txn, before)? {
+                            let e = self.db.lookup(self.txn, &uuid)?;
+                            if e.metadata.get(&target).is_some_and(|v| v.to_date().any(|d| d.overlaps(&(lower, upper)))) {
+                                found.insert(uuid);
+                            }
+                        }
+                    }
+                    Ok(found)
+                }
                 (Index::IntMap(db), Filter::IntInRange(lower,upper)) => {
-                    Ok(db.map.range((lower,upper)).map(|(_,u)| *u).collect())
+                    db.range(self.txn, (lower,upper))
                 }
                 (Index::Term(db), Filter::TermExists(ref term)) => {
-                    db.lookup(self.txn, &term).map(|m| m.into_set())
+                    db.lookup(self.txn, term)
+                }
+                (Index::Term(db), Filter::Phrase(ref text)) => {
+                    db.phrase(self.txn, text)
+                }
+                (Index::Term(db), Filter::Near(ref text, n)) => {
+                    db.near(self.txn, text, n)
+                }
+                (Index::Term(db), Filter::Prefix(ref prefix)) => {
+                    db.prefix(self.txn, prefix)
+                }
+                (Index::Term(db), Filter::Wildcard(ref pattern)) => {
+                    db.wildcard(self.txn, pattern)
+                }
+                (Index::Term(db), Filter::Fuzzy(ref term, n)) => {
+                    db.fuzzy(self.txn, term, n)
+                }
+                // Full-text indices keep no values to compare against
+                (Index::Term(db), Filter::Equals(_)) if db.is_fulltext() => Err(Error::QueryType),
+                // Every value equal to the given one also contains it as a phrase. Values made of
+                // only stopwords aren't indexed at all and have to be checked against every entry.
+                (Index::Term(db), Filter::Equals(ref value)) => {
+                    let found = if db.analyzer().tokens(value).is_empty() {
+                        self.all()?
+                    } else {
+                        db.phrase(self.txn, value)?
+                    };
+                    self.equal_values(found, &target, value)
+                }
+                (Index::Keyword(db), Filter::Equals(ref value))
+                    | (Index::Keyword(db), Filter::TermExists(ref value))
+                    | (Index::Keyword(db), Filter::Phrase(ref value)) => {
+                    let found = db.equals(self.txn, value)?;
+                    if keyword::normalize(value).len() > keyword::MAX_KEY {
+                        self.equal_values(found, &target, value)
+                    } else {
+                        Ok(found)
+                    }
+                }
+                (Index::Keyword(db), Filter::Prefix(ref prefix)) => {
+                    db.prefix(self.txn, prefix)
+                }
+                (Index::Keyword(db), Filter::Wildcard(ref pattern)) => {
+                    db.wildcard(self.txn, pattern)
+                }
+                (Index::Keyword(db), Filter::Fuzzy(ref value, n)) => {
+                    db.fuzzy(self.txn, value, n)
                 }
                 _ => Err(Error::QueryType),
             }
         } else {
-            // Iterative search
-            // TODO: Actually do the search.
-            Err(Error::QueryIterating)
+            self.scan(filter, target)
         }
     }
 
+    /// Check if `target` holds dates, which cover a period rather than a single second
+    fn is_date(&self, target: &Target) -> bool {
+        self.db.schema.attribute(target).is_some_and(|a| a.atype == Attributetype::Date)
+    }
+
+    /// Keep only the UUIDs with a value of `target` equal to `value`
+    fn equal_values(&self, uuids: HashSet<UUID>, target: &Target, value: &str) -> Result<HashSet<UUID>> {
+        let value = keyword::normalize(value);
+        let mut out = HashSet::new();
+        for uuid in uuids {
+            let e = self.db.lookup(self.txn, &uuid)?;
+            if e.metadata.get(target).is_some_and(|v| v.to_str().any(|s| keyword::normalize(s) == value)) {
+                out.insert(uuid);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Slow path for targets without an index: Decode every entry and match its values directly
+    ///
+    /// Text is analyzed with the default English analyzer since there is no index configuring one.
+    fn scan(&mut self, filter: Filter, target: Target) -> Result<HashSet<UUID>> {
+        let analyzer = term::Analyzer::default();
+        let query = match filter {
+            Filter::TermExists(ref text) | Filter::Phrase(ref text) | Filter::Near(ref text, _) => analyzer.tokens(text),
+            Filter::IntInRange(_, _) | Filter::Prefix(_) | Filter::Wildcard(_) | Filter::Fuzzy(_, _)
+                | Filter::Equals(_) => Vec::new(),
+        };
+
+        let mut out = HashSet::new();
+        for r in self.db.entries.iter_start(self.txn)? {
+            let (k, v) = r?;
+            let e = EntryT::decode(v)?;
+            let value = match e.metadata.get(&target) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let matches = match filter {
+                Filter::IntInRange(lower, upper) => match value {
+                    Metavalue::Date(d) => d.iter().any(|d| d.overlaps(&(lower, upper))),
+                    _ => value.to_int().any(|i| (lower, upper).contains(&i)),
+                },
+                Filter::TermExists(_) | Filter::Phrase(_) => {
+                    term::match_phrase(&query, &analyzer.positions(value.to_str().map(|s| &**s)))
+                }
+                Filter::Near(_, n) => {
+                    term::match_near(&query, n, &analyzer.positions(value.to_str().map(|s| &**s)))
+                }
+                Filter::Prefix(ref prefix) => {
+                    let prefix = prefix.to_lowercase();
+                    value.to_str().flat_map(|s| analyzer.stems(s)).any(|s| s.starts_with(&prefix))
+                }
+                Filter::Wildcard(ref pattern) => {
+                    let pattern = pattern.to_lowercase();
+                    value.to_str().flat_map(|s| analyzer.stems(s)).any(|s| term::glob_match(&pattern, &s))
+                }
+                Filter::Fuzzy(ref t, n) => {
+                    let stem = analyzer.query_stem(t);
+                    value.to_str().flat_map(|s| analyzer.stems(s)).any(|s| term::within_edits(&stem, &s, n))
+                }
+                Filter::Equals(ref v) => {
+                    let v = keyword::normalize(v);
+                    value.to_str().any(|s| keyword::normalize(s) == v)
+                }
+            };
+            if matches {
+                out.insert(UUID::from_bytes(k)?);
+            }
+        }
+
+        if !self.unindexed.contains(&target) {
+            self.unindexed.push(target);
+        }
+
+        Ok(out)
+    }
+
     pub fn all(&mut self) -> Result<HashSet<UUID>> {
         let i = self.db.entries.iter_start(self.txn)?;
         i.map(|r| {
             let (b,_) = r?;
-            let (int_bytes, _rest) = b.split_at(std::mem::size_of::<u128>());
-            let u = u128::from_le_bytes(int_bytes.try_into().unwrap());
-            Ok(UUID::from_u128(u))
+            UUID::from_bytes(b)
         }).collect()
     }
 }
 
-// 'python OR raspberry or pi' => "title:python OR title:raspberry OR title:pi"
-// 'python AND raspberry description:pi' => "title:python AND title:raspberry OR description:pi"
-// 'date:[2019..2020]' for range query
+// Query grammar, loosest binding first:
+//
+//   query  := or
+//   or     := and ("OR" and)*
+//   and    := unary (["AND"] unary)*
+//   unary  := "NOT" unary | "-" unary | atom
+//   atom   := "(" or ")" | [key ":"] value
+//   value  := "[" [int] ".." [int] "]" | '"' text '"' ["~" int] | word ["~" [int]]
+//           | "=" ('"' text '"' | word) | (">" | ">=" | "<" | "<=") bound
+//
+// 'python raspberry OR description:pi' => "(title:python AND title:raspberry) OR description:pi"
+// 'date:[2019..2020]' for range query, 'date:[1990..1999-06]' or 'date:>2015-01-01' on dates
+// '"dark side"' for a phrase, '"dark moon"~3' for words at most 3 positions apart
+// 'leviat*' for a prefix, 'lev*an' for a wildcard pattern, 'tchaikovski~2' for fuzzy matching
+// 'artist:="The The"' for the whole value. Terms and phrases on attributes with a keyword index
+// always match the whole value.
+
+/// Parse a query, resolving attribute names using `schema`
+pub fn parse(query: &str, schema: &Schema) -> Result<Query> {
+    let p = Parser { query, schema };
+
+    let (rest, root) = p.or(query)?;
+    let rest = ws(rest);
+    if !rest.is_empty() {
+        // `or` only stops early at a closing parenthesis
+        return Err(Error::QueryUnbalanced { column: p.column(rest) });
+    }
+
+    Ok(Query::new(root))
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Keyword {
+    And,
+    Or,
+    Not,
+}
+
+fn ws(i: &str) -> &str {
+    i.trim_start()
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"()\"".contains(c)
+}
+
+fn word(i: &str) -> IResult<&str, &str> {
+    take_while1(is_word_char)(i)
+}
+
+fn keyword(i: &str) -> Option<(&str, Keyword)> {
+    let (rest, w) = word(i).ok()?;
+    let k = match w {
+        "AND" | "and" => Keyword::And,
+        "OR" | "or" => Keyword::Or,
+        "NOT" | "not" => Keyword::Not,
+        _ => return None,
+    };
+    Some((rest, k))
+}
+
+fn key(i: &str) -> IResult<&str, &str> {
+    terminated(take_while1(|c: char| c.is_alphanumeric() || c == '_'), char(':'))(i)
+}
+
+fn quoted(i: &str) -> IResult<&str, &str> {
+    delimited(char('"'), take_while(|c| c != '"'), char('"'))(i)
+}
+
+fn range_bound(i: &str) -> IResult<&str, &str> {
+    take_while(|c: char| c.is_ascii_digit() || "-+:TZ".contains(c))(i)
+}
+
+fn range(i: &str) -> IResult<&str, (&str, &str)> {
+    delimited(char('['), separated_pair(range_bound, tag(".."), range_bound), char(']'))(i)
+}
 
-pub fn parse(query: &str) -> Result<Query> {
-    enum C { OR, AND };
+struct Parser<'q> {
+    query: &'q str,
+    schema: &'q Schema,
+}
 
-    let mut a: Option<QueryT> = None;
-    let mut comb = C::OR;
-    for word in query.split_whitespace() {
-        let mut step = None;
-        if let Some(i) = word.find(':') {
-            let (target, rest) = word.split_at(i);
-            let filter = &rest[1..];
+impl<'q> Parser<'q> {
+    /// 1-based column in the query at which `at` starts. `at` must be a slice of the query.
+    fn column(&self, at: &str) -> usize {
+        let offset = at.as_ptr() as usize - self.query.as_ptr() as usize;
+        self.query[..offset].chars().count() + 1
+    }
 
-            let f = parse_f(filter)?;
-            step.replace(Box::new(QueryT::F(f, Metakey::from_str(target)?)));
+    fn syntax<S: Into<String>>(&self, at: &str, message: S) -> Error {
+        if at.is_empty() {
+            Error::QueryUnexpectedEOS
         } else {
-            match word {
-                "OR" | "or" => comb = C::OR,
-                "AND" | "and" => comb = C::AND,
-                _ => {
-                    let f = parse_f(word)?;
-                    step.replace(Box::new(QueryT::F(f, Metakey::Title)));
-                }
-            }
+            Error::QuerySyntax { column: self.column(at), message: message.into() }
         }
+    }
 
-        if a.is_none() {
-            if let Some(s) = step {
-                a.replace(*s);
-            }
-        } else {
-            if let Some(s) = step {
-                let a2 = a.take().unwrap();
-                a.replace(match comb {
-                    C::OR => QueryT::OR(Box::new(a2), s),
-                    C::AND => QueryT::AND(Box::new(a2), s),
-                });
-                comb = C::OR;
+    fn or(&self, i: &'q str) -> Result<(&'q str, QueryT)> {
+        let (mut i, mut q) = self.and(i)?;
+        while let Some((rest, Keyword::Or)) = keyword(ws(i)) {
+            let (rest, b) = self.and(rest)?;
+            q = QueryT::OR(Box::new(q), Box::new(b));
+            i = rest;
+        }
+        Ok((i, q))
+    }
+
+    fn and(&self, i: &'q str) -> Result<(&'q str, QueryT)> {
+        let (mut i, mut q) = self.unary(ws(i))?;
+        loop {
+            let next = ws(i);
+            let next = match keyword(next) {
+                Some((rest, Keyword::And)) => ws(rest),
+                Some((_, Keyword::Or)) => break,
+                // Adjacent terms are implicitly AND-ed
+                _ if next.is_empty() || next.starts_with(')') => break,
+                _ => next,
+            };
+            let (rest, b) = self.unary(next)?;
+            q = QueryT::AND(Box::new(q), Box::new(b));
+            i = rest;
+        }
+        Ok((i, q))
+    }
+
+    fn unary(&self, i: &'q str) -> Result<(&'q str, QueryT)> {
+        let negated = match keyword(i) {
+            Some((rest, Keyword::Not)) => Some(ws(rest)),
+            _ => i.strip_prefix('-').filter(|r| r.starts_with(is_word_char) || r.starts_with(&['(', '"'][..])),
+        };
+
+        match negated {
+            Some(rest) => {
+                let (rest, q) = self.unary(rest)?;
+                Ok((rest, QueryT::NOT(Box::new(q))))
             }
+            None => self.atom(i),
         }
     }
 
-    Ok(Query {
-        root: a.unwrap(),
-    })
-}
+    fn atom(&self, i: &'q str) -> Result<(&'q str, QueryT)> {
+        if let Some(inner) = i.strip_prefix('(') {
+            let (rest, q) = self.or(inner)?;
+            return ws(rest).strip_prefix(')')
+                .map(|rest| (rest, q))
+                .ok_or(Error::QueryUnbalanced { column: self.column(i) });
+        }
 
-pub fn parse_f(filter: &str) -> Result<Filter> {
-    if filter.starts_with('[') {
-        // Range query
-        let m: &[_] = &['[', ']'];
-        let inner = filter.trim_matches(m);
-        let mut i = inner.split("..");
-        let lower = i.next().ok_or(Error::QueryUnexpectedEOS)?;
-        let upper = i.next().ok_or(Error::QueryUnexpectedEOS)?;
-        let lower_b = if lower.is_empty() {
-                Bound::Unbounded
+        match key(i) {
+            Ok((rest, k)) => {
+                let target = Metakey::from_str(k, self.schema)
+                    .map_err(|_| self.syntax(i, format!("unknown attribute {:?}", k)))?;
+                self.value(rest, target)
+            }
+            Err(_) => self.value(i, Metakey::from_str(DEFAULT_TARGET, self.schema)?),
+        }
+    }
+
+    fn value(&self, i: &'q str, target: Target) -> Result<(&'q str, QueryT)> {
+        if let Some(v) = i.strip_prefix('=') {
+            let (rest, text) = if v.starts_with('"') {
+                quoted(v).map_err(|_| self.syntax(v, "unterminated quote"))?
             } else {
-                Bound::Included(lower.parse()?)
+                word(v).map_err(|_| self.syntax(v, "expected a value"))?
             };
-        let upper_b = if upper.is_empty() {
-                Bound::Unbounded
+            return Ok((rest, QueryT::F(Filter::Equals(text.to_string()), target)));
+        }
+
+        let (rest, q) = self.text(i, target)?;
+        let q = match q {
+            QueryT::F(Filter::TermExists(v), t) | QueryT::F(Filter::Phrase(v), t) if self.is_keyword(&t) =>
+                QueryT::F(Filter::Equals(v), t),
+            q => q,
+        };
+        Ok((rest, q))
+    }
+
+    fn is_keyword(&self, target: &Target) -> bool {
+        self.schema.attribute(target)
+            .and_then(|a| a.index.as_ref())
+            .is_some_and(|i| i.is_keyword())
+    }
+
+    fn text(&self, i: &'q str, target: Target) -> Result<(&'q str, QueryT)> {
+        if i.starts_with('[') {
+            let (rest, (lower, upper)) = range(i)
+                .map_err(|_| self.syntax(i, "expected a range like [1..10]"))?;
+            let f = Filter::IntInRange(self.bound(lower, &target, false)?, self.bound(upper, &target, true)?);
+            Ok((rest, QueryT::F(f, target)))
+        } else if i.starts_with(['<', '>']) {
+            let (op, b) = i.split_at(if i[1..].starts_with('=') { 2 } else { 1 });
+            let (rest, b) = range_bound(b).map_err(|_| self.syntax(b, "expected a value"))?;
+            if b.is_empty() {
+                return Err(self.syntax(rest, "expected a value"));
+            }
+            let excluded = |bound| match bound {
+                Bound::Included(v) => Bound::Excluded(v),
+                bound => bound,
+            };
+            // A date is an interval, so "after" starts behind its end and "before" ends at its
+            // start
+            let f = match op {
+                ">" => Filter::IntInRange(excluded(self.bound(b, &target, true)?), Bound::Unbounded),
+                ">=" => Filter::IntInRange(self.bound(b, &target, false)?, Bound::Unbounded),
+                "<" => Filter::IntInRange(Bound::Unbounded, excluded(self.bound(b, &target, false)?)),
+                _ => Filter::IntInRange(Bound::Unbounded, self.bound(b, &target, true)?),
+            };
+            Ok((rest, QueryT::F(f, target)))
+        } else if i.starts_with('"') {
+            let (rest, text) = quoted(i).map_err(|_| self.syntax(i, "unterminated quote"))?;
+            if text.trim().is_empty() {
+                return Err(self.syntax(i, "expected a search term"));
+            }
+            match rest.strip_prefix('~') {
+                Some(n) => {
+                    let (rest, d) = digit1::<_, (&str, nom::error::ErrorKind)>(n)
+                        .map_err(|_| self.syntax(n, "expected a distance"))?;
+                    let d = d.parse().map_err(|e| self.syntax(d, format!("invalid distance {:?}: {}", d, e)))?;
+                    Ok((rest, QueryT::F(Filter::Near(text.to_string(), d), target)))
+                }
+                None => Ok((rest, QueryT::F(Filter::Phrase(text.to_string()), target))),
+            }
+        } else {
+            let (rest, w) = word(i).map_err(|_| self.syntax(i, "expected a search term"))?;
+            Ok((rest, QueryT::F(self.term(w)?, target)))
+        }
+    }
+
+    /// Filter for a single unquoted word, depending on its wildcards or fuzziness suffix
+    fn term(&self, w: &str) -> Result<Filter> {
+        if let Some(i) = w.rfind('~') {
+            let (t, d) = (&w[..i], &w[i+1..]);
+            let d = if d.is_empty() {
+                DEFAULT_EDITS
             } else {
-                Bound::Included(upper.parse()?)
+                d.parse().map_err(|e| self.syntax(d, format!("invalid edit distance {:?}: {}", d, e)))?
             };
+            if t.is_empty() {
+                return Err(self.syntax(w, "expected a search term"));
+            }
+            return Ok(Filter::Fuzzy(t.to_string(), d));
+        }
 
-        Ok(Filter::IntInRange(lower_b, upper_b))
-    } else {
-        // Term query
-        Ok(Filter::TermExists(filter.to_string()))
+        let wildcards = |c| c == '*' || c == '?';
+        match w.find(wildcards) {
+            None => Ok(Filter::TermExists(w.to_string())),
+            Some(i) if i == w.len() - 1 && w.ends_with('*') =>
+                Ok(Filter::Prefix(w[..i].to_string())),
+            Some(_) => Ok(Filter::Wildcard(w.to_string())),
+        }
+    }
+
+    /// Parse one end of a range. On dates and timestamps a partial date is resolved to its
+    /// first second for the lower end and to its last second for the upper end; anything that
+    /// isn't a date is taken as seconds since the UNIX epoch.
+    fn bound(&self, b: &str, target: &Target, upper: bool) -> Result<Bound<i64>> {
+        if b.is_empty() {
+            return Ok(Bound::Unbounded);
+        }
+
+        let is_date = self.schema.attribute(target)
+            .is_some_and(|a| a.atype == Attributetype::Date || a.atype == Attributetype::Timestamp);
+        if is_date {
+            if let Ok(d) = b.parse::<Date>() {
+                return Ok(Bound::Included(if upper { d.end() } else { d.start() }));
+            }
+        }
+
+        b.parse()
+            .map(Bound::Included)
+            .map_err(|e| if is_date {
+                self.syntax(b, format!("invalid date {:?}", b))
+            } else {
+                self.syntax(b, format!("invalid integer {:?}: {}", b, e))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::schema::{Attribute, IndexDescription};
+
+    fn schema() -> Schema {
+        let mut attributes = HashMap::new();
+        attributes.insert(Metakey::new("title"), Attribute::new(Attributetype::String, None));
+        attributes.insert(Metakey::new("artist"), Attribute::new(
+            Attributetype::String,
+            Some(IndexDescription::Keyword { name: "test_artist".to_string() }),
+        ));
+        attributes.insert(Metakey::new("date"), Attribute::new(Attributetype::Int, None));
+        attributes.insert(Metakey::new("released"), Attribute::new(Attributetype::Date, None));
+        Schema {
+            name: "test".to_string(),
+            description: String::new(),
+            version: (0, 1),
+            attributes,
+        }
+    }
+
+    fn t(term: &str) -> Box<QueryT> {
+        Box::new(QueryT::F(Filter::TermExists(term.to_string()), Metakey::new("title")))
+    }
+
+    #[test]
+    fn precedence() {
+        let s = schema();
+        let q = parse("a b OR c AND d", &s).unwrap();
+        assert_eq!(q.root, QueryT::OR(
+            Box::new(QueryT::AND(t("a"), t("b"))),
+            Box::new(QueryT::AND(t("c"), t("d"))),
+        ));
+
+        let q = parse("a (b OR c)", &s).unwrap();
+        assert_eq!(q.root, QueryT::AND(t("a"), Box::new(QueryT::OR(t("b"), t("c")))));
+    }
+
+    #[test]
+    fn negation_and_quotes() {
+        let s = schema();
+        let q = parse("NOT a -b", &s).unwrap();
+        assert_eq!(q.root, QueryT::AND(Box::new(QueryT::NOT(t("a"))), Box::new(QueryT::NOT(t("b")))));
+
+        let q = parse("-\"and or\" date:[-5..]", &s).unwrap();
+        assert_eq!(q.root, QueryT::AND(
+            Box::new(QueryT::NOT(Box::new(QueryT::F(Filter::Phrase("and or".to_string()), Metakey::new("title"))))),
+            Box::new(QueryT::F(Filter::IntInRange(Bound::Included(-5), Bound::Unbounded), Metakey::new("date"))),
+        ));
+    }
+
+    #[test]
+    fn phrase_and_near() {
+        let s = schema();
+        let q = parse("title:\"dark side\" \"dark moon\"~3", &s).unwrap();
+        assert_eq!(q.root, QueryT::AND(
+            Box::new(QueryT::F(Filter::Phrase("dark side".to_string()), Metakey::new("title"))),
+            Box::new(QueryT::F(Filter::Near("dark moon".to_string(), 3), Metakey::new("title"))),
+        ));
+        match parse("\"dark moon\"~x", &s) {
+            Err(Error::QuerySyntax { column: 13, .. }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn term_modifiers() {
+        let s = schema();
+        let f = |q| match parse(q, &s).unwrap().root {
+            QueryT::F(f, _) => f,
+            r => panic!("Unexpected query {:?}", r),
+        };
+        assert_eq!(f("leviat*"), Filter::Prefix("leviat".to_string()));
+        assert_eq!(f("lev*an"), Filter::Wildcard("lev*an".to_string()));
+        assert_eq!(f("l?v*"), Filter::Wildcard("l?v*".to_string()));
+        assert_eq!(f("tchaikovski~1"), Filter::Fuzzy("tchaikovski".to_string(), 1));
+        assert_eq!(f("tchaikovski~"), Filter::Fuzzy("tchaikovski".to_string(), DEFAULT_EDITS));
+        match parse("a tchaikovski~x", &s) {
+            Err(Error::QuerySyntax { column: 15, .. }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn exact_values() {
+        let s = schema();
+        let f = |q| match parse(q, &s).unwrap().root {
+            QueryT::F(f, _) => f,
+            r => panic!("Unexpected query {:?}", r),
+        };
+        assert_eq!(f("title:=\"The The\""), Filter::Equals("The The".to_string()));
+        assert_eq!(f("title:=Leviathan"), Filter::Equals("Leviathan".to_string()));
+        assert_eq!(f("artist:\"The The\""), Filter::Equals("The The".to_string()));
+        assert_eq!(f("artist:Mastodon"), Filter::Equals("Mastodon".to_string()));
+        assert_eq!(f("artist:Mast*"), Filter::Prefix("Mast".to_string()));
+        match parse("title:=\"The", &s) {
+            Err(Error::QuerySyntax { column: 8, .. }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn date_ranges() {
+        let s = schema();
+        let f = |q| match parse(q, &s).unwrap().root {
+            QueryT::F(f, _) => f,
+            r => panic!("Unexpected query {:?}", r),
+        };
+        let d = |s: &str| s.parse::<Date>().unwrap();
+        assert_eq!(f("released:[1990..1999-06]"),
+            Filter::IntInRange(Bound::Included(d("1990").start()), Bound::Included(d("1999-06").end())));
+        assert_eq!(f("released:>2015-01-01"),
+            Filter::IntInRange(Bound::Excluded(d("2015-01-01").end()), Bound::Unbounded));
+        assert_eq!(f("released:<=2015"),
+            Filter::IntInRange(Bound::Unbounded, Bound::Included(d("2015").end())));
+        assert_eq!(f("date:<5"), Filter::IntInRange(Bound::Unbounded, Bound::Excluded(5)));
+        match parse("released:[1990..1999-13]", &s) {
+            Err(Error::QuerySyntax { column: 17, .. }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+        match parse("released:>", &s) {
+            Err(Error::QueryUnexpectedEOS) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn error_columns() {
+        let s = schema();
+        match parse("a (b OR c", &s) {
+            Err(Error::QueryUnbalanced { column: 3 }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+        match parse("a b)", &s) {
+            Err(Error::QueryUnbalanced { column: 4 }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+        match parse("a date:[1..x]", &s) {
+            Err(Error::QuerySyntax { column: 8, .. }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+        match parse("a isbn:5", &s) {
+            Err(Error::QuerySyntax { column: 3, .. }) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
+        match parse("a AND", &s) {
+            Err(Error::QueryUnexpectedEOS) => {},
+            r => panic!("Unexpected parse result {:?}", r),
+        }
     }
 }
@@ -1,18 +1,49 @@
 use serde::{Serialize, Deserialize};
+use bincode::Options;
 
 use crate::error::{Result, Error};
-use crate::db::meta::Metakey;
+use crate::db::meta::{self, Metakey, Metavalue};
+use crate::db::entry::EntryT;
 
 use std::hash::Hash;
+
+pub use rust_stemmers::Algorithm;
+pub use crate::db::term::{Tokenizer, Segmentation};
 use std::collections::HashMap;
 
 use crate::db::Index;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-enum Attributetype {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Type of the values of an attribute
+pub enum Attributetype {
+    String,
+    Int,
     Timestamp,
-    Unsigned,
-    Signed,
+    /// Calendar date of year, month or day precision
+    Date,
+}
+
+impl Attributetype {
+    /// Check if values of this type are ordered integers, for dates and timestamps the seconds
+    /// since the UNIX epoch
+    pub fn is_int(self) -> bool {
+        match self {
+            Attributetype::Int | Attributetype::Timestamp | Attributetype::Date => true,
+            Attributetype::String => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Attributetype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Attributetype::String => f.write_str("string"),
+            Attributetype::Int => f.write_str("int"),
+            Attributetype::Timestamp => f.write_str("timestamp"),
+            Attributetype::Date => f.write_str("date"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,7 +51,7 @@ enum Attributetype {
 ///
 /// The schema contains all information about the construction of a database both and some
 /// meta-information for humans like a name and description
-/// It also defines what attributes an entry has and what types those attributes are. 
+/// It also defines what attributes an entry has and what types those attributes are.
 /// Lastly the indices for the db are saved
 pub struct Schema {
     /// Human-readable identifier of the database
@@ -32,25 +63,59 @@ pub struct Schema {
     /// Version of rarian-lib this database was last opened with. Used for compatability
     pub version: (u32, u32),
 
-    pub attributes: HashMap<Metakey, IndexDescription>,
+    pub attributes: HashMap<Metakey, Attribute>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Attribute {
-    pub index: IndexDescription,
+    #[serde(rename = "type")]
+    pub atype: Attributetype,
+    #[serde(default)]
+    pub index: Option<IndexDescription>,
+    /// Weight of matches in this attribute when ranking query results
+    #[serde(default = "default_boost")]
+    pub boost: f32,
+    /// Entries have only one value of this attribute. Merging a different one is a conflict
+    /// resolved by the `MergePolicy` instead of keeping both.
+    #[serde(default)]
+    pub single: bool,
 }
 
-impl<'a> Schema {
-    pub fn decode(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).map_err(Error::Bincode)
+fn default_boost() -> f32 {
+    1.0
+}
+
+impl Attribute {
+    pub fn new(atype: Attributetype, index: Option<IndexDescription>) -> Self {
+        Self { atype, index, boost: default_boost(), single: false }
     }
+}
+
+impl<'a> Schema {
+    /// Decode a schema, also accepting schemas written by older versions
+    ///
+    /// Schemas are stored as YAML so fields added later can fall back to defaults. Older
+    /// versions stored them using bincode. The returned flag is set if the schema was in one of
+    /// those old formats.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, bool)> {
+        if bytes.starts_with(b"---") {
+            return Ok((Self::from_yaml(bytes)?, false));
+        }
 
-    pub fn encode_into(&self, bytes: &mut [u8]) -> Result<()> {
-        bincode::serialize_into(bytes, &self).map_err(Error::Bincode)
+        match strict().deserialize::<SchemaV2>(bytes) {
+            Ok(s) => Ok((s.convert(), true)),
+            Err(e) => if let Ok(s) = strict().deserialize::<SchemaV1>(bytes) {
+                Ok((s.convert(), true))
+            } else if let Ok(l) = strict().deserialize::<LegacySchema>(bytes) {
+                Ok((l.convert(), true))
+            } else {
+                Err(Error::Bincode(e))
+            }
+        }
     }
 
-    pub fn encoded_size(&self) -> Result<u64> {
-        bincode::serialized_size(self).map_err(Error::Bincode)
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(self.to_yaml()?.into_bytes())
     }
 
     pub fn to_yaml(&self) -> std::result::Result<String, serde_yaml::Error> {
@@ -61,6 +126,170 @@ impl<'a> Schema {
         let s = serde_yaml::from_slice(input)?;
         Ok(s)
     }
+
+    pub fn attribute(&self, key: &Metakey) -> Option<&Attribute> {
+        self.attributes.get(key)
+    }
+
+    /// Check that `key` is declared and `value` has the declared type
+    pub fn typecheck(&self, key: &Metakey, value: &Metavalue) -> Result<()> {
+        let attr = self.attribute(key).ok_or_else(|| Error::UnknownAttribute(key.clone()))?;
+        if attr.index.as_ref().is_some_and(IndexDescription::is_fulltext) {
+            return Err(Error::NotStored(key.clone()));
+        }
+        let found = value.attributetype();
+        if attr.atype != found {
+            return Err(Error::TypeMismatch { key: key.clone(), expected: attr.atype, found });
+        }
+        Ok(())
+    }
+
+    /// Attributes with a full-text index and the number of bytes of text each of them indexes
+    pub fn fulltext(&self) -> impl Iterator<Item=(&Metakey, usize)> {
+        self.attributes.iter().filter_map(|(k, a)| match a.index {
+            Some(IndexDescription::FullText { max_size, .. }) => Some((k, max_size)),
+            _ => None,
+        })
+    }
+
+    /// Bytes of body text to read from files for the full-text indices, 0 if there are none
+    pub fn text_limit(&self) -> usize {
+        self.fulltext().map(|(_, n)| n).max().unwrap_or(0)
+    }
+
+    /// Typecheck all metadata of an entry
+    pub fn typecheck_entry(&self, entry: &EntryT) -> Result<()> {
+        for (k, v) in entry.metadata.iter() {
+            self.typecheck(k, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bincode configuration matching `bincode::serialize` but rejecting trailing bytes, so that
+/// decoding data written in a different format reliably fails.
+pub(crate) fn strict() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+#[derive(Deserialize)]
+/// Schema as last written using bincode
+struct SchemaV2 {
+    name: String,
+    description: String,
+    version: (u32, u32),
+    attributes: HashMap<Metakey, AttributeV2>,
+}
+
+#[derive(Deserialize)]
+struct AttributeV2 {
+    atype: Attributetype,
+    index: Option<LegacyIndexDescription>,
+    boost: f32,
+}
+
+impl SchemaV2 {
+    fn convert(self) -> Schema {
+        Schema {
+            name: self.name,
+            description: self.description,
+            version: self.version,
+            attributes: self.attributes.into_iter()
+                .map(|(k, a)| (k, Attribute {
+                    atype: a.atype,
+                    index: a.index.map(LegacyIndexDescription::convert),
+                    boost: a.boost,
+                    single: false,
+                }))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// Schema as written before attributes had a ranking boost
+struct SchemaV1 {
+    name: String,
+    description: String,
+    version: (u32, u32),
+    attributes: HashMap<Metakey, AttributeV1>,
+}
+
+#[derive(Deserialize)]
+struct AttributeV1 {
+    atype: Attributetype,
+    index: Option<LegacyIndexDescription>,
+}
+
+impl SchemaV1 {
+    fn convert(self) -> Schema {
+        Schema {
+            name: self.name,
+            description: self.description,
+            version: self.version,
+            attributes: self.attributes.into_iter()
+                .map(|(k, a)| (k, Attribute::new(a.atype, a.index.map(LegacyIndexDescription::convert))))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// Index description as stored in bincode schemas
+enum LegacyIndexDescription {
+    StemmedTerm {
+        dbname: String,
+    },
+    RangeTree {
+        name: String,
+    },
+}
+
+impl LegacyIndexDescription {
+    fn convert(self) -> IndexDescription {
+        match self {
+            Self::StemmedTerm { dbname } => IndexDescription::StemmedTerm {
+                dbname,
+                language: default_language(),
+                stopwords: None,
+                tokenizer: Tokenizer::default(),
+            },
+            Self::RangeTree { name } => IndexDescription::RangeTree { name },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// Schema as written before attributes were user-defined
+struct LegacySchema {
+    name: String,
+    description: String,
+    version: (u32, u32),
+    attributes: HashMap<meta::legacy::Metakey, LegacyIndexDescription>,
+}
+
+impl LegacySchema {
+    fn convert(self) -> Schema {
+        let mut attributes: HashMap<Metakey, Attribute> = self.attributes.into_iter()
+            .map(|(k, index)| (k.convert(), Attribute::new(k.attributetype(), Some(index.convert()))))
+            .collect();
+
+        // All of the old fixed keys were valid even if not indexed
+        use meta::legacy::Metakey::*;
+        for k in [Title, Artist, Date, Comment, Description, Album, TrackNumber, Albumartist, Author].iter() {
+            attributes.entry(k.convert())
+                .or_insert_with(|| Attribute::new(k.attributetype(), None));
+        }
+
+        Schema {
+            name: self.name,
+            description: self.description,
+            version: self.version,
+            attributes,
+        }
+    }
 }
 
 
@@ -68,9 +297,91 @@ impl<'a> Schema {
 pub enum IndexDescription {
     StemmedTerm {
         dbname: String,
+        /// Stemming algorithm, "none" to index words unchanged
+        #[serde(default = "default_language", with = "language")]
+        language: Option<Algorithm>,
+        /// Words not to index. Replaces the built-in English stopwords if given.
+        #[serde(default)]
+        stopwords: Option<Vec<String>>,
+        /// How text is split into words, both when indexing and querying
+        #[serde(default)]
+        tokenizer: Tokenizer,
     },
     RangeTree {
         name: String,
+    },
+    /// Whole values, compared ignoring case and repeated whitespace
+    Keyword {
+        name: String,
+    },
+    /// Words of the body text of files, as read by their extractor when they are added
+    ///
+    /// The text itself is not stored, so the attribute can't be given a value; it can only be
+    /// searched. Analyzed like `StemmedTerm`.
+    FullText {
+        dbname: String,
+        #[serde(default = "default_language", with = "language")]
+        language: Option<Algorithm>,
+        #[serde(default)]
+        stopwords: Option<Vec<String>>,
+        #[serde(default)]
+        tokenizer: Tokenizer,
+        /// Bytes of text indexed per entry, the rest is ignored
+        #[serde(default = "default_max_size")]
+        max_size: usize,
+    },
+}
+
+fn default_max_size() -> usize {
+    64 * 1024
+}
+
+fn default_language() -> Option<Algorithm> {
+    Some(Algorithm::English)
+}
+
+/// (De)serialize an optional stemming algorithm, writing no stemming as "none"
+mod language {
+    use serde::{Serialize, Deserialize, Serializer, Deserializer, de::Error};
+    use rust_stemmers::Algorithm;
+
+    pub fn serialize<S: Serializer>(language: &Option<Algorithm>, s: S) -> Result<S::Ok, S::Error> {
+        match language {
+            Some(a) => a.serialize(s),
+            None => s.serialize_str("none"),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Algorithm>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Stemmed(Algorithm),
+            Other(String),
+        }
+
+        match Repr::deserialize(d)? {
+            Repr::Stemmed(a) => Ok(Some(a)),
+            Repr::Other(s) if s.eq_ignore_ascii_case("none") => Ok(None),
+            Repr::Other(s) => Err(D::Error::custom(format!("unknown language {:?}", s))),
+        }
+    }
+}
+
+impl IndexDescription {
+    /// Check if this describes an index over the words of a text
+    pub fn is_term(&self) -> bool {
+        matches!(self, IndexDescription::StemmedTerm { .. })
+    }
+
+    /// Check if this describes an index over whole values
+    pub fn is_keyword(&self) -> bool {
+        matches!(self, IndexDescription::Keyword { .. })
+    }
+
+    /// Check if this describes an index over the body text of files
+    pub fn is_fulltext(&self) -> bool {
+        matches!(self, IndexDescription::FullText { .. })
     }
 }
 
@@ -82,3 +393,114 @@ pub enum IndexDescription {
 // Schema tells us: Field #XYZ has type ABC and identifier DEF. Type ABC defines encoding/decoding
 // rules & possible indices.
 // Schema then also defines what fields are indexed in which way.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_legacy() {
+        #[derive(Serialize)]
+        #[allow(dead_code)]
+        enum OldKey { Title, Artist, Date }
+        #[derive(Serialize)]
+        enum OldIndex { StemmedTerm { dbname: String }, RangeTree { name: String } }
+        #[derive(Serialize)]
+        struct Old {
+            name: String,
+            description: String,
+            version: (u32, u32),
+            attributes: Vec<(OldKey, OldIndex)>,
+        }
+        let old = Old {
+            name: "music".to_string(),
+            description: String::new(),
+            version: (0, 1),
+            attributes: vec![
+                (OldKey::Title, OldIndex::StemmedTerm { dbname: "music_title".to_string() }),
+                (OldKey::Date, OldIndex::RangeTree { name: "music_date".to_string() }),
+            ],
+        };
+        let bytes = bincode::serialize(&old).unwrap();
+
+        let (schema, legacy) = Schema::decode(&bytes).unwrap();
+        assert!(legacy);
+        assert_eq!(schema.attribute(&Metakey::new("date")).unwrap().atype, Attributetype::Int);
+        assert!(schema.attribute(&Metakey::new("title")).unwrap().index.is_some());
+        assert!(schema.attribute(&Metakey::new("author")).unwrap().index.is_none());
+
+        let buf = schema.encode().unwrap();
+        assert!(!Schema::decode(&buf).unwrap().1);
+    }
+
+    #[test]
+    fn decode_without_boost() {
+        #[derive(Serialize)]
+        struct Attr { atype: Attributetype, index: Option<IndexDescription> }
+        #[derive(Serialize)]
+        struct Old {
+            name: String,
+            description: String,
+            version: (u32, u32),
+            attributes: HashMap<Metakey, Attr>,
+        }
+        let mut attributes = HashMap::new();
+        attributes.insert(Metakey::new("title"), Attr { atype: Attributetype::String, index: None });
+        let old = Old { name: "music".to_string(), description: String::new(), version: (0, 1), attributes };
+        let bytes = bincode::serialize(&old).unwrap();
+
+        let (schema, legacy) = Schema::decode(&bytes).unwrap();
+        assert!(legacy);
+        assert_eq!(schema.attribute(&Metakey::new("title")).unwrap().boost, 1.0);
+    }
+
+    #[test]
+    fn index_language() {
+        let yaml = b"name: books\ndescription: ''\nversion: [0, 1]\nattributes:\n  title:\n    type: string\n    index:\n      StemmedTerm:\n        dbname: books_title\n        language: German\n        tokenizer:\n          segmentation: unicode\n          strip_diacritics: true\n  isbn:\n    type: string\n    index:\n      StemmedTerm:\n        dbname: books_isbn\n        language: none\n        stopwords: [isbn]\n  author:\n    type: string\n    index:\n      StemmedTerm:\n        dbname: books_author\n";
+        let schema = Schema::from_yaml(yaml).unwrap();
+        let language = |k: &str| match schema.attribute(&Metakey::new(k)).unwrap().index {
+            Some(IndexDescription::StemmedTerm { language, .. }) => language,
+            ref i => panic!("Unexpected index {:?}", i),
+        };
+        assert_eq!(language("title"), Some(Algorithm::German));
+        assert_eq!(language("isbn"), None);
+        assert_eq!(language("author"), Some(Algorithm::English));
+
+        let tokenizer = |k: &str| match schema.attribute(&Metakey::new(k)).unwrap().index {
+            Some(IndexDescription::StemmedTerm { tokenizer, .. }) => tokenizer,
+            ref i => panic!("Unexpected index {:?}", i),
+        };
+        assert_eq!(tokenizer("title"), Tokenizer { segmentation: Segmentation::Unicode, strip_diacritics: true, cjk_bigrams: false });
+        assert_eq!(tokenizer("author"), Tokenizer::default());
+
+        let (decoded, legacy) = Schema::decode(&schema.encode().unwrap()).unwrap();
+        assert!(!legacy);
+        match decoded.attribute(&Metakey::new("isbn")).unwrap().index {
+            Some(IndexDescription::StemmedTerm { language: None, stopwords: Some(ref s), .. }) => assert_eq!(s, &["isbn"]),
+            ref i => panic!("Unexpected index {:?}", i),
+        }
+    }
+
+    #[test]
+    fn typecheck() {
+        let mut attributes = HashMap::new();
+        attributes.insert(Metakey::new("date"), Attribute::new(Attributetype::Timestamp, None));
+        let schema = Schema {
+            name: "test".to_string(),
+            description: String::new(),
+            version: (0, 1),
+            attributes,
+        };
+
+        let date = Metakey::new("date");
+        assert!(schema.typecheck(&date, &Metavalue::Timestamp(vec![0].into_boxed_slice())).is_ok());
+        match schema.typecheck(&date, &Metavalue::Str(vec!["foo".into()].into_boxed_slice())) {
+            Err(Error::TypeMismatch { expected: Attributetype::Timestamp, found: Attributetype::String, .. }) => {},
+            r => panic!("Unexpected typecheck result {:?}", r),
+        }
+        match schema.typecheck(&Metakey::new("isbn"), &Metavalue::Int(vec![0].into_boxed_slice())) {
+            Err(Error::UnknownAttribute(_)) => {},
+            r => panic!("Unexpected typecheck result {:?}", r),
+        }
+    }
+}
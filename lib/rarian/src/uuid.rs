@@ -10,7 +10,7 @@ use serde::{
 
 pub use uuid::Uuid;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Hash)]
 pub struct UUID(u128);
 
 impl UUID {
@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+
+use tempfile::TempDir;
+
+use rarian::Transaction;
+use rarian::db::Database;
+use rarian::db::dbm::DBManager;
+use rarian::db::entry::{EntryT, FileT};
+use rarian::db::meta::{Metakey, Metavalue};
+use rarian::query::{parse, Querier};
+use rarian::schema::{Schema, Attribute, Attributetype, IndexDescription, Algorithm};
+
+fn setup() -> (TempDir, DBManager) {
+    setup_sized(10485760)
+}
+
+fn setup_sized(map_size: usize) -> (TempDir, DBManager) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut dbmb = DBManager::builder();
+    dbmb.set_max_dbs(126);
+    dbmb.set_map_size(map_size);
+    let dbm = DBManager::from_builder(dir.path(), dbmb).unwrap();
+
+    let mut attributes = HashMap::new();
+    attributes.insert(Metakey::new("title"), Attribute {
+        single: true,
+        ..Attribute::new(
+            Attributetype::String,
+            Some(IndexDescription::StemmedTerm { dbname: "test_title".to_string(), language: Some(Algorithm::English), stopwords: None, tokenizer: Default::default() }),
+        )
+    });
+    attributes.insert(Metakey::new("tracknumber"), Attribute::new(
+        Attributetype::Int,
+        Some(IndexDescription::RangeTree { name: "test_tracknumber".to_string() }),
+    ));
+    attributes.insert(Metakey::new("comment"), Attribute::new(Attributetype::String, None));
+    attributes.insert(Metakey::new("artist"), Attribute::new(
+        Attributetype::String,
+        Some(IndexDescription::Keyword { name: "test_artist".to_string() }),
+    ));
+    attributes.insert(Metakey::new("date"), Attribute::new(
+        Attributetype::Date,
+        Some(IndexDescription::RangeTree { name: "test_date".to_string() }),
+    ));
+    attributes.insert(Metakey::new("content"), Attribute::new(
+        Attributetype::String,
+        Some(IndexDescription::FullText { dbname: "test_content".to_string(), language: Some(Algorithm::English), stopwords: None, tokenizer: Default::default(), max_size: 1024 }),
+    ));
+    let schema = Schema {
+        name: "test".to_string(),
+        description: "Test database".to_string(),
+        version: (0, 1),
+        attributes,
+    };
+
+    let mut txn = dbm.write().unwrap();
+    Database::create(&mut txn, "test", schema).unwrap();
+    txn.commit().unwrap();
+
+    (dir, dbm)
+}
+
+fn track(key: &str, title: &str, nr: i64) -> EntryT {
+    let mut metadata = HashMap::new();
+    metadata.insert(Metakey::new("title"), Metavalue::Str(vec![title.to_string().into_boxed_str()].into_boxed_slice()));
+    metadata.insert(Metakey::new("tracknumber"), Metavalue::Int(vec![nr].into_boxed_slice()));
+    EntryT::new(FileT::new(key.to_string(), HashMap::new()), metadata)
+}
+
+#[test]
+fn range_shared_values() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    db.insert_rand(&mut txn, &track("a", "Leviathan", 1)).unwrap();
+    db.insert_rand(&mut txn, &track("b", "Behemoth", 1)).unwrap();
+    db.insert_rand(&mut txn, &track("c", "Ziz", 2)).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("tracknumber:[1..1]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 2);
+    let r = qr.run(parse("tracknumber:[1..2]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 3);
+}
+
+#[test]
+fn range_migrate_blob() {
+    let (_dir, dbm) = setup();
+
+    // Replace the range index with a blob as written by older versions
+    let mut old = std::collections::BTreeMap::new();
+    old.insert(1i64, rarian::db::UUID::from_u128(1));
+    old.insert(2i64, rarian::db::UUID::from_u128(2));
+    let blob = bincode::serialize(&old).unwrap();
+    let mut txn = dbm.write().unwrap();
+    unsafe {
+        let db = txn.open_db(Some("test_tracknumber")).unwrap();
+        txn.drop_db(db).unwrap();
+    }
+    let main = unsafe { txn.open_db(None).unwrap() };
+    txn.put(main, &"test_tracknumber", &blob, rarian::db::dbm::WriteFlags::empty()).unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    assert!(db.needs_migration());
+    db.migrate(&mut txn).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    assert!(!db.needs_migration());
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("tracknumber:[2..]", &db.schema).unwrap()).unwrap();
+    assert!(r.contains(&rarian::db::UUID::from_u128(2)));
+    assert_eq!(r.len(), 1);
+}
+
+#[test]
+fn remove_entry() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let a = rarian::db::UUID::generate();
+    let b = rarian::db::UUID::generate();
+    db.insert(&mut txn, a, &track("a", "Leviathan", 1)).unwrap();
+    db.insert(&mut txn, b, &track("b", "Leviathan Rising", 1)).unwrap();
+    db.remove(&mut txn, a).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    assert!(db.lookup(&txn, &a).is_err());
+    assert!(db.filekeys.get(&txn, &"a".to_string()).is_err());
+    assert_eq!(db.filekeys.get(&txn, &"b".to_string()).unwrap(), b);
+
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("leviathan", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![b]);
+    let r = qr.run(parse("tracknumber:[1..1]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![b]);
+}
+
+#[test]
+fn merge_entries() {
+    use rarian::db::MergePolicy;
+
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let u = rarian::db::UUID::generate();
+    db.insert(&mut txn, u, &track("flac", "Leviathan", 1)).unwrap();
+
+    // Same song, additional file; no disagreement in metadata
+    let mut e = track("ogg", "Leviathan", 1);
+    e.files.insert(FileT::new("flac".to_string(), HashMap::new()));
+    db.insert_rand(&mut txn, &e).unwrap();
+
+    let merged = db.lookup(&txn, &u).unwrap();
+    assert_eq!(merged.files.len(), 2);
+    assert_eq!(db.filekeys.get(&txn, &"ogg".to_string()).unwrap(), u);
+
+    // Disjoint lists of a multi-valued attribute are combined, whatever the policy
+    db.set_merge_policy(MergePolicy::Fail);
+    let artist = |a: &str| Metavalue::Str(vec![a.to_string().into_boxed_str()].into_boxed_slice());
+    for (key, a) in [("flac", "A"), ("ogg", "B")].iter() {
+        let mut e = track(key, "Leviathan", 1);
+        e.metadata.insert(Metakey::new("artist"), artist(a));
+        db.insert_rand(&mut txn, &e).unwrap();
+    }
+    let merged = db.lookup(&txn, &u).unwrap();
+    assert_eq!(merged.metadata[&Metakey::new("artist")], Metavalue::Str(vec!["A".into(), "B".into()].into_boxed_slice()));
+
+    // Disagreeing title
+    db.set_merge_policy(MergePolicy::Fail);
+    assert!(db.insert_rand(&mut txn, &track("ogg", "Behemoth", 1)).is_err());
+
+    db.set_merge_policy(MergePolicy::KeepExisting);
+    db.insert_rand(&mut txn, &track("ogg", "Behemoth", 1)).unwrap();
+    assert_eq!(db.lookup(&txn, &u).unwrap().metadata, merged.metadata);
+
+    db.set_merge_policy(MergePolicy::PreferNew);
+    db.insert_rand(&mut txn, &track("ogg", "Behemoth", 1)).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    assert!(qr.run(parse("leviathan", &db.schema).unwrap()).unwrap().is_empty());
+    assert!(qr.run(parse("behemoth", &db.schema).unwrap()).unwrap().contains(&u));
+}
+
+#[test]
+fn update_entry() {
+    use rarian::db::meta::Change;
+
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let u = rarian::db::UUID::generate();
+    let old = track("a", "Leviathan", 1);
+    db.insert(&mut txn, u, &old).unwrap();
+
+    let mut new = old.clone();
+    new.metadata.insert(Metakey::new("title"), Metavalue::Str(vec!["Behemoth".into()].into_boxed_slice()));
+    new.metadata.remove(&Metakey::new("tracknumber"));
+    let changes = old.diff(&new);
+    assert_eq!(changes.len(), 2);
+    db.update(&mut txn, u, &changes).unwrap();
+
+    db.update(&mut txn, u, &[Change::Append(Metakey::new("title"), Metavalue::Str(vec!["Ziz".into()].into_boxed_slice()))]).unwrap();
+    db.update(&mut txn, u, &[Change::Remove(Metakey::new("title"), Metavalue::Str(vec!["Behemoth".into()].into_boxed_slice()))]).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let e = db.lookup(&txn, &u).unwrap();
+    assert_eq!(e.metadata[&Metakey::new("title")], Metavalue::Str(vec!["Ziz".into()].into_boxed_slice()));
+    assert!(!e.metadata.contains_key(&Metakey::new("tracknumber")));
+
+    let mut qr = Querier::new(&txn, &db);
+    assert!(qr.run(parse("leviathan", &db.schema).unwrap()).unwrap().is_empty());
+    assert!(qr.run(parse("behemoth", &db.schema).unwrap()).unwrap().is_empty());
+    assert!(qr.run(parse("ziz", &db.schema).unwrap()).unwrap().contains(&u));
+    assert!(qr.run(parse("tracknumber:[1..1]", &db.schema).unwrap()).unwrap().is_empty());
+}
+
+#[test]
+fn typecheck_query() {
+    let (_dir, dbm) = setup();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    match qr.run(parse("tracknumber:foo", &db.schema).unwrap()) {
+        Err(e @ rarian::Error::QueryTypeMismatch { .. }) => {
+            assert_eq!(e.to_string(), "tracknumber: expected int, found term \"foo\"");
+        },
+        r => panic!("Unexpected query result {:?}", r),
+    }
+}
+
+#[test]
+fn scan_unindexed() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let u = rarian::db::UUID::generate();
+    let mut e = track("a", "Leviathan", 1);
+    e.metadata.insert(Metakey::new("comment"), Metavalue::Str(vec!["Recorded live in Berlin".into()].into_boxed_slice()));
+    db.insert(&mut txn, u, &e).unwrap();
+    db.insert_rand(&mut txn, &track("b", "Behemoth", 2)).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("comment:recording", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![u]);
+    assert_eq!(qr.unindexed(), &[Metakey::new("comment")]);
+}
+
+#[test]
+fn phrase_search() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let a = rarian::db::UUID::generate();
+    let b = rarian::db::UUID::generate();
+    db.insert(&mut txn, a, &track("a", "The Dark Side of the Moon", 1)).unwrap();
+    db.insert(&mut txn, b, &track("b", "Moon over the dark Side", 2)).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("\"the dark side\"", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 2);
+    let r = qr.run(parse("\"side of the moon\"", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![a]);
+    let r = qr.run(parse("\"moon dark\"~3", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![b]);
+    let r = qr.run(parse("\"moon dark\"~4", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 2);
+}
+
+#[test]
+fn inexact_terms() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let a = rarian::db::UUID::generate();
+    let b = rarian::db::UUID::generate();
+    db.insert(&mut txn, a, &track("a", "Leviathan", 1)).unwrap();
+    db.insert(&mut txn, b, &track("b", "Tchaikovsky", 2)).unwrap();
+    db.insert_rand(&mut txn, &track("c", "Levity", 3)).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("title:leviat*", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![a]);
+    let r = qr.run(parse("lev*an", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![a]);
+    let r = qr.run(parse("lev*", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 2);
+    let r = qr.run(parse("tschaikowski~2", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![b]);
+    assert!(qr.run(parse("tschaikowski~1", &db.schema).unwrap()).unwrap().is_empty());
+}
+
+#[test]
+fn ranked_results() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let a = rarian::db::UUID::generate();
+    let b = rarian::db::UUID::generate();
+    let c = rarian::db::UUID::generate();
+    db.insert(&mut txn, a, &track("a", "Leviathan", 1)).unwrap();
+    db.insert(&mut txn, b, &track("b", "Leviathan, Leviathan", 2)).unwrap();
+    db.insert(&mut txn, c, &track("c", "Song for the great big Leviathan in the deep sea", 3)).unwrap();
+    db.insert_rand(&mut txn, &track("d", "Behemoth", 4)).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.ranked(parse("leviathan", &db.schema).unwrap()).unwrap();
+    let order: Vec<_> = r.iter().map(|(u, _)| *u).collect();
+    // More occurrences and shorter titles rank higher
+    assert_eq!(order, vec![b, a, c]);
+    assert!(r.windows(2).all(|w| w[0].1 >= w[1].1));
+
+    // Range matches aren't scored but still part of the result
+    let r = qr.ranked(parse("leviathan OR tracknumber:[4..4]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 4);
+    assert_eq!(r[3].1, 0.0);
+}
+
+#[test]
+fn sorted_results() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let mut uuids = Vec::new();
+    for (key, title, nr) in [("a", "Leviathan b", 2), ("b", "leviathan a", 2), ("c", "Leviathan c", 1)].iter() {
+        let u = rarian::db::UUID::generate();
+        db.insert(&mut txn, u, &track(key, title, *nr)).unwrap();
+        uuids.push(u);
+    }
+    let u = rarian::db::UUID::generate();
+    let mut e = track("d", "Leviathan", 0);
+    e.metadata.remove(&Metakey::new("tracknumber"));
+    db.insert(&mut txn, u, &e).unwrap();
+    uuids.push(u);
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let mut q = parse("leviathan", &db.schema).unwrap();
+    q.add_sort(Metakey::new("tracknumber"), true).add_sort(Metakey::new("title"), false);
+    let order: Vec<_> = qr.ranked(q.clone()).unwrap().into_iter().map(|(u, _)| u).collect();
+    // Missing values sort last, strings compare case-insensitively
+    assert_eq!(order, vec![uuids[1], uuids[0], uuids[2], uuids[3]]);
+
+    q.set_offset(1).set_limit(2);
+    let order: Vec<_> = qr.ranked(q).unwrap().into_iter().map(|(u, _)| u).collect();
+    assert_eq!(order, vec![uuids[0], uuids[2]]);
+}
+
+#[test]
+fn facet_counts() {
+    use rarian::query::{Facet, Bucket};
+
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    for (key, title, nr) in [("a", "Leviathan", 1), ("b", "Leviathan", 5), ("c", "Behemoth", 25)].iter() {
+        db.insert_rand(&mut txn, &track(key, title, *nr)).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let all = qr.run(parse("leviathan OR behemoth", &db.schema).unwrap()).unwrap();
+    let f = qr.facets(all.iter(), &[Metakey::new("title"), Metakey::new("tracknumber")]).unwrap();
+
+    assert_eq!(f[0].1, Facet::Values(vec![("Leviathan".to_string(), 2), ("Behemoth".to_string(), 1)]));
+    match &f[1].1 {
+        Facet::Ranges(b) => {
+            assert_eq!(b.len(), 9);
+            assert_eq!(b[0], Bucket { lower: 1, upper: 3, count: 1 });
+            assert_eq!(b[1].count, 1);
+            assert_eq!(b[8], Bucket { lower: 25, upper: 25, count: 1 });
+            assert_eq!(b.iter().map(|b| b.count).sum::<usize>(), 3);
+        }
+        r => panic!("Unexpected facet {:?}", r),
+    }
+}
+
+#[test]
+fn keyword_values() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let mut uuids = Vec::new();
+    for (key, title, artist) in [("a", "Infected", "The The"), ("b", "The The", "Matt Johnson"), ("c", "This Is the Day", "the  the")].iter() {
+        let u = rarian::db::UUID::generate();
+        let mut e = track(key, title, 1);
+        e.metadata.insert(Metakey::new("artist"), Metavalue::Str(vec![artist.to_string().into_boxed_str()].into_boxed_slice()));
+        db.insert(&mut txn, u, &e).unwrap();
+        uuids.push(u);
+    }
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("artist:\"The The\"", &db.schema).unwrap()).unwrap();
+    assert_eq!(r, [uuids[0], uuids[2]].iter().copied().collect());
+    assert!(qr.run(parse("artist:the", &db.schema).unwrap()).unwrap().is_empty());
+    let r = qr.run(parse("artist:matt*", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![uuids[1]]);
+
+    // Whole values of term indexed attributes, where "the" is a stopword
+    let r = qr.run(parse("title:=\"the the\"", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![uuids[1]]);
+    assert!(qr.unindexed().is_empty());
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    db.remove(&mut txn, uuids[0]).unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("artist:\"the the\"", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![uuids[2]]);
+}
+
+#[test]
+fn fulltext() {
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    db.insert_text(&mut txn, &track("a", "Moby Dick", 1), "Call me Ishmael. Some years ago, never mind how long precisely").unwrap();
+    let pride = format!("It is a truth universally acknowledged, {}that a single man in possession of a good fortune", "however little known ".repeat(50));
+    db.insert_text(&mut txn, &track("b", "Pride and Prejudice", 2), &pride).unwrap();
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let a = db.filekeys.get(&txn, &"a".to_string()).unwrap();
+    let b = db.filekeys.get(&txn, &"b".to_string()).unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("content:ishmael", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![a]);
+    let r = qr.run(parse("content:\"universal truth\"~3", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![b]);
+    // Only the first 1024 bytes are indexed
+    assert!(qr.run(parse("content:fortune", &db.schema).unwrap()).unwrap().is_empty());
+    assert!(qr.run(parse("content:=\"call me ishmael\"", &db.schema).unwrap()).is_err());
+    assert!(db.lookup(&txn, &a).unwrap().metadata.get(&Metakey::new("content")).is_none());
+    drop(txn);
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let mut e = track("c", "Ulysses", 3);
+    e.metadata.insert(Metakey::new("content"), Metavalue::Str(vec!["Stately, plump Buck Mulligan".into()].into_boxed_slice()));
+    assert!(db.insert_rand(&mut txn, &e).is_err());
+    db.remove(&mut txn, a).unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    assert!(qr.run(parse("content:ishmael", &db.schema).unwrap()).unwrap().is_empty());
+
+    // Words longer than an LMDB key are indexed cut short, in text and values alike
+    let hash = "0f3a".repeat(150);
+    db.insert_text(&mut txn, &track("d", &hash, 4), &format!("sha256 {}", hash)).unwrap();
+    let d = db.filekeys.get(&txn, &"d".to_string()).unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse(&format!("content:{}", hash), &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![d]);
+    let r = qr.run(parse(&format!("title:{}", hash), &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![d]);
+    db.remove(&mut txn, d).unwrap();
+}
+
+#[test]
+fn date_ranges() {
+    use rarian::db::date::Date;
+
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    let mut uuids = Vec::new();
+    for (key, date) in [("a", "1990"), ("b", "1999-06"), ("c", "1999-07-01"), ("d", "2015-01-01T12:00:00Z")].iter() {
+        let u = rarian::db::UUID::generate();
+        let mut e = track(key, "Leviathan", 1);
+        e.metadata.insert(Metakey::new("date"), Metavalue::Date(vec![date.parse::<Date>().unwrap()].into_boxed_slice()));
+        db.insert(&mut txn, u, &e).unwrap();
+        uuids.push(u);
+    }
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("date:[1990..1999-06]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r, uuids[..2].iter().copied().collect());
+    let r = qr.run(parse("date:>2015-01-01", &db.schema).unwrap()).unwrap();
+    assert!(r.is_empty());
+    let r = qr.run(parse("date:>=2015-01-01", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![uuids[3]]);
+    let r = qr.run(parse("date:<1999-07", &db.schema).unwrap()).unwrap();
+    assert_eq!(r, uuids[..2].iter().copied().collect());
+    // Partial dates match ranges overlapping any part of the period they cover
+    let r = qr.run(parse("date:[1990-06..1990-12]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![uuids[0]]);
+    let r = qr.run(parse("date:>1999-06-15", &db.schema).unwrap()).unwrap();
+    assert_eq!(r, uuids[1..].iter().copied().collect());
+
+    let e = db.lookup(&txn, &uuids[1]).unwrap();
+    assert!(e.to_yaml().unwrap().contains("1999-06"));
+}
+
+#[test]
+fn database_stats() {
+    use rarian::db::stats::IndexStats;
+
+    let (_dir, dbm) = setup();
+
+    let mut txn = dbm.write().unwrap();
+    let mut db = Database::open(&txn, "test").unwrap();
+    for (key, title, nr) in [("a", "Leviathan", 3), ("b", "Leviathan Rising", 5), ("c", "Behemoth", 25)].iter() {
+        db.insert_rand(&mut txn, &track(key, title, *nr)).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let s = db.stats(&txn, 1).unwrap();
+    assert_eq!((s.entries, s.files), (3, 3));
+    match &s.indices["title"] {
+        IndexStats::Term { terms, documents, largest, .. } => {
+            assert_eq!(*terms, 3);
+            assert_eq!((documents.count, documents.total), (3, 4));
+            assert_eq!(largest, &[("leviathan".to_string(), 2)]);
+        }
+        i => panic!("Unexpected index stats {:?}", i),
+    }
+    match &s.indices["tracknumber"] {
+        IndexStats::Range { values, min, max, .. } => assert_eq!((*values, *min, *max), (3, Some(3), Some(25))),
+        i => panic!("Unexpected index stats {:?}", i),
+    }
+
+    let e = dbm.stats().unwrap();
+    assert!(e.used > 0 && e.used <= e.map_size);
+}
+
+#[test]
+fn grow_map() {
+    let (_dir, mut dbm) = setup_sized(64 * 1024);
+    let initial = dbm.map_size().unwrap();
+
+    let insert = |txn: &mut rarian::RwTransaction| {
+        let mut db = Database::open(txn, "test")?;
+        for i in 0..500 {
+            let title = format!("Track {} of a long and winding compilation number {}", i, i * 7);
+            db.insert_rand(txn, &track(&format!("key{}", i), &title, i))?;
+        }
+        Ok(())
+    };
+
+    dbm.set_max_map_size(Some(initial));
+    assert!(dbm.write_with(insert).unwrap_err().is_map_full());
+
+    dbm.set_max_map_size(None);
+    dbm.write_with(insert).unwrap();
+    assert!(dbm.map_size().unwrap() > initial);
+
+    let txn = dbm.read().unwrap();
+    let db = Database::open(&txn, "test").unwrap();
+    let mut qr = Querier::new(&txn, &db);
+    let r = qr.run(parse("tracknumber:[0..499]", &db.schema).unwrap()).unwrap();
+    assert_eq!(r.len(), 500);
+}